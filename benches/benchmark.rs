@@ -15,6 +15,17 @@ fn gcd(engine: Engine, module: Module) -> Result<()> {
     Ok(())
 }
 
+fn gcd_pre(engine: Engine, instance_pre: InstancePre<()>) -> Result<()> {
+    let mut store = Store::new(&engine, ());
+    let instance = instance_pre.instantiate(&mut store)?;
+
+    // Invoke `gcd` export
+    let gcd = instance.get_typed_func::<(i32, i32), i32>(&mut store, "gcd")?;
+    gcd.call(&mut store, (6, 27))?;
+
+    Ok(())
+}
+
 fn gcd_wasi(engine: Engine, module: Module, linker: Linker<WasiCtx>) -> Result<()> {
     let wasi = WasiCtxBuilder::new()
         .inherit_stdio()
@@ -30,6 +41,21 @@ fn gcd_wasi(engine: Engine, module: Module, linker: Linker<WasiCtx>) -> Result<(
     Ok(())
 }
 
+fn gcd_wasi_pre(engine: Engine, instance_pre: InstancePre<WasiCtx>) -> Result<()> {
+    let wasi = WasiCtxBuilder::new()
+        .inherit_stdio()
+        .inherit_args()
+        .unwrap()
+        .build();
+    let mut store = Store::new(&engine, wasi);
+
+    let instance = instance_pre.instantiate(&mut store)?;
+    let gcd = instance.get_typed_func::<(i32, i32), i32>(&mut store, "gcd")?;
+    gcd.call(&mut store, (6, 27))?;
+
+    Ok(())
+}
+
 #[derive(Deserialize, Serialize)]
 struct Data {
     arg_uri: String,
@@ -51,6 +77,21 @@ fn authentication(engine: Engine, module: Module) -> Result<()> {
     Ok(())
 }
 
+fn authentication_pre(engine: Engine, instance_pre: InstancePre<()>) -> Result<()> {
+    let mut store = Store::new(&engine, ());
+    let instance = instance_pre.instantiate(&mut store)?;
+
+    let p: Data = Data {
+        arg_uri: "uri".to_string(),
+        arg_body: "body".to_string(),
+        arg_secret: "fff111fff".to_string(),
+    };
+    let serialized = serde_json::to_string(&p).unwrap();
+    run_module(store, instance, "authentication", &serialized)?;
+
+    Ok(())
+}
+
 fn authentication_wasi(engine: Engine, module: Module, linker: Linker<WasiCtx>) -> Result<()> {
     let wasi = WasiCtxBuilder::new()
         .inherit_stdio()
@@ -71,6 +112,26 @@ fn authentication_wasi(engine: Engine, module: Module, linker: Linker<WasiCtx>)
     Ok(())
 }
 
+fn authentication_wasi_pre(engine: Engine, instance_pre: InstancePre<WasiCtx>) -> Result<()> {
+    let wasi = WasiCtxBuilder::new()
+        .inherit_stdio()
+        .inherit_args()
+        .unwrap()
+        .build();
+    let mut store = Store::new(&engine, wasi);
+    let instance = instance_pre.instantiate(&mut store)?;
+
+    let p: Data = Data {
+        arg_uri: "uri".to_string(),
+        arg_body: "body".to_string(),
+        arg_secret: "fff111fff".to_string(),
+    };
+    let serialized = serde_json::to_string(&p).unwrap();
+    run_module(store, instance, "authentication", &serialized)?;
+
+    Ok(())
+}
+
 fn echo_string_b(engine: Engine, module: Module) -> Result<()> {
     let mut store = Store::new(&engine, ());
     let instance = Instance::new(&mut store, &module, &[])?;
@@ -134,6 +195,12 @@ fn criterion_benchmark(c: &mut Criterion) {
         let module = Module::from_file(&engine, "benches/gcd.wat").unwrap();
         b.iter(|| gcd(engine.clone(), module.clone()))
     });
+    c.bench_function("gcd_pre", |b| {
+        let engine = Engine::default();
+        let module = Module::from_file(&engine, "benches/gcd.wat").unwrap();
+        let instance_pre = Linker::new(&engine).instantiate_pre(&module).unwrap();
+        b.iter(|| gcd_pre(engine.clone(), instance_pre.clone()))
+    });
     c.bench_function("gcd_wasi", |b| {
         let engine = Engine::default();
         let module = Module::from_file(&engine, "benches/gcd.wat").unwrap();
@@ -142,6 +209,15 @@ fn criterion_benchmark(c: &mut Criterion) {
 
         b.iter(|| gcd_wasi(engine.clone(), module.clone(), linker.clone()))
     });
+    c.bench_function("gcd_wasi_pre", |b| {
+        let engine = Engine::default();
+        let module = Module::from_file(&engine, "benches/gcd.wat").unwrap();
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::add_to_linker(&mut linker, |s| s).unwrap();
+        let instance_pre = linker.instantiate_pre(&module).unwrap();
+
+        b.iter(|| gcd_wasi_pre(engine.clone(), instance_pre.clone()))
+    });
     c.bench_function("echo_string_b", |b| {
         let engine = Engine::default();
         let module = Module::from_file(&engine, "benches/echo_string.wat").unwrap();
@@ -160,6 +236,12 @@ fn criterion_benchmark(c: &mut Criterion) {
         let module = Module::from_file(&engine, "benches/authentication.wat").unwrap();
         b.iter(|| authentication(engine.clone(), module.clone()))
     });
+    c.bench_function("authentication_pre", |b| {
+        let engine = Engine::default();
+        let module = Module::from_file(&engine, "benches/authentication.wat").unwrap();
+        let instance_pre = Linker::new(&engine).instantiate_pre(&module).unwrap();
+        b.iter(|| authentication_pre(engine.clone(), instance_pre.clone()))
+    });
     c.bench_function("authentication_wasi", |b| {
         let engine = Engine::default();
         let module = Module::from_file(&engine, "benches/authentication.wat").unwrap();
@@ -168,6 +250,15 @@ fn criterion_benchmark(c: &mut Criterion) {
 
         b.iter(|| authentication_wasi(engine.clone(), module.clone(), linker.clone()))
     });
+    c.bench_function("authentication_wasi_pre", |b| {
+        let engine = Engine::default();
+        let module = Module::from_file(&engine, "benches/authentication.wat").unwrap();
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::add_to_linker(&mut linker, |s| s).unwrap();
+        let instance_pre = linker.instantiate_pre(&module).unwrap();
+
+        b.iter(|| authentication_wasi_pre(engine.clone(), instance_pre.clone()))
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,148 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::{self, Display};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+// Number of past events kept around for clients resuming a watch
+const EVENT_HISTORY_SIZE: usize = 1024;
+
+/// Kind of change made to a function.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FunctionEventKind {
+    Deployed,
+    Updated,
+    Deleted,
+    Loaded,
+    Evicted,
+    Failed,
+}
+
+impl Display for FunctionEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            FunctionEventKind::Deployed => "deployed",
+            FunctionEventKind::Updated => "updated",
+            FunctionEventKind::Deleted => "deleted",
+            FunctionEventKind::Loaded => "loaded",
+            FunctionEventKind::Evicted => "evicted",
+            FunctionEventKind::Failed => "failed",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+/// A change made to a function, `version` is the resource version of the
+/// function store right after the change.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FunctionEvent {
+    pub version: u64,
+    pub kind: FunctionEventKind,
+    pub function: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+struct EventHistory {
+    version: u64,
+    events: VecDeque<FunctionEvent>,
+}
+
+/// Ordered log of function events shared by the `FunctionStore` and the
+/// `ModuleStore`.
+///
+/// Every published event bumps the resource version. Watchers get the events
+/// after a given version from the bounded history, followed by live events.
+#[derive(Clone)]
+pub struct EventLog {
+    history: Arc<Mutex<EventHistory>>,
+    sender: broadcast::Sender<FunctionEvent>,
+}
+
+impl EventLog {
+    /// Create a new empty EventLog
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_HISTORY_SIZE);
+        EventLog {
+            history: Arc::new(Mutex::new(EventHistory {
+                version: 0,
+                events: VecDeque::with_capacity(EVENT_HISTORY_SIZE),
+            })),
+            sender,
+        }
+    }
+
+    /// Record an event and notify the watchers, returns the new resource version.
+    pub fn publish(&self, kind: FunctionEventKind, function: &str, message: Option<String>) -> u64 {
+        let mut history = self.history.lock().unwrap();
+
+        history.version += 1;
+        let event = FunctionEvent {
+            version: history.version,
+            kind,
+            function: function.to_string(),
+            message,
+        };
+
+        if history.events.len() == EVENT_HISTORY_SIZE {
+            history.events.pop_front();
+        }
+        history.events.push_back(event.clone());
+
+        // no receiver is not an error, nobody is watching
+        let _ = self.sender.send(event);
+
+        history.version
+    }
+
+    /// Current resource version.
+    pub fn version(&self) -> u64 {
+        self.history.lock().unwrap().version
+    }
+
+    /// Watch the events published after resource version `since`.
+    ///
+    /// Returns the already published events to replay and the receiver of the
+    /// upcoming ones. Fails if `since` is no longer in the history or comes
+    /// from an earlier run of the engine, the client has to list again.
+    pub fn subscribe(
+        &self,
+        since: u64,
+    ) -> Result<(Vec<FunctionEvent>, broadcast::Receiver<FunctionEvent>)> {
+        let history = self.history.lock().unwrap();
+
+        if since > history.version {
+            return Err(anyhow!(
+                "resource version {} is newer than the current version {}",
+                since,
+                history.version
+            ));
+        }
+        if let Some(oldest) = history.events.front() {
+            if since + 1 < oldest.version {
+                return Err(anyhow!(
+                    "resource version {} is too old, the oldest available is {}",
+                    since,
+                    oldest.version - 1
+                ));
+            }
+        }
+
+        let replay = history
+            .events
+            .iter()
+            .filter(|e| e.version > since)
+            .cloned()
+            .collect();
+
+        Ok((replay, self.sender.subscribe()))
+    }
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
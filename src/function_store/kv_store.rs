@@ -0,0 +1,270 @@
+use crate::wrapper::kv::KvHandle;
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Bytes of keys and values a bucket may hold, unless set for the engine.
+pub const DEFAULT_KV_QUOTA_BYTES: u64 = 1024 * 1024;
+
+// Tree names of the buckets, apart from the sled default tree
+const BUCKET_PREFIX: &str = "bucket/";
+
+/// Durable key-value buckets of the functions, in a sled database next to
+/// the function images.
+///
+/// A function gets the bucket of its namespace, its own name by default, so
+/// functions sharing a namespace share their state. A bucket is only created
+/// by its first write, see `KvStore::lazy_bucket`. Buckets outlive their
+/// functions, a redeployed function finds its state again, they are only
+/// emptied through `KvBucket::clear`.
+///
+/// sled blocks on I/O, callers on an async runtime use its blocking threads.
+#[derive(Clone, Debug)]
+pub struct KvStore {
+    db: sled::Db,
+    quota: u64,
+    buckets: Arc<Mutex<HashMap<String, KvBucket>>>,
+}
+
+/// Usage of a bucket, as reported by the admin API.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BucketInfo {
+    pub name: String,
+    pub keys: usize,
+    pub bytes: u64,
+    pub quota: u64,
+}
+
+impl KvStore {
+    /// Open the store at `path`, where every bucket may hold `quota` bytes.
+    pub fn open<P: AsRef<Path>>(path: P, quota: u64) -> Result<Self> {
+        Ok(KvStore {
+            db: sled::open(path)?,
+            quota,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Bucket named `name`, created on first use.
+    pub fn bucket(&self, name: &str) -> Result<KvBucket> {
+        if name.is_empty() {
+            return Err(anyhow!("bucket name can't be empty"));
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.get(name) {
+            return Ok(bucket.clone());
+        }
+        let tree = self.db.open_tree(format!("{}{}", BUCKET_PREFIX, name))?;
+        let used = tree
+            .iter()
+            .map(|entry| entry.map(|(key, value)| (key.len() + value.len()) as u64))
+            .sum::<sled::Result<u64>>()?;
+        let bucket = KvBucket {
+            name: name.to_string(),
+            tree,
+            quota: self.quota,
+            used: Arc::new(Mutex::new(used)),
+        };
+        buckets.insert(name.to_string(), bucket.clone());
+        Ok(bucket)
+    }
+
+    /// Handle on the bucket named `name` that opens it on first use, without
+    /// blocking on I/O until then.
+    pub fn lazy_bucket(&self, name: &str) -> LazyBucket {
+        LazyBucket {
+            store: self.clone(),
+            name: name.to_string(),
+            bucket: OnceLock::new(),
+        }
+    }
+
+    /// Usage of every bucket, sorted by name. A bucket exists once opened
+    /// with `bucket` or written through a `LazyBucket`.
+    pub fn buckets(&self) -> Result<Vec<BucketInfo>> {
+        let mut names: Vec<String> = self
+            .db
+            .tree_names()
+            .iter()
+            .filter_map(|name| std::str::from_utf8(name).ok()?.strip_prefix(BUCKET_PREFIX))
+            .map(str::to_string)
+            .collect();
+        names.sort();
+        names
+            .iter()
+            .map(|name| Ok(self.bucket(name)?.info()))
+            .collect()
+    }
+
+    /// Returns true if a bucket named `name` exists, see `buckets`.
+    pub fn exist(&self, name: &str) -> bool {
+        self.db
+            .tree_names()
+            .iter()
+            .any(|tree| tree.as_ref() == format!("{}{}", BUCKET_PREFIX, name).as_bytes())
+    }
+}
+
+/// Keys and values of a bucket, bounded by its quota.
+#[derive(Clone, Debug)]
+pub struct KvBucket {
+    name: String,
+    tree: sled::Tree,
+    quota: u64,
+    // bytes of the keys and values, writes are checked against the quota
+    // while holding it
+    used: Arc<Mutex<u64>>,
+}
+
+impl KvBucket {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.tree.get(key)?.map(|value| value.to_vec()))
+    }
+
+    /// Set `key` to `value`, returns false without writing if the bucket
+    /// would exceed its quota.
+    pub fn set(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        let mut used = self.used.lock().unwrap();
+        let old = self
+            .tree
+            .get(key)?
+            .map_or(0, |old| (key.len() + old.len()) as u64);
+        let new = (key.len() + value.len()) as u64;
+        let total = *used - old + new;
+        if new > old && total > self.quota {
+            return Ok(false);
+        }
+        self.tree.insert(key, value)?;
+        *used = total;
+        Ok(true)
+    }
+
+    /// Remove `key`, returns false if it wasn't set.
+    pub fn delete(&self, key: &[u8]) -> Result<bool> {
+        let mut used = self.used.lock().unwrap();
+        match self.tree.remove(key)? {
+            Some(old) => {
+                *used -= (key.len() + old.len()) as u64;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Keys starting with `prefix`, in order.
+    pub fn keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.tree
+            .scan_prefix(prefix)
+            .keys()
+            .map(|key| Ok(key?.to_vec()))
+            .collect()
+    }
+
+    /// Keys starting with `prefix` with their values, in order.
+    pub fn entries(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.tree
+            .scan_prefix(prefix)
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((key.to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+
+    /// Remove every key.
+    pub fn clear(&self) -> Result<()> {
+        let mut used = self.used.lock().unwrap();
+        self.tree.clear()?;
+        *used = 0;
+        Ok(())
+    }
+
+    pub fn info(&self) -> BucketInfo {
+        let used = self.used.lock().unwrap();
+        BucketInfo {
+            name: self.name.clone(),
+            keys: self.tree.len(),
+            bytes: *used,
+            quota: self.quota,
+        }
+    }
+}
+
+impl KvHandle for KvBucket {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        KvBucket::get(self, key)
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        KvBucket::set(self, key, value)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<bool> {
+        KvBucket::delete(self, key)
+    }
+
+    fn keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>> {
+        KvBucket::keys(self, prefix)
+    }
+}
+
+/// Bucket of an invocation, opened by its first key-value call. Reads of a
+/// bucket that was never written don't create it.
+#[derive(Debug)]
+pub struct LazyBucket {
+    store: KvStore,
+    name: String,
+    bucket: OnceLock<KvBucket>,
+}
+
+impl LazyBucket {
+    // The bucket, created if it doesn't exist yet
+    fn open(&self) -> Result<&KvBucket> {
+        if let Some(bucket) = self.bucket.get() {
+            return Ok(bucket);
+        }
+        let bucket = self.store.bucket(&self.name)?;
+        Ok(self.bucket.get_or_init(|| bucket))
+    }
+
+    // The bucket, `None` if it was never written
+    fn existing(&self) -> Result<Option<&KvBucket>> {
+        if self.bucket.get().is_none() && !self.store.exist(&self.name) {
+            return Ok(None);
+        }
+        self.open().map(Some)
+    }
+}
+
+impl KvHandle for LazyBucket {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.existing()? {
+            Some(bucket) => bucket.get(key),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.open()?.set(key, value)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<bool> {
+        match self.existing()? {
+            Some(bucket) => bucket.delete(key),
+            None => Ok(false),
+        }
+    }
+
+    fn keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>> {
+        match self.existing()? {
+            Some(bucket) => bucket.keys(prefix),
+            None => Ok(Vec::new()),
+        }
+    }
+}
@@ -1,24 +1,138 @@
+use super::events::{EventLog, FunctionEventKind};
 use super::pull;
+use super::selector::LabelSelector;
+use crate::wrapper::config::{FunctionConfig, ResourceLimits};
+use crate::wrapper::input::WasiInput;
+use crate::wrapper::instance::ReusePolicy;
+use crate::wrapper::kind::FunctionKind;
 use anyhow::{anyhow, Ok, Result};
+use futures_util::{stream, Future, StreamExt};
 use oci_distribution::client::ClientConfig;
 use oci_distribution::{secrets::RegistryAuth, Client, Reference};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Display};
 use std::fs::read_dir;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::RwLock;
-use tracing::{self, debug, info};
+use tracing::{self, debug, info, warn};
+
+/// Image annotation (or config label) naming the wasm module file inside the image.
+pub const MODULE_PATH_ANNOTATION: &str = "io.wasmengine.module.path";
+
+// Every binary wasm module starts with the `\0asm` magic bytes
+const WASM_MAGIC: [u8; 4] = *b"\0asm";
+
+// Subdirectory of a function directory the image is unpacked in, the files
+// the engine derives from the image are kept outside of it
+const IMAGE_DIR: &str = "image";
+
+// Key spaces of the buckets of a single function and of a namespace
+const FUNCTION_BUCKET_PREFIX: &str = "fn/";
+const NAMESPACE_BUCKET_PREFIX: &str = "ns/";
+
+/// Options given at deploy time in addition to the function name and image.
+#[derive(Clone, Default, Debug)]
+pub struct DeployOptions {
+    /// Path of the wasm module relative to the image root.
+    pub module_path: Option<String>,
+    /// Guest path the unpacked image files are mounted at read-only, WASI
+    /// functions only.
+    pub data_mount: Option<String>,
+    /// Identifying labels, usable in label selectors.
+    pub labels: HashMap<String, String>,
+    /// Arbitrary non-identifying metadata.
+    pub annotations: HashMap<String, String>,
+    /// Human readable description of the function.
+    pub description: Option<String>,
+    /// Seconds without invocation before the compiled module is evicted.
+    pub idle_timeout: Option<u64>,
+    /// Serve a snapshot taken after running the `wizer.initialize` export.
+    pub pre_initialize: bool,
+    /// Keep instances warm between invocations, plain modules only.
+    pub instance_reuse: Option<ReusePolicy>,
+    /// Compute budget of an invocation in units of 100k instructions.
+    pub max_fuel: Option<u64>,
+    /// Wall-clock deadline of an invocation in milliseconds.
+    pub timeout_ms: Option<u64>,
+    /// Resources an instance of the function may hold.
+    pub limits: Option<ResourceLimits>,
+    /// How the arguments are handed to a WASI function.
+    pub wasi_input: Option<WasiInput>,
+    /// Export called by default, the function name if unset.
+    pub entrypoint: Option<String>,
+    /// Exports invoked as `<function>/<handler>`, by handler name.
+    pub handlers: HashMap<String, String>,
+    /// Key-value bucket of the function, its name if unset.
+    pub kv_namespace: Option<String>,
+}
+
+/// A host directory made visible to the guest under `guest_dir`, read-only.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct DataMount {
+    pub host_dir: String,
+    pub guest_dir: String,
+}
+
+/// Whether a function can currently be invoked.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FunctionStatus {
+    #[default]
+    Available,
+    /// The function failed validation, with the reason.
+    Unavailable(String),
+}
 
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct FunctionEntry {
     pub func_name: String,
     pub func_image_name: String,
     pub func_local_path: String,
+    // holds the image unpacked in its `image` subdirectory, missing for
+    // functions whose image was unpacked in it directly
+    #[serde(default)]
+    pub func_dir: Option<String>,
     pub wasi_cap: bool,
+    #[serde(default)]
+    pub func_data_mount: Option<DataMount>,
+    // detected at deploy time, missing for functions deployed before detection
+    #[serde(default)]
+    pub kind: Option<FunctionKind>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub idle_timeout: Option<u64>,
+    #[serde(default)]
+    pub pre_initialize: bool,
+    #[serde(default)]
+    pub instance_reuse: Option<ReusePolicy>,
+    #[serde(default)]
+    pub max_fuel: Option<u64>,
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub limits: Option<ResourceLimits>,
+    #[serde(default)]
+    pub wasi_input: Option<WasiInput>,
+    #[serde(default)]
+    pub entrypoint: Option<String>,
+    #[serde(default)]
+    pub handlers: HashMap<String, String>,
+    #[serde(default)]
+    pub kv_namespace: Option<String>,
+    // status is re-evaluated on every start, never restore it
+    #[serde(default, skip_deserializing)]
+    pub status: FunctionStatus,
 }
 
 impl FunctionEntry {
@@ -27,8 +141,113 @@ impl FunctionEntry {
             func_name: name.to_string(),
             func_image_name: image_name.to_string(),
             func_local_path: path.to_string(),
+            func_dir: None,
             wasi_cap: cap,
+            func_data_mount: None,
+            kind: None,
+            labels: HashMap::new(),
+            annotations: HashMap::new(),
+            description: None,
+            idle_timeout: None,
+            pre_initialize: false,
+            instance_reuse: None,
+            max_fuel: None,
+            timeout_ms: None,
+            limits: None,
+            wasi_input: None,
+            entrypoint: None,
+            handlers: HashMap::new(),
+            kv_namespace: None,
+            status: FunctionStatus::Available,
+        }
+    }
+
+    /// Time without invocation before the compiled module is evicted.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout.map(Duration::from_secs)
+    }
+
+    /// Export called when no handler is asked for.
+    pub fn entrypoint(&self) -> &str {
+        self.entrypoint.as_deref().unwrap_or(&self.func_name)
+    }
+
+    /// Export invoked for `handler`, the entrypoint without one.
+    pub fn handler_export(&self, handler: Option<&str>) -> Result<&str> {
+        match handler {
+            None => Ok(self.entrypoint()),
+            Some(handler) => self
+                .handlers
+                .get(handler)
+                .map(String::as_str)
+                .ok_or_else(|| anyhow!("function {} has no handler `{}`", self.func_name, handler)),
+        }
+    }
+
+    /// Whether `export` can be called with typed parameters, any export
+    /// unless the function declares handlers, then only the entrypoint and
+    /// the handler exports.
+    pub fn exposes(&self, export: &str) -> bool {
+        self.handlers.is_empty()
+            || export == self.entrypoint()
+            || self.handlers.values().any(|handler| handler == export)
+    }
+
+    /// Key-value bucket the function works on, `fn/<function>` or
+    /// `ns/<namespace>`: a namespace can't name the bucket of a function.
+    pub fn kv_bucket(&self) -> String {
+        match &self.kv_namespace {
+            Some(namespace) => format!("{}{}", NAMESPACE_BUCKET_PREFIX, namespace),
+            None => format!("{}{}", FUNCTION_BUCKET_PREFIX, self.func_name),
+        }
+    }
+
+    /// Path of the pre-initialized snapshot of the module, kept in the
+    /// function directory outside of the image.
+    pub fn initialized_path(&self) -> Option<String> {
+        if !self.pre_initialize {
+            return None;
+        }
+        let path = Path::new(&self.func_local_path);
+        let stem = path.file_stem()?.to_str()?;
+        let dir = match &self.func_dir {
+            Some(dir) => Path::new(dir),
+            None => path.parent()?,
+        };
+        Some(
+            dir.join(format!("{}.initialized.wasm", stem))
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+
+    /// Path of the module invocations are served from.
+    pub fn module_path(&self) -> String {
+        self.initialized_path()
+            .unwrap_or_else(|| self.func_local_path.clone())
+    }
+
+    /// Build the runtime configuration used to invoke this function.
+    pub fn function_config(&self) -> FunctionConfig {
+        let mut config = FunctionConfig::new();
+        if let Some(mount) = &self.func_data_mount {
+            config.preopen_read_only_dir_as(mount.host_dir.as_str(), mount.guest_dir.as_str());
+        }
+        if let Some(max_fuel) = self.max_fuel {
+            config.set_max_fuel(max_fuel);
+        }
+        if let Some(timeout_ms) = self.timeout_ms {
+            config.set_timeout(Duration::from_millis(timeout_ms));
+        }
+        if let Some(limits) = &self.limits {
+            config.set_limits(limits.clone());
+        }
+        if let Some(wasi_input) = &self.wasi_input {
+            config.set_wasi_input(wasi_input.clone());
         }
+        config.set_program_name(self.func_name.as_str());
+        config.set_function_name(self.func_name.as_str());
+        config
     }
 }
 
@@ -50,11 +269,33 @@ impl Display for FunctionEntries {
     }
 }
 
+/// Name of a function being deployed, reserved in its `FunctionStore` until
+/// dropped. Registering the function must happen before that.
+pub struct Reservation {
+    name: String,
+    deploying: Arc<std::sync::Mutex<HashSet<String>>>,
+}
+
+impl Reservation {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.deploying.lock().unwrap().remove(&self.name);
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct FunctionStore {
     function_list: Arc<RwLock<HashMap<String, FunctionEntry>>>,
+    // names reserved by the deploys in progress
+    deploying: Arc<std::sync::Mutex<HashSet<String>>>,
     function_store_path: String,
     function_persist_path: String,
+    events: EventLog,
 }
 
 impl FunctionStore {
@@ -68,11 +309,23 @@ impl FunctionStore {
 
         FunctionStore {
             function_list: Arc::new(RwLock::new(HashMap::new())),
+            deploying: Arc::new(std::sync::Mutex::new(HashSet::new())),
             function_store_path: path.to_string(),
             function_persist_path: persist_path,
+            events: EventLog::new(),
         }
     }
 
+    /// Publish the function events into `events` instead of a private log.
+    pub fn with_events(mut self, events: EventLog) -> Self {
+        self.events = events;
+        self
+    }
+
+    pub fn events(&self) -> &EventLog {
+        &self.events
+    }
+
     pub async fn exist(&self, func_name: &str) -> bool {
         let func_list = self.function_list.read().await;
 
@@ -85,21 +338,76 @@ impl FunctionStore {
 
     /// Add module into the function store under a specific function name, and wasi capabilites.
     pub async fn add(&self, function_name: &str, image_name: &str, wasi_cap: bool) -> Result<()> {
-        let mut writer = self.function_list.write().await;
+        self.add_with_options(
+            function_name,
+            image_name,
+            wasi_cap,
+            &DeployOptions::default(),
+        )
+        .await
+    }
 
-        let func_store_dir = Path::new(&self.function_store_path)
-            .join(function_name)
-            .into_os_string()
-            .into_string()
-            .unwrap();
+    /// Add module into the function store like `add`, with extra deploy options.
+    pub async fn add_with_options(
+        &self,
+        function_name: &str,
+        image_name: &str,
+        wasi_cap: bool,
+        options: &DeployOptions,
+    ) -> Result<()> {
+        let reservation = self.reserve(function_name).await?;
+        let mut entry = self.pull(&reservation, image_name, options).await?;
+        entry.wasi_cap = wasi_cap;
+
+        self.insert(entry).await
+    }
+
+    /// Reserve the name of a function to deploy, failing while the function
+    /// exists or another deploy holds the name.
+    pub async fn reserve(&self, function_name: &str) -> Result<Reservation> {
+        // holding the list keeps the function from being registered meanwhile
+        let reader = self.function_list.read().await;
+        let mut deploying = self.deploying.lock().unwrap();
 
-        if writer.contains_key(function_name) {
-            tracing::error!("function image already exist");
+        if reader.contains_key(function_name) {
             return Err(anyhow!(
                 "function {} already exist in the local function store",
                 function_name
             ));
         }
+        if !deploying.insert(function_name.to_string()) {
+            return Err(anyhow!(
+                "function {} is already being deployed",
+                function_name
+            ));
+        }
+
+        Ok(Reservation {
+            name: function_name.to_string(),
+            deploying: self.deploying.clone(),
+        })
+    }
+
+    /// Pull the function image into the function store dir and build its
+    /// entry, without registering the function yet.
+    pub async fn pull(
+        &self,
+        reservation: &Reservation,
+        image_name: &str,
+        options: &DeployOptions,
+    ) -> Result<FunctionEntry> {
+        let function_name = reservation.name();
+        let func_store_dir = self.function_dir(function_name);
+        let image_dir = Path::new(&func_store_dir)
+            .join(IMAGE_DIR)
+            .into_os_string()
+            .into_string()
+            .unwrap();
+
+        // unpacking merges into the directory, drop files a previous image left
+        if Path::new(&image_dir).exists() {
+            std::fs::remove_dir_all(&image_dir)?;
+        }
 
         let mut client = Client::new(ClientConfig::default());
         let reference: Reference = image_name
@@ -111,57 +419,118 @@ impl FunctionStore {
             &mut client,
             &RegistryAuth::Anonymous,
             &reference,
-            func_store_dir.as_str(),
+            image_dir.as_str(),
         )
         .await;
 
-        if !pull_result.is_ok() {
-            let mut client = Client::new(pull::build_client_config(true));
-            pull::pull_wasm(
-                &mut client,
-                &RegistryAuth::Anonymous,
-                &reference,
-                func_store_dir.as_str(),
-            )
-            .await
-            .map_err(|err| {
-                anyhow::format_err!(
-                    "Pull image both failed with https auth: {}, and insecure http: {}",
-                    pull_result.unwrap_err(),
-                    err
+        let annotations = match pull_result {
+            Result::Ok(annotations) => annotations,
+            Err(https_err) => {
+                let mut client = Client::new(pull::build_client_config(true));
+                pull::pull_wasm(
+                    &mut client,
+                    &RegistryAuth::Anonymous,
+                    &reference,
+                    image_dir.as_str(),
                 )
-            })?;
+                .await
+                .map_err(|err| {
+                    anyhow::format_err!(
+                        "Pull image both failed with https auth: {}, and insecure http: {}",
+                        https_err,
+                        err
+                    )
+                })?
+            }
+        };
+
+        let func_wasm_file_path = select_module(
+            Path::new(&image_dir),
+            options.module_path.as_deref(),
+            &annotations,
+        )?
+        .into_os_string()
+        .into_string()
+        .unwrap();
+
+        let mut entry = FunctionEntry::new(
+            function_name,
+            image_name,
+            func_wasm_file_path.as_str(),
+            false,
+        );
+        entry.func_dir = Some(func_store_dir.clone());
+        if let Some(guest_dir) = &options.data_mount {
+            entry.func_data_mount = Some(DataMount {
+                host_dir: Path::new(&image_dir)
+                    .canonicalize()?
+                    .into_os_string()
+                    .into_string()
+                    .unwrap(),
+                guest_dir: guest_dir.clone(),
+            });
         }
 
-        // only one wasm module file should be in the func_store_dir
-        if read_dir(func_store_dir.clone()).unwrap().count() != 1 {
+        entry.labels = options.labels.clone();
+        entry.annotations = options.annotations.clone();
+        entry.description = options.description.clone();
+        entry.idle_timeout = options.idle_timeout;
+        entry.pre_initialize = options.pre_initialize;
+        entry.instance_reuse = options.instance_reuse.clone();
+        entry.max_fuel = options.max_fuel;
+        entry.timeout_ms = options.timeout_ms;
+        entry.limits = options.limits.clone();
+        entry.wasi_input = options.wasi_input.clone();
+        entry.entrypoint = options.entrypoint.clone();
+        entry.handlers = options.handlers.clone();
+        entry.kv_namespace = options.kv_namespace.clone();
+
+        Ok(entry)
+    }
+
+    /// Register a function entry built by `pull`.
+    pub async fn insert(&self, entry: FunctionEntry) -> Result<()> {
+        let mut writer = self.function_list.write().await;
+
+        if writer.contains_key(&entry.func_name) {
             return Err(anyhow!(
-                "only one wasm module file under the {} function stor dir",
-                func_store_dir.as_str()
+                "function {} already exist in the local function store",
+                entry.func_name
             ));
         }
 
-        let store_path = read_dir(func_store_dir.clone()).unwrap().next().unwrap()?;
-        let func_wasm_file_path = store_path
-            .path()
-            .canonicalize()?
-            .into_os_string()
-            .into_string()
-            .unwrap();
+        let function_name = entry.func_name.clone();
+        writer.insert(function_name.clone(), entry);
+        self.events
+            .publish(FunctionEventKind::Deployed, &function_name, None);
 
-        writer.insert(
-            function_name.to_string(),
-            FunctionEntry::new(
-                function_name,
-                image_name,
-                func_wasm_file_path.as_str(),
-                wasi_cap,
-            ),
-        );
+        Ok(())
+    }
+
+    /// Remove the pulled image files of a function whose deploy failed.
+    pub fn remove_files(&self, reservation: &Reservation) -> Result<()> {
+        let func_store_dir = self.function_dir(reservation.name());
+        if Path::new(&func_store_dir).exists() {
+            std::fs::remove_dir_all(&func_store_dir)?;
+        }
 
         Ok(())
     }
 
+    /// Directory of the key-value store of the functions, next to their
+    /// images.
+    pub fn kv_path(&self) -> PathBuf {
+        Path::new(&self.function_store_path).join(".kv")
+    }
+
+    fn function_dir(&self, function_name: &str) -> String {
+        Path::new(&self.function_store_path)
+            .join(function_name)
+            .into_os_string()
+            .into_string()
+            .unwrap()
+    }
+
     pub async fn delete(&self, func_name: &str) -> Result<()> {
         let mut writer = self.function_list.write().await;
 
@@ -172,11 +541,85 @@ impl FunctionStore {
             ));
         }
 
+        // a redeploy under the same name starts from an empty directory
+        let func_store_dir = self.function_dir(func_name);
+        if Path::new(&func_store_dir).exists() {
+            std::fs::remove_dir_all(&func_store_dir)?;
+        }
+
         writer.remove(func_name).unwrap();
+        self.events
+            .publish(FunctionEventKind::Deleted, func_name, None);
+
+        Ok(())
+    }
+
+    /// Update the status of a function, e.g. after validating it.
+    pub async fn set_status(&self, func_name: &str, status: FunctionStatus) -> Result<()> {
+        let mut writer = self.function_list.write().await;
+
+        let entry = writer.get_mut(func_name).ok_or_else(|| {
+            anyhow!(
+                "request update func {} not exist in the local store",
+                func_name
+            )
+        })?;
+        let (kind, message) = match &status {
+            FunctionStatus::Available => (FunctionEventKind::Updated, None),
+            FunctionStatus::Unavailable(reason) => {
+                (FunctionEventKind::Failed, Some(reason.clone()))
+            }
+        };
+        entry.status = status;
+        self.events.publish(kind, func_name, message);
 
         Ok(())
     }
 
+    /// Validate every function with `load`, at most `concurrency` at a time.
+    /// Functions that fail are marked unavailable with the failure reason,
+    /// their names are returned.
+    pub async fn warm_up<F, Fut>(&self, concurrency: usize, load: F) -> Result<Vec<String>>
+    where
+        F: Fn(FunctionEntry) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let funcs = self.list().await?;
+        info!("warming up {} functions", funcs.len());
+
+        let failures = stream::iter(funcs)
+            .map(|func| {
+                let name = func.func_name.clone();
+                let loaded = load(func);
+                async move { (name, loaded.await) }
+            })
+            .buffer_unordered(concurrency.max(1))
+            .filter_map(|(name, loaded)| async move {
+                match loaded {
+                    Result::Ok(()) => {
+                        info!("function {} warmed up", name);
+                        None
+                    }
+                    Err(err) => {
+                        warn!("function {} failed validation: {:?}", name, err);
+                        Some((name, format!("{:#}", err)))
+                    }
+                }
+            })
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut names = Vec::new();
+        for (name, reason) in failures {
+            self.set_status(&name, FunctionStatus::Unavailable(reason))
+                .await?;
+            names.push(name);
+        }
+        info!("function warm-up finished");
+
+        Ok(names)
+    }
+
     pub async fn list(&self) -> Result<Vec<FunctionEntry>> {
         let reader = self.function_list.read().await;
         let mut funcs_vec: Vec<FunctionEntry> = Vec::new();
@@ -188,6 +631,17 @@ impl FunctionStore {
         Ok(funcs_vec)
     }
 
+    /// List the functions whose labels match the `selector`.
+    pub async fn select(&self, selector: &LabelSelector) -> Result<Vec<FunctionEntry>> {
+        let reader = self.function_list.read().await;
+
+        Ok(reader
+            .values()
+            .filter(|entry| selector.matches(&entry.labels))
+            .cloned()
+            .collect())
+    }
+
     pub async fn query(&self, func_name: &str) -> Result<FunctionEntry> {
         let reader = self.function_list.read().await;
 
@@ -256,3 +710,74 @@ impl FunctionStore {
         Ok(())
     }
 }
+
+/// Pick the wasm module file under the unpacked image directory `dir`.
+///
+/// The `module_path` of the deploy request wins over the one of the image
+/// `annotations`, and is resolved relative to `dir`. Without either the
+/// directory is searched for files starting with the wasm magic bytes and
+/// exactly one of them must be found.
+pub fn select_module(
+    dir: &Path,
+    module_path: Option<&str>,
+    annotations: &HashMap<String, String>,
+) -> Result<PathBuf> {
+    let dir = dir.canonicalize()?;
+    let module_path =
+        module_path.or_else(|| annotations.get(MODULE_PATH_ANNOTATION).map(|p| p.as_str()));
+
+    if let Some(module_path) = module_path {
+        let path = dir
+            .join(module_path.trim_start_matches('/'))
+            .canonicalize()
+            .map_err(|err| anyhow!("module path {} not found in image: {}", module_path, err))?;
+        if !path.starts_with(&dir) || !path.is_file() {
+            return Err(anyhow!(
+                "module path {} is not a file inside the image",
+                module_path
+            ));
+        }
+        return Ok(path);
+    }
+
+    let mut modules = Vec::new();
+    find_wasm_modules(&dir, &mut modules)?;
+
+    match modules.len() {
+        0 => Err(anyhow!(
+            "no wasm module file found under the {} function store dir",
+            dir.display()
+        )),
+        1 => Ok(modules.remove(0)),
+        _ => Err(anyhow!(
+            "found {} wasm module files under the {} function store dir, set module_path or the {} annotation to choose one",
+            modules.len(),
+            dir.display(),
+            MODULE_PATH_ANNOTATION
+        )),
+    }
+}
+
+fn find_wasm_modules(dir: &Path, modules: &mut Vec<PathBuf>) -> Result<()> {
+    // symlinks are not followed, they could lead out of the image
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            find_wasm_modules(&path, modules)?;
+        } else if file_type.is_file() && is_wasm_module(&path) {
+            modules.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn is_wasm_module(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    match std::fs::File::open(path) {
+        Result::Ok(mut file) => file.read_exact(&mut magic).is_ok() && magic == WASM_MAGIC,
+        Err(_) => false,
+    }
+}
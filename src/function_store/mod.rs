@@ -1,3 +1,6 @@
+pub mod events;
+pub mod kv_store;
 pub mod local_store;
 pub mod module_store;
 pub mod pull;
+pub mod selector;
@@ -1,14 +1,56 @@
+use super::events::{EventLog, FunctionEventKind};
+use crate::wrapper::instance::{Compiled, PreparedInstance};
 use anyhow::{anyhow, Result};
+use futures_util::future::{BoxFuture, FutureExt, Shared};
+use serde::Serialize;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, sync::Arc, sync::RwLock};
 use tracing::info;
-use wasmtime::Module;
+
+/// Residency budget of the ModuleStore, `None` means unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct ModuleLimits {
+    /// Maximum number of resident modules.
+    pub max_entries: Option<usize>,
+    /// Maximum size in bytes of the resident compiled modules.
+    pub max_memory: Option<usize>,
+    /// Evict modules not used for this long, unless set per module.
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Snapshot of the ModuleStore residency and eviction counters.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ModuleStoreStats {
+    pub resident_entries: usize,
+    pub resident_bytes: usize,
+    pub lru_evictions: u64,
+    pub idle_evictions: u64,
+}
+
+// A module load shared by all the callers waiting for it
+type LoadFlight = Shared<BoxFuture<'static, std::result::Result<ModuleEntry, Arc<anyhow::Error>>>>;
+
+#[derive(Default)]
+struct EvictionCounters {
+    lru: AtomicU64,
+    idle: AtomicU64,
+}
 
 /// A memory store for storing `Wasm Modules`.
 ///
-/// Modules are registered by `name`
+/// Modules are registered by `name`. When `ModuleLimits` are set the least
+/// recently used modules are evicted to stay within budget, and idle modules
+/// are evicted by `evict_idle`. Evicted modules are loaded again on next use.
 #[derive(Clone, Default)]
 pub struct ModuleStore {
     module_store: Arc<RwLock<HashMap<String, ModuleEntry>>>,
+    loading: Arc<Mutex<HashMap<String, LoadFlight>>>,
+    limits: Arc<RwLock<ModuleLimits>>,
+    evictions: Arc<EvictionCounters>,
+    events: EventLog,
 }
 
 impl ModuleStore {
@@ -16,11 +58,33 @@ impl ModuleStore {
     pub fn new() -> Self {
         ModuleStore {
             module_store: Arc::new(RwLock::new(HashMap::new())),
+            loading: Arc::new(Mutex::new(HashMap::new())),
+            limits: Arc::new(RwLock::new(ModuleLimits::default())),
+            evictions: Arc::new(EvictionCounters::default()),
+            events: EventLog::new(),
         }
     }
 
+    /// Publish the module events into `events` instead of a private log.
+    pub fn with_events(mut self, events: EventLog) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Set the residency budget, applied from the next insert.
+    pub fn set_limits(&self, limits: ModuleLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
     /// Insert module into the ModuleStore under a specific name, module and wasi capabilites.
-    pub fn insert(&self, name: &str, module: Module, wasi_cap: bool) -> Result<()> {
+    pub fn insert(&self, name: &str, module: impl Into<Compiled>, wasi_cap: bool) -> Result<()> {
+        self.insert_entry(ModuleEntry::new(name.to_string(), module, wasi_cap))
+    }
+
+    /// Insert a module entry built with `ModuleEntry::new` and its setters.
+    pub fn insert_entry(&self, entry: ModuleEntry) -> Result<()> {
+        let name = entry.name.clone();
+        let name = name.as_str();
         let mut writer = self.module_store.write().unwrap();
 
         if writer.contains_key(name) {
@@ -28,10 +92,36 @@ impl ModuleStore {
             return Ok(());
         }
 
-        writer.insert(
-            name.to_string(),
-            ModuleEntry::new(name.to_string(), module, wasi_cap),
-        );
+        writer.insert(name.to_string(), entry);
+        self.events.publish(FunctionEventKind::Loaded, name, None);
+
+        // make room for the new module by evicting the least recently used ones
+        let limits = self.limits.read().unwrap().clone();
+        loop {
+            let resident_bytes: usize = writer.values().map(|e| e.size).sum();
+            let over_entries = limits.max_entries.is_some_and(|max| writer.len() > max);
+            let over_memory = limits.max_memory.is_some_and(|max| resident_bytes > max);
+            if !over_entries && !over_memory {
+                break;
+            }
+
+            let lru = writer
+                .values()
+                .filter(|e| e.name != name)
+                .min_by_key(|e| e.last_used())
+                .map(|e| e.name.clone());
+            match lru {
+                Some(lru) => {
+                    writer.remove(&lru);
+                    self.evictions.lru.fetch_add(1, Ordering::Relaxed);
+                    self.events
+                        .publish(FunctionEventKind::Evicted, &lru, Some("lru".to_string()));
+                    info!("evicted least recently used module {}", lru);
+                }
+                // the new module alone exceeds the budget, keep it anyway
+                None => break,
+            }
+        }
 
         Ok(())
     }
@@ -47,21 +137,69 @@ impl ModuleStore {
         }
 
         writer.remove(name).unwrap();
+        self.events.publish(FunctionEventKind::Evicted, name, None);
 
         Ok(())
     }
 
-    /// Returns module with specified name
+    /// Returns module with specified name, and marks it as used
     pub fn get(&self, name: &str) -> Result<ModuleEntry> {
         let reader = self.module_store.read().unwrap();
 
-        if !reader.contains_key(name) {
-            return Err(anyhow::format_err!("failed to find module in module store"));
+        let v = reader
+            .get(name)
+            .ok_or_else(|| anyhow::format_err!("failed to find module in module store"))?;
+        *v.last_used.lock().unwrap() = Instant::now();
+
+        Ok(v.clone())
+    }
+
+    /// Returns module with specified name, running `load` to insert it first
+    /// if it isn't in the store.
+    ///
+    /// Concurrent callers for the same module share a single `load` run, and
+    /// all of them get its error if it fails.
+    pub async fn get_or_load<F, Fut>(&self, name: &str, load: F) -> Result<ModuleEntry>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        if let Ok(entry) = self.get(name) {
+            return Ok(entry);
         }
 
-        let v = reader.get(name).unwrap();
+        let flight = {
+            let mut loading = self.loading.lock().unwrap();
 
-        Ok(v.clone())
+            match loading.get(name) {
+                Some(flight) => flight.clone(),
+                None => {
+                    // a flight is only removed after its insert, check again
+                    if let Ok(entry) = self.get(name) {
+                        return Ok(entry);
+                    }
+
+                    let store = self.clone();
+                    let key = name.to_string();
+                    let load = load();
+                    let flight = async move {
+                        let result = match load.await {
+                            Ok(()) => store.get(&key),
+                            Err(err) => Err(err),
+                        };
+                        store.loading.lock().unwrap().remove(&key);
+                        result.map_err(Arc::new)
+                    }
+                    .boxed()
+                    .shared();
+
+                    loading.insert(name.to_string(), flight.clone());
+                    flight
+                }
+            }
+        };
+
+        flight.await.map_err(|err| anyhow!("{:#}", err))
     }
 
     pub fn exist(&self, name: &str) -> bool {
@@ -73,26 +211,105 @@ impl ModuleStore {
 
         return false;
     }
+
+    /// Evict the modules idle for longer than their idle timeout, returns the
+    /// names of the evicted modules.
+    pub fn evict_idle(&self) -> Vec<String> {
+        let default_timeout = self.limits.read().unwrap().idle_timeout;
+        let mut writer = self.module_store.write().unwrap();
+
+        let idle: Vec<String> = writer
+            .values()
+            .filter(|e| {
+                e.idle_timeout
+                    .or(default_timeout)
+                    .is_some_and(|timeout| e.last_used().elapsed() >= timeout)
+            })
+            .map(|e| e.name.clone())
+            .collect();
+
+        for name in idle.iter() {
+            writer.remove(name);
+            self.evictions.idle.fetch_add(1, Ordering::Relaxed);
+            self.events
+                .publish(FunctionEventKind::Evicted, name, Some("idle".to_string()));
+            info!("evicted idle module {}", name);
+        }
+
+        idle
+    }
+
+    /// Current residency and eviction counters.
+    pub fn stats(&self) -> ModuleStoreStats {
+        let reader = self.module_store.read().unwrap();
+
+        ModuleStoreStats {
+            resident_entries: reader.len(),
+            resident_bytes: reader.values().map(|e| e.size).sum(),
+            lru_evictions: self.evictions.lru.load(Ordering::Relaxed),
+            idle_evictions: self.evictions.idle.load(Ordering::Relaxed),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct ModuleEntry {
     name: String,
-    module: Module,
+    compiled: Compiled,
     wasi_cap: bool,
+    instance_pre: Option<PreparedInstance>,
+    // size of the compiled code and data image
+    size: usize,
+    idle_timeout: Option<Duration>,
+    // shared by the clones, so marking it used only takes the store read lock
+    last_used: Arc<Mutex<Instant>>,
 }
 
 impl ModuleEntry {
-    fn new(name: String, module: Module, wasi_cap: bool) -> Self {
+    pub fn new(name: String, compiled: impl Into<Compiled>, wasi_cap: bool) -> Self {
+        let compiled = compiled.into();
+        let size = match &compiled {
+            Compiled::Module(module) => module.image_range().len(),
+            // components don't expose their image, see `with_size`
+            Compiled::Component(_) | Compiled::HttpProxy(_) => 0,
+        };
         Self {
             name,
-            module,
+            compiled,
             wasi_cap,
+            instance_pre: None,
+            size,
+            idle_timeout: None,
+            last_used: Arc::new(Mutex::new(Instant::now())),
         }
     }
 
-    pub fn module(&self) -> Module {
-        self.module.clone()
+    /// Account `size` bytes for the module, such as the artifact size
+    /// returned by `compile_sized`.
+    pub fn with_size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Evict the module after `idle_timeout` without use instead of the
+    /// store wide idle timeout.
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Keep the module with its imports resolved, for cheaper instantiation.
+    pub fn with_instance_pre(mut self, instance_pre: PreparedInstance) -> Self {
+        self.instance_pre = Some(instance_pre);
+        self
+    }
+
+    pub fn instance_pre(&self) -> Option<&PreparedInstance> {
+        self.instance_pre.as_ref()
+    }
+
+    pub fn compiled(&self) -> Compiled {
+        self.compiled.clone()
     }
 
     pub fn name(&self) -> &str {
@@ -102,4 +319,14 @@ impl ModuleEntry {
     pub fn capability(&self) -> bool {
         self.wasi_cap
     }
+
+    /// When the module was last returned by the store.
+    pub fn last_used(&self) -> Instant {
+        *self.last_used.lock().unwrap()
+    }
+
+    /// Size in bytes of the compiled module.
+    pub fn size(&self) -> usize {
+        self.size
+    }
 }
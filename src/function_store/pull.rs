@@ -1,6 +1,8 @@
 use anyhow::Result;
 use flate2::read;
+use oci_distribution::client::ImageData;
 use oci_distribution::{manifest, secrets::RegistryAuth, Client, Reference};
+use std::collections::HashMap;
 use tar::Archive;
 use tracing::info;
 
@@ -17,22 +19,51 @@ pub fn build_client_config(insecure: bool) -> oci_distribution::client::ClientCo
     }
 }
 
+/// Collect the image manifest annotations and the image config labels, labels
+/// take precedence over annotations with the same key.
+fn image_annotations(image: &ImageData) -> HashMap<String, String> {
+    let mut annotations = HashMap::new();
+
+    if let Some(manifest) = &image.manifest {
+        if let Some(manifest_annotations) = &manifest.annotations {
+            annotations.extend(manifest_annotations.clone());
+        }
+    }
+
+    if let Ok(config) = serde_json::from_slice::<serde_json::Value>(&image.config.data) {
+        if let Some(labels) = config["config"]["Labels"].as_object() {
+            for (key, value) in labels {
+                if let Some(value) = value.as_str() {
+                    annotations.insert(key.clone(), value.to_string());
+                }
+            }
+        }
+    }
+
+    annotations
+}
+
+/// Pull the wasm image and unpack it into `output`, returns the image
+/// annotations and labels.
 pub async fn pull_wasm(
     client: &mut Client,
     auth: &RegistryAuth,
     reference: &Reference,
     output: &str,
-) -> Result<()> {
+) -> Result<HashMap<String, String>> {
     info!(?reference, ?output, "pulling wasm module");
 
-    let image_content = client
+    let image = client
         .pull(
             reference,
             auth,
             vec![manifest::IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE],
         )
         .await
-        .map_err(|err| anyhow::format_err!("Cannot pull Wasm module {}", err))?
+        .map_err(|err| anyhow::format_err!("Cannot pull Wasm module {}", err))?;
+    let annotations = image_annotations(&image);
+
+    let image_content = image
         .layers
         .into_iter()
         .next()
@@ -49,5 +80,5 @@ pub async fn pull_wasm(
         .map_err(|err| anyhow::format_err!("Cannot write to file: {}", err))?;
 
     info!("Wasm module successfully written to {}", output);
-    Ok(())
+    Ok(annotations)
 }
@@ -0,0 +1,101 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// A single label requirement of a `LabelSelector`.
+#[derive(Clone, Debug, PartialEq)]
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+    NotExists(String),
+}
+
+impl Requirement {
+    fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        match self {
+            Requirement::Equals(key, value) => labels.get(key) == Some(value),
+            Requirement::NotEquals(key, value) => labels.get(key) != Some(value),
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::NotExists(key) => !labels.contains_key(key),
+        }
+    }
+}
+
+impl Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Requirement::Equals(key, value) => write!(f, "{}={}", key, value),
+            Requirement::NotEquals(key, value) => write!(f, "{}!={}", key, value),
+            Requirement::Exists(key) => write!(f, "{}", key),
+            Requirement::NotExists(key) => write!(f, "!{}", key),
+        }
+    }
+}
+
+/// Label selector in the `team=payments,env!=dev` form.
+///
+/// Requirements are separated by commas and all of them must match:
+/// * `key=value` or `key==value`: the label is set to value
+/// * `key!=value`: the label is not set to value, or not set at all
+/// * `key`: the label is set
+/// * `!key`: the label is not set
+///
+/// The empty selector matches everything.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LabelSelector {
+    requirements: Vec<Requirement>,
+}
+
+impl LabelSelector {
+    /// Returns true if `labels` satisfy every requirement of the selector.
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.requirements.iter().all(|r| r.matches(labels))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requirements.is_empty()
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut requirements = Vec::new();
+
+        for term in s.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            let requirement = if let Some((key, value)) = term.split_once("!=") {
+                Requirement::NotEquals(parse_key(key, term)?, value.trim().to_string())
+            } else if let Some((key, value)) = term.split_once("==") {
+                Requirement::Equals(parse_key(key, term)?, value.trim().to_string())
+            } else if let Some((key, value)) = term.split_once('=') {
+                Requirement::Equals(parse_key(key, term)?, value.trim().to_string())
+            } else if let Some(key) = term.strip_prefix('!') {
+                Requirement::NotExists(parse_key(key, term)?)
+            } else {
+                Requirement::Exists(parse_key(term, term)?)
+            };
+            requirements.push(requirement);
+        }
+
+        Ok(LabelSelector { requirements })
+    }
+}
+
+impl Display for LabelSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let terms: Vec<String> = self.requirements.iter().map(|r| r.to_string()).collect();
+        write!(f, "{}", terms.join(","))
+    }
+}
+
+fn parse_key(key: &str, term: &str) -> Result<String> {
+    let key = key.trim();
+    if key.is_empty() || key.contains(|c: char| c.is_whitespace() || c == '!' || c == '=') {
+        return Err(anyhow!("invalid label selector requirement: {}", term));
+    }
+
+    Ok(key.to_string())
+}
@@ -1,18 +1,38 @@
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+use clap::Parser;
 use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
 use std::{collections::HashMap, error::Error};
-use tracing::{info, instrument, Level};
+use tracing::{info, instrument, warn, Level};
 use tracing_subscriber::{self, EnvFilter};
-use wasm_engine::wrapper::{config::EnvConfig, environment::Environment};
-use wasmtime::Module;
-mod function_store;
-use function_store::local_store::FunctionStore;
-use function_store::module_store::ModuleStore;
+use warp::Filter;
+use wasm_engine::function_store::{
+    events::EventLog,
+    kv_store::{KvStore, DEFAULT_KV_QUOTA_BYTES},
+    local_store::{FunctionEntry, FunctionStatus, FunctionStore},
+    module_store::{ModuleEntry, ModuleLimits, ModuleStore},
+};
+use wasm_engine::wrapper::{
+    config::{
+        EnvConfig, PoolingConfig, ResourceLimits, DEFAULT_MAX_OUTPUT_BYTES,
+        DEFAULT_MAX_STDERR_BYTES,
+    },
+    environment::Environment,
+    error::InvokeError,
+    input::WasiInput,
+    instance::{Compiled, ReusePolicy},
+    kind::FunctionKind,
+};
 
 lazy_static::lazy_static! {
-    pub static ref WASMTIME_RUNTIME :Environment = Environment::new(EnvConfig::default()).unwrap();
-    pub static ref MODULE_STORE :ModuleStore = ModuleStore::new();
-    pub static ref FUNCTION_STORE: FunctionStore = FunctionStore::new("/var/lib/wasmengine/functions/");
+    static ref ARGS :Args = Args::parse();
+    pub static ref WASMTIME_RUNTIME :Environment = Environment::new(ARGS.env_config()).unwrap();
+    pub static ref EVENT_LOG :EventLog = EventLog::new();
+    pub static ref MODULE_STORE :ModuleStore = ModuleStore::new().with_events(EVENT_LOG.clone());
+    pub static ref FUNCTION_STORE: FunctionStore = FunctionStore::new("/var/lib/wasmengine/functions/").with_events(EVENT_LOG.clone());
     pub static ref LOG_LEVEL:HashMap<u8,Level> = HashMap::from([
         (0, tracing::Level::TRACE),
         (1, tracing::Level::DEBUG),
@@ -22,17 +42,179 @@ lazy_static::lazy_static! {
     ]);
 }
 
+// Key-value buckets of the functions, opened in `main`
+static KV_STORE: OnceLock<KvStore> = OnceLock::new();
+
+// Set once the restored functions are warmed up, or right away without warm-up
+static READY: AtomicBool = AtomicBool::new(false);
+
+// Fuel consumed by all invocations, and the invocations that ran out of it
+static FUEL_CONSUMED: AtomicU64 = AtomicU64::new(0);
+static FUEL_EXHAUSTED: AtomicU64 = AtomicU64::new(0);
+
+// Invocations denied a resource by their limits
+static LIMIT_EXCEEDED: AtomicU64 = AtomicU64::new(0);
+
+// Invocations of WASI functions exiting with a non-zero code
+static EXIT_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+// Invocations served so far, numbering the lines guests log
+static INVOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Parser, Debug)]
+#[clap(version, about = "WasmEngine function runtime")]
+struct Args {
+    /// Validate and compile every restored function before reporting ready
+    #[clap(long)]
+    warm_up: bool,
+    /// Maximum number of modules compiled in parallel during warm-up
+    #[clap(long, default_value_t = 4)]
+    warm_up_concurrency: usize,
+    /// Maximum number of compiled modules kept in memory
+    #[clap(long)]
+    module_cache_entries: Option<usize>,
+    /// Maximum size in bytes of the compiled modules kept in memory
+    #[clap(long)]
+    module_cache_memory: Option<usize>,
+    /// Evict compiled modules unused for this many seconds, unless set per function
+    #[clap(long)]
+    module_idle_timeout: Option<u64>,
+    /// Compute budget of an invocation in units of 100k instructions, unless
+    /// set per function
+    #[clap(long)]
+    max_fuel: Option<u64>,
+    /// Wall-clock deadline of an invocation in milliseconds, unless set per function
+    #[clap(long)]
+    timeout_ms: Option<u64>,
+    /// Maximum size in bytes of a linear memory, unless set per function
+    #[clap(long)]
+    limit_memory_bytes: Option<usize>,
+    /// Maximum number of elements of a table, unless set per function
+    #[clap(long)]
+    limit_table_elements: Option<u32>,
+    /// Maximum number of instances of an invocation, unless set per function
+    #[clap(long)]
+    limit_instances: Option<usize>,
+    /// Maximum number of linear memories of an invocation, unless set per function
+    #[clap(long)]
+    limit_memories: Option<usize>,
+    /// Bytes of the stderr of WASI functions kept per invocation
+    #[clap(long, default_value_t = DEFAULT_MAX_STDERR_BYTES)]
+    max_stderr_bytes: usize,
+    /// Bytes of stdout a WASI function may write per invocation
+    #[clap(long, default_value_t = DEFAULT_MAX_OUTPUT_BYTES)]
+    max_output_bytes: usize,
+    /// Bytes of keys and values a key-value bucket may hold
+    #[clap(long, default_value_t = DEFAULT_KV_QUOTA_BYTES)]
+    kv_quota_bytes: u64,
+    /// Directory of the precompiled modules, apart from the function images
+    #[clap(long, default_value = "/var/lib/wasmengine/cache")]
+    cache_dir: PathBuf,
+    /// Preallocate instances in pools instead of allocating them on demand
+    #[clap(long)]
+    pooling: bool,
+    /// Maximum number of concurrent component instances in pooling mode
+    #[clap(long, default_value_t = PoolingConfig::default().total_instances)]
+    pool_total_instances: u32,
+    /// Maximum number of concurrent core instances in pooling mode
+    #[clap(long, default_value_t = PoolingConfig::default().total_core_instances)]
+    pool_total_core_instances: u32,
+    /// Maximum number of concurrent linear memories in pooling mode
+    #[clap(long, default_value_t = PoolingConfig::default().total_memories)]
+    pool_total_memories: u32,
+    /// Maximum number of concurrent tables in pooling mode
+    #[clap(long, default_value_t = PoolingConfig::default().total_tables)]
+    pool_total_tables: u32,
+    /// Maximum linear memory size in 64 KiB wasm pages in pooling mode
+    #[clap(long, default_value_t = PoolingConfig::default().memory_pages)]
+    pool_memory_pages: u64,
+    /// Maximum number of table elements in pooling mode
+    #[clap(long, default_value_t = PoolingConfig::default().table_elements)]
+    pool_table_elements: u32,
+}
+
+impl Args {
+    fn env_config(&self) -> EnvConfig {
+        let mut config = EnvConfig::default();
+        config.set_max_fuel(self.max_fuel);
+        config.set_timeout(self.timeout_ms.map(Duration::from_millis));
+        config.set_limits(ResourceLimits {
+            max_memory_bytes: self.limit_memory_bytes,
+            max_table_elements: self.limit_table_elements,
+            max_instances: self.limit_instances,
+            max_memories: self.limit_memories,
+        });
+        config.set_max_stderr_bytes(self.max_stderr_bytes);
+        config.set_max_output_bytes(self.max_output_bytes);
+        config.set_cache_dir(Some(self.cache_dir.clone()));
+        if self.pooling {
+            config.set_pooling(PoolingConfig {
+                total_instances: self.pool_total_instances,
+                total_core_instances: self.pool_total_core_instances,
+                total_memories: self.pool_total_memories,
+                total_tables: self.pool_total_tables,
+                memory_pages: self.pool_memory_pages,
+                table_elements: self.pool_table_elements,
+            });
+        }
+        config
+    }
+}
+
+// How often idle modules are looked for
+const IDLE_EVICTION_INTERVAL: Duration = Duration::from_secs(10);
+
 #[instrument]
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    let args = &*ARGS;
+
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .try_init()?;
 
+    // Create the engine up front, so a bad allocator configuration fails at start
+    lazy_static::initialize(&WASMTIME_RUNTIME);
+    // Open the key-value store up front, it can't be shared with another engine
+    let kv_path = FUNCTION_STORE.kv_path();
+    let kv_store = KvStore::open(&kv_path, args.kv_quota_bytes).with_context(|| {
+        format!(
+            "failed to open the key-value store at {}",
+            kv_path.display()
+        )
+    })?;
+    let _ = KV_STORE.set(kv_store);
+
+    MODULE_STORE.set_limits(ModuleLimits {
+        max_entries: args.module_cache_entries,
+        max_memory: args.module_cache_memory,
+        idle_timeout: args.module_idle_timeout.map(Duration::from_secs),
+    });
+
     // try restore the function from local fucntion store
     FUNCTION_STORE.restore().await?;
 
-    let routes = filters::function_management();
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(IDLE_EVICTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            MODULE_STORE.evict_idle();
+        }
+    });
+
+    if args.warm_up {
+        tokio::spawn(async move {
+            warm_up(args.warm_up_concurrency).await;
+            READY.store(true, Ordering::SeqCst);
+        });
+    } else {
+        READY.store(true, Ordering::SeqCst);
+    }
+
+    let routes = filters::function_management()
+        .or(filters::kv_management())
+        .or(filters::readiness())
+        .or(filters::metrics());
 
     info!("WasmEngine listening on http://0.0.0.0:10000, waiting for request...");
     warp::serve(routes).run(([0, 0, 0, 0], 10000)).await;
@@ -40,27 +222,187 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
     Ok(())
 }
 
-/// Load the wasm module file from local file system into ModuleStore
-pub async fn load(name: &str) -> anyhow::Result<()> {
+// The key-value store, once `main` opened it
+fn kv_store() -> &'static KvStore {
+    KV_STORE
+        .get()
+        .expect("the key-value store is opened at start")
+}
+
+/// Compile the wasm module or component file at `path`, or load its
+/// precompiled artifact.
+fn compile_file(path: &str) -> anyhow::Result<Compiled> {
+    WASMTIME_RUNTIME
+        .runtime()
+        .compile(path)
+        .with_context(|| format!("failed to open module file {}", path))
+}
+
+/// Compile the module a function is served from, along with its artifact size.
+fn compile(func: &FunctionEntry) -> anyhow::Result<(Compiled, usize)> {
+    let path = func.module_path();
+    WASMTIME_RUNTIME
+        .runtime()
+        .compile_sized(&path)
+        .with_context(|| format!("failed to open module file {}", path))
+}
+
+/// Compile a pulled function and detect its kind, which must agree with the
+/// requested `wasi_cap` if any. Records the detected kind in the entry.
+fn validate(func: &mut FunctionEntry, wasi_cap: Option<bool>) -> anyhow::Result<()> {
+    let module = compile_file(&func.func_local_path)?;
+    let runtime = WASMTIME_RUNTIME.runtime();
+    let kind = runtime
+        .inspect_compiled(&module, func.entrypoint())
+        .with_context(|| format!("invalid function module {}", func.func_local_path))?;
+
+    // handlers are exports called like the entrypoint
+    if (func.entrypoint.is_some() || !func.handlers.is_empty())
+        && !matches!(
+            kind,
+            FunctionKind::Export | FunctionKind::Typed | FunctionKind::WasiReactor
+        )
+    {
+        return Err(anyhow!(
+            "entrypoint and handlers are only supported by plain and reactor modules"
+        ));
+    }
+    for (handler, export) in &func.handlers {
+        if handler.is_empty() || handler.contains('/') {
+            return Err(anyhow!("invalid handler name `{}`", handler));
+        }
+        let handler_kind = runtime
+            .inspect_compiled(&module, export)
+            .with_context(|| format!("invalid handler `{}`", handler))?;
+        if handler_kind != kind {
+            return Err(anyhow!(
+                "handler `{}` is detected as {:?} but the entrypoint as {:?}",
+                handler,
+                handler_kind,
+                kind
+            ));
+        }
+    }
+
+    if let Some(wasi_cap) = wasi_cap {
+        if wasi_cap != kind.wasi() {
+            return Err(anyhow!(
+                "wasi_cap is {} but the module is detected as {:?}",
+                wasi_cap,
+                kind
+            ));
+        }
+    }
+
+    if let Some(wasi_input) = &func.wasi_input {
+        if !kind.wasi() {
+            return Err(anyhow!("wasi_input is only supported by WASI functions"));
+        }
+        wasi_input.validate()?;
+    }
+
+    if let Some(namespace) = &func.kv_namespace {
+        if namespace.is_empty() || namespace.contains('/') || namespace.starts_with('.') {
+            return Err(anyhow!(
+                "kv_namespace {:?} can't be empty, contain `/` or start with `.`",
+                namespace
+            ));
+        }
+    }
+
+    if kind.wasi() && func.instance_reuse.is_some() {
+        return Err(anyhow!("instance_reuse is only supported by plain modules"));
+    }
+    if let Some(policy) = &func.instance_reuse {
+        runtime
+            .prepare_compiled(&module, false, Some(policy))
+            .context("invalid instance_reuse")?;
+    }
+
+    func.wasi_cap = kind.wasi();
+    func.kind = Some(kind);
+
+    Ok(())
+}
+
+/// Build the pre-initialized snapshot of a function from its original module.
+async fn pre_initialize(func: &FunctionEntry) -> anyhow::Result<()> {
+    let path = match func.initialized_path() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let wasm = tokio::fs::read(&func.func_local_path)
+        .await
+        .with_context(|| format!("failed to read module file {}", func.func_local_path))?;
+    let initialized = WASMTIME_RUNTIME
+        .runtime()
+        .pre_initialize(&wasm, func.wasi_cap, &func.function_config())
+        .await
+        .with_context(|| format!("failed to pre-initialize function {}", func.func_name))?;
+    tokio::fs::write(&path, initialized)
+        .await
+        .with_context(|| format!("failed to write module file {}", path))?;
+
+    info!("function {} pre-initialized into {}", func.func_name, path);
+    Ok(())
+}
+
+/// Load the wasm module file from local file system into ModuleStore, returns
+/// the loaded module entry
+pub async fn load(name: &str) -> anyhow::Result<ModuleEntry> {
     let func = FUNCTION_STORE
         .query(name)
         .await
         .with_context(|| format!("failed to find the function"))?;
 
-    if MODULE_STORE.exist(name) {
-        info!("function module {} alread loaded in the module store", name);
-        return Ok(());
+    if let FunctionStatus::Unavailable(reason) = &func.status {
+        return Err(InvokeError::Unavailable(reason.clone()).into());
     }
 
-    let module = Module::from_file(
-        WASMTIME_RUNTIME.runtime().get_engine(),
-        func.func_local_path,
-    )
-    .with_context(|| format!("failed to open module file"))?;
+    // concurrent first invocations share a single compile
+    MODULE_STORE
+        .get_or_load(name, move || async move {
+            let name = func.func_name.clone();
+            let wasi_cap = func.wasi_cap;
+            let idle_timeout = func.idle_timeout();
+            let instance_reuse = func.instance_reuse.clone();
+            // rebuild a missing snapshot from the original module
+            if let Some(path) = func.initialized_path() {
+                if !std::path::Path::new(&path).exists() {
+                    pre_initialize(&func).await?;
+                }
+            }
+            let (module, size) = tokio::task::spawn_blocking(move || compile(&func)).await??;
+            let instance_pre = WASMTIME_RUNTIME.runtime().prepare_compiled(
+                &module,
+                wasi_cap,
+                instance_reuse.as_ref(),
+            )?;
 
-    MODULE_STORE.insert(&func.func_name, module, func.wasi_cap)?;
+            info!("function module {} loaded into the module store", name);
+            MODULE_STORE.insert_entry(
+                ModuleEntry::new(name, module, wasi_cap)
+                    .with_size(size)
+                    .with_idle_timeout(idle_timeout)
+                    .with_instance_pre(instance_pre),
+            )
+        })
+        .await
+}
 
-    Ok(())
+/// Validate every restored function by compiling it into the ModuleStore, at
+/// most `concurrency` modules at a time. Functions that fail are marked
+/// unavailable with the failure reason.
+async fn warm_up(concurrency: usize) {
+    let warmed = FUNCTION_STORE
+        .warm_up(concurrency, |func| async move {
+            load(&func.func_name).await.map(|_| ())
+        })
+        .await;
+    if let Err(err) = warmed {
+        warn!("failed to warm up functions: {:?}", err);
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -68,16 +410,79 @@ pub struct FunctionInfo {
     function_name: String,
     function_image: Option<String>,
     wasi_cap: Option<bool>,
+    module_path: Option<String>,
+    data_mount: Option<String>,
+    labels: Option<HashMap<String, String>>,
+    annotations: Option<HashMap<String, String>>,
+    description: Option<String>,
+    // seconds without invocation before the compiled module is evicted
+    idle_timeout: Option<u64>,
+    // snapshot the module after running its `wizer.initialize` export
+    pre_initialize: Option<bool>,
+    // keep instances warm between invocations, plain modules only
+    instance_reuse: Option<ReusePolicy>,
+    // compute budget of an invocation in units of 100k instructions
+    max_fuel: Option<u64>,
+    // wall-clock deadline of an invocation in milliseconds
+    timeout_ms: Option<u64>,
+    // resources an instance may hold
+    limits: Option<ResourceLimits>,
+    // how the arguments are handed to a WASI function, legacy by default
+    wasi_input: Option<WasiInput>,
+    // export called by default, the function name if unset
+    entrypoint: Option<String>,
+    // exports invoked as `<function>/<handler>`, by handler name
+    handlers: Option<HashMap<String, String>>,
+    // key-value bucket shared by the functions of a namespace, the function
+    // name by default
+    kv_namespace: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct FuncListReq {
+    // label selector, e.g. `team=payments,env!=dev`
+    selector: Option<String>,
+    // sort key, `name` (default) or `image`
+    sort_by: Option<String>,
+    desc: Option<bool>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct FuncEventsReq {
+    // resume after this resource version, the `Last-Event-ID` header wins
+    since: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KvBucketReq {
+    bucket: String,
+    // only the keys starting with it, when inspecting
+    prefix: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct FuncInvokeReq {
+    // `<function>` or `<function>/<handler>`
     function_name: String,
     args: Option<HashMap<String, String>>,
+    // caps the compute budget of this invocation, in units of 100k instructions
+    max_fuel: Option<u64>,
+    // export called with typed `params`, the function name by default
+    export: Option<String>,
+    // parameters of a typed call, checked against the export type
+    params: Option<Vec<serde_json::Value>>,
+    // raw input of a WASI function, given on stdin
+    input: Option<String>,
+    // binary input of a WASI function in base64, in place of `input`
+    input_base64: Option<String>,
+    // return the lines the guest logged along with the output
+    debug: Option<bool>,
 }
 
 mod filters {
-    use crate::handlers;
+    use crate::{handlers, FuncEventsReq, FuncListReq, KvBucketReq};
     use warp::Filter;
 
     pub fn function_management(
@@ -88,7 +493,10 @@ mod filters {
                 .or(function_list())
                 .or(function_query())
                 .or(function_invoke())
-                .recover(handle_not_found),
+                .or(function_http())
+                .or(function_events())
+                .recover(handle_not_found)
+                .recover(handlers::handle_invoke_error),
         )
     }
 
@@ -114,6 +522,7 @@ mod filters {
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path("list")
             .and(warp::get())
+            .and(warp::query::<FuncListReq>())
             .and_then(handlers::list_function)
     }
 
@@ -132,9 +541,78 @@ mod filters {
             .and(warp::post())
             .and(warp::body::content_length_limit(1024 * 16))
             .and(warp::body::json())
+            .and(warp::header::optional::<u64>(handlers::TIMEOUT_HEADER))
             .and_then(handlers::invoke_function)
     }
 
+    pub fn function_http(
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("http")
+            .and(warp::path::param::<String>())
+            .and(warp::path::tail())
+            .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+            .and(warp::method())
+            .and(warp::header::headers_cloned())
+            .and(warp::body::stream())
+            .and(warp::header::optional::<u64>(handlers::TIMEOUT_HEADER))
+            .and_then(handlers::serve_http)
+    }
+
+    pub fn function_events(
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("events")
+            .and(warp::get())
+            .and(warp::sse::last_event_id::<u64>())
+            .and(warp::query::<FuncEventsReq>())
+            .and_then(handlers::function_events)
+    }
+
+    pub fn kv_management(
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("kv").and(
+            kv_buckets()
+                .or(kv_inspect())
+                .or(kv_clear())
+                .recover(handle_not_found),
+        )
+    }
+
+    pub fn kv_buckets() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        warp::path("buckets")
+            .and(warp::get())
+            .and_then(handlers::list_buckets)
+    }
+
+    pub fn kv_inspect() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        warp::path("inspect")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(1024 * 16))
+            .and(warp::body::json::<KvBucketReq>())
+            .and_then(handlers::inspect_bucket)
+    }
+
+    pub fn kv_clear() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("clear")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(1024 * 16))
+            .and(warp::body::json::<KvBucketReq>())
+            .and_then(handlers::clear_bucket)
+    }
+
+    pub fn metrics() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("metrics")
+            .and(warp::get())
+            .and_then(handlers::metrics)
+    }
+
+    pub fn readiness() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("ready")
+            .and(warp::get())
+            .and_then(handlers::readiness)
+    }
+
     pub async fn handle_not_found(
         reject: warp::Rejection,
     ) -> Result<impl warp::Reply, warp::Rejection> {
@@ -147,12 +625,47 @@ mod filters {
 }
 
 mod handlers {
-    use super::{FuncInvokeReq, FunctionInfo, FUNCTION_STORE, MODULE_STORE, WASMTIME_RUNTIME};
-    use crate::{function_store::local_store::FunctionEntries, load};
+    use super::{
+        FuncEventsReq, FuncInvokeReq, FuncListReq, FunctionInfo, KvBucketReq, EVENT_LOG,
+        EXIT_FAILURES, FUEL_CONSUMED, FUEL_EXHAUSTED, FUNCTION_STORE, INVOCATIONS, LIMIT_EXCEEDED,
+        MODULE_STORE, READY, WASMTIME_RUNTIME,
+    };
+    use crate::{compile, kv_store, load, pre_initialize, validate};
     use anyhow::{anyhow, Context};
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use bytes::Buf;
+    use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+    use http_body_util::{BodyExt, StreamBody};
+    use hyper::body::Frame;
     use serde::{Deserialize, Serialize};
-    use std::{collections::HashMap, fmt::Debug};
-    use tracing::{debug, instrument};
+    use std::{
+        collections::HashMap, fmt::Debug, sync::atomic::Ordering, sync::Arc, time::Duration,
+    };
+    use tracing::{debug, instrument, warn};
+    use wasm_engine::function_store::{
+        kv_store::{BucketInfo, KvBucket, KvStore},
+        local_store::{DeployOptions, FunctionEntry},
+        selector::LabelSelector,
+    };
+    use wasm_engine::wrapper::{
+        environment::UNIT_OF_COMPUTE_IN_INSTRUCTIONS, error::InvokeError, guest_log::LogLine,
+        instance::PreparedInstance, kind::FunctionKind,
+    };
+    use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+    use wasmtime_wasi_http::body::{HyperIncomingBody, HyperOutgoingBody};
+
+    // Body of an invocation of a WASI function or with debug, the output of
+    // other invocations is the body as is
+    #[derive(Serialize)]
+    struct InvocationBody<'a> {
+        output: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        exit_code: Option<i32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stderr: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        logs: Option<&'a [LogLine]>,
+    }
 
     #[derive(Serialize, Deserialize, Debug, Default)]
     pub struct Response {
@@ -160,6 +673,19 @@ mod handlers {
         pub body: String,
     }
 
+    impl Response {
+        /// Reply like `into_response` with `status` as the HTTP status too,
+        /// other replies are sent with 200 and carry the status in the body.
+        pub fn with_http_status(self) -> warp::reply::Response {
+            let status = http::StatusCode::from_u16(self.status);
+            let mut response = warp::Reply::into_response(self);
+            if let Ok(status) = status {
+                *response.status_mut() = status;
+            }
+            response
+        }
+    }
+
     impl warp::Reply for Response {
         fn into_response(self) -> warp::reply::Response {
             http::Response::new(
@@ -176,28 +702,126 @@ mod handlers {
         warp::reject::custom(CustomReject(error))
     }
 
+    // Response header carrying the fuel an invocation consumed
+    const FUEL_CONSUMED_HEADER: &str = "x-fuel-consumed";
+
+    // Response header carrying the most bytes of linear memory an invocation held
+    const PEAK_MEMORY_HEADER: &str = "x-peak-memory";
+
+    // Response header carrying the exit code of a WASI function
+    const EXIT_CODE_HEADER: &str = "x-exit-code";
+
+    // Response header carrying the identifier of an invocation, recorded with
+    // the lines the guest logged
+    const INVOCATION_ID_HEADER: &str = "x-invocation-id";
+
+    // Request header shortening the deadline of an invocation, in milliseconds
+    pub const TIMEOUT_HEADER: &str = "x-timeout-ms";
+
+    // Seconds a client should wait before retrying a retryable invocation error
+    const RETRY_AFTER_SECS: u64 = 1;
+
+    /// Answer invocation errors with a status telling them apart, retryable
+    /// ones with `Retry-After`. Other rejections are left to warp.
+    pub async fn handle_invoke_error(
+        reject: warp::Rejection,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let err = match reject
+            .find::<CustomReject>()
+            .and_then(|CustomReject(err)| err.downcast_ref::<InvokeError>())
+        {
+            Some(err) => err,
+            None => return Err(reject),
+        };
+
+        let status = match err {
+            InvokeError::PoolExhausted(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+            InvokeError::FuelExhausted(_) => http::StatusCode::UNPROCESSABLE_ENTITY,
+            InvokeError::Timeout(_) => http::StatusCode::GATEWAY_TIMEOUT,
+            InvokeError::LimitExceeded(_) => http::StatusCode::UNPROCESSABLE_ENTITY,
+            InvokeError::Unavailable(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+        };
+        let mut response = Response {
+            status: status.as_u16(),
+            body: err.to_string(),
+        }
+        .with_http_status();
+        if err.is_retryable() {
+            response
+                .headers_mut()
+                .insert("retry-after", http::HeaderValue::from(RETRY_AFTER_SECS));
+        }
+
+        Ok(response)
+    }
+
     #[instrument]
     pub async fn deploy_function(func: FunctionInfo) -> Result<impl warp::Reply, warp::Rejection> {
         debug!("deploy function info: {:?}", func);
 
-        let func_exist = FUNCTION_STORE.exist(func.function_name.as_str()).await;
+        check_function_name(&func.function_name).map_err(custom_reject)?;
+        // the name stays reserved until the function is registered
+        let reservation = FUNCTION_STORE
+            .reserve(func.function_name.as_str())
+            .await
+            .map_err(custom_reject)?;
 
-        if func_exist {
-            return Err(custom_reject(anyhow!(
-                "function already exist in the local function store"
-            )));
+        let options = DeployOptions {
+            module_path: func.module_path.clone(),
+            data_mount: func.data_mount.clone(),
+            labels: func.labels.clone().unwrap_or_default(),
+            annotations: func.annotations.clone().unwrap_or_default(),
+            description: func.description.clone(),
+            idle_timeout: func.idle_timeout,
+            pre_initialize: func.pre_initialize.unwrap_or_default(),
+            instance_reuse: func.instance_reuse.clone(),
+            max_fuel: func.max_fuel,
+            timeout_ms: func.timeout_ms,
+            limits: func.limits,
+            wasi_input: func.wasi_input,
+            entrypoint: func.entrypoint,
+            handlers: func.handlers.unwrap_or_default(),
+            kv_namespace: func.kv_namespace,
+        };
+
+        let image = func
+            .function_image
+            .as_deref()
+            .ok_or_else(|| custom_reject(anyhow!("function_image is required")))?;
+
+        // compiling takes a while, keep it off the async workers
+        let wasi_cap = func.wasi_cap;
+        let prepared = async {
+            // pull the function image into local function store
+            let mut entry = FUNCTION_STORE
+                .pull(&reservation, image, &options)
+                .await
+                .context("failed to pull function into local store")?;
+            let entry =
+                tokio::task::spawn_blocking(move || validate(&mut entry, wasi_cap).map(|_| entry))
+                    .await??;
+            pre_initialize(&entry).await?;
+            let compiled = entry.clone();
+            tokio::task::spawn_blocking(move || compile(&compiled)).await??;
+            anyhow::Ok(entry)
         }
+        .await;
+        let entry = match prepared {
+            Ok(entry) => entry,
+            Err(err) => {
+                if let Err(clean_err) = FUNCTION_STORE.remove_files(&reservation) {
+                    warn!("failed to remove pulled function files: {:?}", clean_err);
+                }
+                return Err(custom_reject(err));
+            }
+        };
 
         // add the function into local function store
         FUNCTION_STORE
-            .add(
-                func.function_name.as_str(),
-                func.function_image.unwrap().as_str(),
-                func.wasi_cap.unwrap(),
-            )
+            .insert(entry)
             .await
-            .with_context(|| format!("failed to add function into local store"))
-            .map_err(|r| custom_reject(r))?;
+            .context("failed to add function into local store")
+            .map_err(custom_reject)?;
 
         // save function store into persist.json file
         FUNCTION_STORE
@@ -244,18 +868,59 @@ mod handlers {
         })
     }
 
+    #[derive(Serialize, Debug)]
+    pub struct FunctionList {
+        pub resource_version: u64,
+        pub total: usize,
+        pub offset: usize,
+        pub items: Vec<FunctionEntry>,
+    }
+
     #[instrument]
-    pub async fn list_function() -> Result<impl warp::Reply, warp::Rejection> {
-        debug!("list all functions info in the local store");
+    pub async fn list_function(req: FuncListReq) -> Result<impl warp::Reply, warp::Rejection> {
+        debug!("list functions info in the local store: {:?}", req);
 
-        let funcs: FunctionEntries = FunctionEntries(FUNCTION_STORE.list().await.unwrap());
+        let selector: LabelSelector = req
+            .selector
+            .as_deref()
+            .unwrap_or_default()
+            .parse()
+            .map_err(custom_reject)?;
 
-        debug!("list all function successfully!");
+        // taken before listing, so watching from it can't miss a change
+        let resource_version = EVENT_LOG.version();
+        let mut funcs = FUNCTION_STORE
+            .select(&selector)
+            .await
+            .map_err(custom_reject)?;
 
-        Ok(Response {
-            status: http::StatusCode::OK.as_u16(),
-            body: format!("all deployed function info: {}\n", funcs),
-        })
+        match req.sort_by.as_deref().unwrap_or("name") {
+            "name" => funcs.sort_by(|a, b| a.func_name.cmp(&b.func_name)),
+            "image" => funcs.sort_by(|a, b| {
+                (&a.func_image_name, &a.func_name).cmp(&(&b.func_image_name, &b.func_name))
+            }),
+            other => return Err(custom_reject(anyhow!("unknown sort key {}", other))),
+        }
+        if req.desc.unwrap_or(false) {
+            funcs.reverse();
+        }
+
+        let total = funcs.len();
+        let offset = req.offset.unwrap_or(0);
+        let items: Vec<FunctionEntry> = funcs
+            .into_iter()
+            .skip(offset)
+            .take(req.limit.unwrap_or(usize::MAX))
+            .collect();
+
+        debug!("list {} of {} functions successfully!", items.len(), total);
+
+        Ok(warp::reply::json(&FunctionList {
+            resource_version,
+            total,
+            offset,
+            items,
+        }))
     }
 
     #[instrument]
@@ -279,17 +944,67 @@ mod handlers {
     #[instrument]
     pub async fn invoke_function(
         invoke_req: FuncInvokeReq,
+        timeout_ms: Option<u64>,
     ) -> Result<impl warp::Reply, warp::Rejection> {
         debug!("invoke function info: {:?}", invoke_req.function_name);
 
-        load(&invoke_req.function_name.as_str())
+        let (function_name, handler) = match invoke_req.function_name.split_once('/') {
+            Some((function_name, handler)) => (function_name, Some(handler)),
+            None => (invoke_req.function_name.as_str(), None),
+        };
+
+        // a function exists before its module is loaded, check the call first
+        let func = FUNCTION_STORE
+            .query(function_name)
             .await
-            .with_context(|| format!("failed to load the function from local store"))
-            .map_err(|r| custom_reject(r))?;
+            .context("failed to load the function from local store")
+            .map_err(custom_reject)?;
+        let entry = func.handler_export(handler).map_err(custom_reject)?;
+        if let Some(export) = invoke_req.export.as_deref() {
+            if !func.exposes(export) {
+                return Err(custom_reject(anyhow!(
+                    "export `{}` is not the entrypoint or a handler of function {}",
+                    export,
+                    function_name
+                )));
+            }
+        }
 
-        let module = MODULE_STORE
-            .get(&invoke_req.function_name)
+        let module = load(function_name)
+            .await
+            .context("failed to load the function from local store")
             .map_err(|r| custom_reject(r))?;
+        let mut func_config = func.function_config();
+        if let Some(max_fuel) = invoke_req.max_fuel {
+            func_config.cap_fuel(max_fuel);
+        }
+        if let Some(timeout_ms) = timeout_ms {
+            func_config.cap_timeout(Duration::from_millis(timeout_ms));
+        }
+        let input = match (invoke_req.input, invoke_req.input_base64) {
+            (Some(_), Some(_)) => {
+                return Err(custom_reject(anyhow!(
+                    "input and input_base64 can't be both set"
+                )))
+            }
+            (Some(input), None) => Some(input.into_bytes()),
+            (None, Some(input)) => Some(
+                BASE64_STANDARD
+                    .decode(input)
+                    .context("invalid input_base64")
+                    .map_err(custom_reject)?,
+            ),
+            (None, None) => None,
+        };
+        if let Some(input) = input {
+            func_config.set_input(input);
+        }
+        // opened by the first key-value call, most functions never make one
+        func_config.set_kv_bucket(Arc::new(kv_store().lazy_bucket(&func.kv_bucket())));
+        let invocation_id = INVOCATIONS.fetch_add(1, Ordering::Relaxed) + 1;
+        func_config.set_invocation_id(invocation_id);
+        let debug = invoke_req.debug.unwrap_or_default();
+        func_config.set_capture_logs(debug);
 
         let args: HashMap<String, String> = match invoke_req.args {
             Some(v) => v,
@@ -297,24 +1012,625 @@ mod handlers {
         };
 
         let runtime = WASMTIME_RUNTIME.runtime();
-        let result;
-        if module.capability() {
-            result = runtime
-                .spawn_wasi(module.module(), args)
-                .await
-                .map_err(|r| custom_reject(r))?;
+        let prepared = match module.instance_pre() {
+            Some(prepared) => prepared.clone(),
+            None => runtime
+                .prepare_compiled(&module.compiled(), module.capability(), None)
+                .map_err(custom_reject)?,
+        };
+        // typed calls are asked for, or the only way to call the module
+        let typed = invoke_req.export.is_some()
+            || invoke_req.params.is_some()
+            || func.kind == Some(FunctionKind::Typed);
+        let export = invoke_req.export.as_deref().unwrap_or(entry);
+        let params = invoke_req.params.unwrap_or_default();
+        let reactor = func.kind == Some(FunctionKind::WasiReactor);
+        let call = async {
+            match (&prepared, typed) {
+                (PreparedInstance::Wasi(instance_pre), true) if reactor => {
+                    runtime
+                        .call_typed_reactor_pre(instance_pre, export, &params, &func_config)
+                        .await
+                }
+                (PreparedInstance::Wasi(instance_pre), false) if reactor => {
+                    runtime
+                        .spawn_reactor_pre(instance_pre, entry, args, &func_config)
+                        .await
+                }
+                (PreparedInstance::Wasi(_), true) => Err(anyhow!(
+                    "typed calls are not supported by WASI command modules"
+                )),
+                (PreparedInstance::Wasi(instance_pre), false) => {
+                    runtime
+                        .spawn_wasi_pre(instance_pre, args, &func_config)
+                        .await
+                }
+                (PreparedInstance::Pooled(pool), true) => {
+                    runtime
+                        .call_typed_pooled(pool, export, &params, &func_config)
+                        .await
+                }
+                (PreparedInstance::Pooled(pool), false) => {
+                    runtime.spawn_pooled(pool, entry, args, &func_config).await
+                }
+                (PreparedInstance::Plain(instance_pre), true) => {
+                    runtime
+                        .call_typed_pre(instance_pre, export, &params, &func_config)
+                        .await
+                }
+                (PreparedInstance::Plain(instance_pre), false) => {
+                    runtime
+                        .spawn_pre(instance_pre, entry, args, &func_config)
+                        .await
+                }
+                (PreparedInstance::Component(instance_pre), true) => {
+                    runtime
+                        .call_component(instance_pre, export, &params, &func_config)
+                        .await
+                }
+                (PreparedInstance::Component(instance_pre), false) => {
+                    runtime
+                        .run_component(instance_pre, args, &func_config)
+                        .await
+                }
+                (PreparedInstance::HttpProxy(_), _) => Err(anyhow!(
+                    "wasi:http proxy components are served under /function/http/{}",
+                    function_name
+                )),
+            }
+        };
+        let result = runtime.with_deadline(&func_config, call).await;
+
+        let invocation = match result {
+            Ok(invocation) => invocation,
+            Err(err) => {
+                count_failure(&err);
+                return Err(custom_reject(err));
+            }
+        };
+        FUEL_CONSUMED.fetch_add(invocation.fuel_consumed, Ordering::Relaxed);
+
+        debug!(
+            "run module {} successfully, {} fuel consumed, peak memory {} bytes",
+            invoke_req.function_name, invocation.fuel_consumed, invocation.peak_memory
+        );
+
+        // a non-zero exit is a failure of the function, not of the engine
+        let status = match invocation.exit_code {
+            Some(code) if code != 0 => {
+                EXIT_FAILURES.fetch_add(1, Ordering::Relaxed);
+                http::StatusCode::UNPROCESSABLE_ENTITY
+            }
+            _ => http::StatusCode::OK,
+        };
+        let body = if debug || invocation.stderr.is_some() {
+            serde_json::to_string(&InvocationBody {
+                output: &invocation.output,
+                exit_code: invocation.exit_code,
+                stderr: invocation.stderr.as_deref(),
+                logs: Some(invocation.logs.as_slice()).filter(|_| debug),
+            })
+            .map_err(|err| custom_reject(err.into()))?
         } else {
-            result = runtime
-                .spawn(module.module(), &invoke_req.function_name, args)
-                .await
-                .map_err(|r| custom_reject(r))?;
+            invocation.output
+        };
+        let mut response = Response {
+            status: status.as_u16(),
+            body,
+        }
+        .with_http_status();
+        let headers = response.headers_mut();
+        headers.insert(INVOCATION_ID_HEADER, http::HeaderValue::from(invocation_id));
+        headers.insert(
+            FUEL_CONSUMED_HEADER,
+            http::HeaderValue::from(invocation.fuel_consumed),
+        );
+        headers.insert(
+            PEAK_MEMORY_HEADER,
+            http::HeaderValue::from(invocation.peak_memory),
+        );
+        if let Some(code) = invocation.exit_code {
+            headers.insert(EXIT_CODE_HEADER, http::HeaderValue::from(code));
+        }
+
+        Ok(response)
+    }
+
+    // Function names end up in paths and URLs, `/` splits off a handler
+    fn check_function_name(name: &str) -> anyhow::Result<()> {
+        if name.is_empty() {
+            return Err(anyhow!("function_name can't be empty"));
+        }
+        if name.contains('/') {
+            return Err(anyhow!("function_name {} can't contain `/`", name));
+        }
+        if name.starts_with('.') {
+            return Err(anyhow!("function_name {} can't start with `.`", name));
+        }
+        Ok(())
+    }
+
+    // Count the invocations that failed on their limits
+    fn count_failure(err: &anyhow::Error) {
+        match err.downcast_ref() {
+            Some(InvokeError::FuelExhausted(budget)) => {
+                FUEL_EXHAUSTED.fetch_add(1, Ordering::Relaxed);
+                FUEL_CONSUMED.fetch_add(
+                    budget.saturating_mul(UNIT_OF_COMPUTE_IN_INSTRUCTIONS),
+                    Ordering::Relaxed,
+                );
+            }
+            Some(InvokeError::LimitExceeded(_)) => {
+                LIMIT_EXCEEDED.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    /// Serve an HTTP request with a `wasi:http/proxy` component. The request
+    /// is handed to the component with the path following the function name,
+    /// and its response is streamed back as is.
+    #[instrument(skip(headers, body))]
+    pub async fn serve_http(
+        function_name: String,
+        path: warp::path::Tail,
+        query: String,
+        method: http::Method,
+        headers: http::HeaderMap,
+        body: impl Stream<Item = Result<impl Buf, warp::Error>> + Send + Sync + 'static,
+        timeout_ms: Option<u64>,
+    ) -> Result<warp::reply::Response, warp::Rejection> {
+        debug!("serve http request of function: {:?}", function_name);
+
+        // only proxy components are worth loading
+        let func = FUNCTION_STORE
+            .query(&function_name)
+            .await
+            .context("failed to find the function")
+            .map_err(custom_reject)?;
+        if func.kind != Some(FunctionKind::HttpProxy) {
+            return Err(custom_reject(anyhow!(
+                "function {} is not a wasi:http proxy component",
+                function_name
+            )));
+        }
+        let module = load(&function_name)
+            .await
+            .context("failed to load the function from local store")
+            .map_err(custom_reject)?;
+        let mut func_config = func.function_config();
+        if let Some(timeout_ms) = timeout_ms {
+            func_config.cap_timeout(Duration::from_millis(timeout_ms));
+        }
+        // opened by the first key-value call, most functions never make one
+        func_config.set_kv_bucket(Arc::new(kv_store().lazy_bucket(&func.kv_bucket())));
+        let invocation_id = INVOCATIONS.fetch_add(1, Ordering::Relaxed) + 1;
+        func_config.set_invocation_id(invocation_id);
+
+        let request = incoming_request(path.as_str(), &query, method, headers, body)
+            .map_err(custom_reject)?;
+        let runtime = WASMTIME_RUNTIME.runtime();
+        let prepared = match module.instance_pre() {
+            Some(prepared) => prepared.clone(),
+            None => runtime
+                .prepare_compiled(&module.compiled(), module.capability(), None)
+                .map_err(custom_reject)?,
+        };
+        let instance_pre = match &prepared {
+            PreparedInstance::HttpProxy(instance_pre) => instance_pre,
+            _ => return Err(custom_reject(anyhow!("expect a wasi:http proxy instance"))),
+        };
+        let served = runtime
+            .with_deadline(
+                &func_config,
+                runtime.serve_http(instance_pre, request, &func_config),
+            )
+            .await;
+        let (response, handled) = match served {
+            Ok(served) => served,
+            Err(err) => {
+                count_failure(&err);
+                return Err(custom_reject(err));
+            }
+        };
+
+        // the invocation ends once the body is written
+        tokio::spawn(async move {
+            match handled.await.map_err(anyhow::Error::from).and_then(|r| r) {
+                Ok(invocation) => {
+                    FUEL_CONSUMED.fetch_add(invocation.fuel_consumed, Ordering::Relaxed);
+                    debug!(
+                        "served http request of {} successfully, {} fuel consumed",
+                        function_name, invocation.fuel_consumed
+                    );
+                }
+                Err(err) => {
+                    count_failure(&err);
+                    warn!("http handler of {} failed: {:?}", function_name, err);
+                }
+            }
+        });
+
+        let mut response = outgoing_response(response).map_err(custom_reject)?;
+        response
+            .headers_mut()
+            .insert(INVOCATION_ID_HEADER, http::HeaderValue::from(invocation_id));
+        Ok(response)
+    }
+
+    // The wasi-http request of a request to the engine, wasi-http is built
+    // on the 1.x releases of `http` and warp on the 0.2 ones
+    fn incoming_request(
+        path: &str,
+        query: &str,
+        method: http::Method,
+        headers: http::HeaderMap,
+        body: impl Stream<Item = Result<impl Buf, warp::Error>> + Send + Sync + 'static,
+    ) -> anyhow::Result<hyper::Request<HyperIncomingBody>> {
+        let authority = headers
+            .get(http::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or("localhost");
+        let mut path_and_query = format!("/{}", path);
+        if !query.is_empty() {
+            path_and_query.push('?');
+            path_and_query.push_str(query);
+        }
+        let uri = hyper::Uri::builder()
+            .scheme("http")
+            .authority(authority)
+            .path_and_query(path_and_query)
+            .build()?;
+
+        let mut request = hyper::Request::builder().method(method.as_str()).uri(uri);
+        for (name, value) in headers.iter() {
+            request = request.header(name.as_str(), value.as_bytes());
+        }
+        let body = body.map(|chunk| match chunk {
+            Ok(mut chunk) => Ok(Frame::data(chunk.copy_to_bytes(chunk.remaining()))),
+            Err(_) => Err(ErrorCode::HttpProtocolError),
+        });
+        Ok(request.body(BodyExt::boxed(StreamBody::new(body)))?)
+    }
+
+    // The reply of a wasi-http response
+    fn outgoing_response(
+        response: hyper::Response<HyperOutgoingBody>,
+    ) -> anyhow::Result<warp::reply::Response> {
+        let (parts, body) = response.into_parts();
+        let body = body
+            .into_data_stream()
+            .map_err(|code| std::io::Error::other(format!("{:?}", code)));
+
+        let mut reply = http::Response::builder().status(parts.status.as_u16());
+        for (name, value) in parts.headers.iter() {
+            reply = reply.header(name.as_str(), value.as_bytes());
         }
+        Ok(reply.body(warp::hyper::Body::wrap_stream(body))?)
+    }
 
-        debug!("run module {} successfully!", invoke_req.function_name);
+    #[instrument]
+    pub async fn function_events(
+        last_event_id: Option<u64>,
+        req: FuncEventsReq,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let since = last_event_id
+            .or(req.since)
+            .unwrap_or_else(|| EVENT_LOG.version());
+        debug!("watch function events since version {}", since);
+
+        let (replay, receiver) = EVENT_LOG.subscribe(since).map_err(custom_reject)?;
+
+        // a lagging watcher is disconnected, it resumes from its last event id
+        let live = stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.ok().map(|event| (event, receiver))
+        });
+        let events = stream::iter(replay).chain(live).map(|event| {
+            warp::sse::Event::default()
+                .id(event.version.to_string())
+                .event(event.kind.to_string())
+                .json_data(&event)
+        });
+
+        Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+    }
+
+    // A key and its value in a bucket, invalid UTF-8 replaced
+    #[derive(Serialize)]
+    struct KvEntry {
+        key: String,
+        value: String,
+    }
+
+    #[derive(Serialize)]
+    struct BucketContents {
+        bucket: BucketInfo,
+        entries: Vec<KvEntry>,
+    }
+
+    // Run an operation of the key-value store off the async workers, sled
+    // blocks on I/O
+    async fn kv_blocking<R, F>(op: F) -> Result<R, warp::Rejection>
+    where
+        R: Send + 'static,
+        F: FnOnce(&'static KvStore) -> anyhow::Result<R> + Send + 'static,
+    {
+        tokio::task::spawn_blocking(move || op(kv_store()))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result)
+            .map_err(custom_reject)
+    }
+
+    // Existing bucket of an admin request
+    fn existing_bucket(kv: &KvStore, name: &str) -> anyhow::Result<KvBucket> {
+        if !kv.exist(name) {
+            return Err(anyhow!("bucket {} doesn't exist", name));
+        }
+        kv.bucket(name)
+    }
+
+    #[instrument]
+    pub async fn list_buckets() -> Result<impl warp::Reply, warp::Rejection> {
+        let buckets = kv_blocking(KvStore::buckets).await?;
+        debug!("list {} key-value buckets successfully", buckets.len());
+        Ok(warp::reply::json(&buckets))
+    }
+
+    #[instrument]
+    pub async fn inspect_bucket(req: KvBucketReq) -> Result<impl warp::Reply, warp::Rejection> {
+        let (name, prefix) = (req.bucket, req.prefix.unwrap_or_default());
+        let contents = kv_blocking(move |kv| {
+            let bucket = existing_bucket(kv, &name)?;
+            let entries = bucket
+                .entries(prefix.as_bytes())?
+                .into_iter()
+                .map(|(key, value)| KvEntry {
+                    key: String::from_utf8_lossy(&key).into_owned(),
+                    value: String::from_utf8_lossy(&value).into_owned(),
+                })
+                .collect();
+            Ok(BucketContents {
+                bucket: bucket.info(),
+                entries,
+            })
+        })
+        .await?;
+
+        Ok(warp::reply::json(&contents))
+    }
+
+    #[instrument]
+    pub async fn clear_bucket(req: KvBucketReq) -> Result<impl warp::Reply, warp::Rejection> {
+        let name = req.bucket.clone();
+        kv_blocking(move |kv| existing_bucket(kv, &name)?.clear()).await?;
+        debug!("clear key-value bucket {} successfully", req.bucket);
 
         Ok(Response {
             status: http::StatusCode::OK.as_u16(),
-            body: result,
+            body: format!("clear bucket {} successfully!\n", req.bucket),
         })
     }
+
+    #[instrument]
+    pub async fn metrics() -> Result<impl warp::Reply, warp::Rejection> {
+        let stats = MODULE_STORE.stats();
+
+        let mut metrics = String::new();
+        for (name, kind, help, value) in [
+            (
+                "wasmengine_modules_resident",
+                "gauge",
+                "Number of compiled modules kept in memory.",
+                stats.resident_entries as u64,
+            ),
+            (
+                "wasmengine_module_bytes_resident",
+                "gauge",
+                "Size in bytes of the compiled modules kept in memory.",
+                stats.resident_bytes as u64,
+            ),
+            (
+                "wasmengine_module_lru_evictions_total",
+                "counter",
+                "Compiled modules evicted to stay within the memory budget.",
+                stats.lru_evictions,
+            ),
+            (
+                "wasmengine_module_idle_evictions_total",
+                "counter",
+                "Compiled modules evicted after their idle timeout.",
+                stats.idle_evictions,
+            ),
+            (
+                "wasmengine_fuel_consumed_total",
+                "counter",
+                "Fuel consumed by invocations, one unit is about one instruction.",
+                FUEL_CONSUMED.load(Ordering::Relaxed),
+            ),
+            (
+                "wasmengine_fuel_exhausted_total",
+                "counter",
+                "Invocations stopped after using up their compute budget.",
+                FUEL_EXHAUSTED.load(Ordering::Relaxed),
+            ),
+            (
+                "wasmengine_limit_exceeded_total",
+                "counter",
+                "Invocations denied memory, table elements or instances by their limits.",
+                LIMIT_EXCEEDED.load(Ordering::Relaxed),
+            ),
+            (
+                "wasmengine_exit_failures_total",
+                "counter",
+                "Invocations of WASI functions exiting with a non-zero code.",
+                EXIT_FAILURES.load(Ordering::Relaxed),
+            ),
+        ] {
+            metrics.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                name, help, name, kind, name, value
+            ));
+        }
+
+        Ok(metrics)
+    }
+
+    #[instrument]
+    pub async fn readiness() -> Result<impl warp::Reply, warp::Rejection> {
+        let response = if READY.load(Ordering::SeqCst) {
+            Response {
+                status: http::StatusCode::OK.as_u16(),
+                body: "ready\n".to_string(),
+            }
+        } else {
+            Response {
+                status: http::StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+                body: "warming up functions\n".to_string(),
+            }
+        };
+
+        Ok(response.with_http_status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn readiness() {
+        READY.store(false, Ordering::SeqCst);
+        let response = warp::test::request()
+            .path("/ready")
+            .reply(&filters::readiness())
+            .await;
+        assert_eq!(response.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+
+        READY.store(true, Ordering::SeqCst);
+        let response = warp::test::request()
+            .path("/ready")
+            .reply(&filters::readiness())
+            .await;
+        assert_eq!(response.status(), http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn invoke_unavailable() -> anyhow::Result<()> {
+        FUNCTION_STORE
+            .insert(FunctionEntry {
+                func_name: "unavailable".to_string(),
+                ..Default::default()
+            })
+            .await?;
+        FUNCTION_STORE
+            .set_status(
+                "unavailable",
+                FunctionStatus::Unavailable("broken module".to_string()),
+            )
+            .await?;
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/function/invoke")
+            .json(&serde_json::json!({ "function_name": "unavailable" }))
+            .reply(&filters::function_management())
+            .await;
+        assert_eq!(response.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().get("retry-after").is_none());
+        let body = String::from_utf8_lossy(response.body());
+        assert!(body.contains("function unavailable: broken module"));
+
+        // requests to wasi:http proxies are routed with the same errors
+        FUNCTION_STORE
+            .insert(FunctionEntry {
+                func_name: "unavailable-proxy".to_string(),
+                kind: Some(FunctionKind::HttpProxy),
+                ..Default::default()
+            })
+            .await?;
+        FUNCTION_STORE
+            .set_status(
+                "unavailable-proxy",
+                FunctionStatus::Unavailable("broken component".to_string()),
+            )
+            .await?;
+        let response = warp::test::request()
+            .method("GET")
+            .path("/function/http/unavailable-proxy/items?id=1")
+            .reply(&filters::function_management())
+            .await;
+        assert_eq!(response.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        // other functions are rejected before being loaded
+        let response = warp::test::request()
+            .method("GET")
+            .path("/function/http/unavailable/items?id=1")
+            .reply(&filters::function_management())
+            .await;
+        let body = String::from_utf8_lossy(response.body());
+        assert!(
+            body.contains("is not a wasi:http proxy component"),
+            "{}",
+            body
+        );
+
+        // management replies keep HTTP 200 and carry their status in the body
+        let response = warp::test::request()
+            .method("POST")
+            .path("/function/query")
+            .json(&serde_json::json!({ "function_name": "unavailable" }))
+            .reply(&filters::function_management())
+            .await;
+        assert_eq!(response.status(), http::StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deploy_invalid_name() {
+        for name in ["", "a/b", ".hidden"] {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/function/deploy")
+                .json(&serde_json::json!({
+                    "function_name": name,
+                    "function_image": "example.com/add:latest",
+                }))
+                .reply(&filters::function_management())
+                .await;
+            let body = String::from_utf8_lossy(response.body());
+            assert!(body.contains("function_name"), "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn invoke_hidden_export() -> anyhow::Result<()> {
+        FUNCTION_STORE
+            .insert(FunctionEntry {
+                func_name: "handled".to_string(),
+                entrypoint: Some("add".to_string()),
+                handlers: HashMap::from([("halve".to_string(), "half".to_string())]),
+                ..Default::default()
+            })
+            .await?;
+        let func = FUNCTION_STORE.query("handled").await?;
+        assert!(func.exposes("add"));
+        assert!(func.exposes("half"));
+        assert!(!func.exposes("internal"));
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/function/invoke")
+            .json(&serde_json::json!({ "function_name": "handled", "export": "internal" }))
+            .reply(&filters::function_management())
+            .await;
+        let body = String::from_utf8_lossy(response.body());
+        assert!(
+            body.contains("is not the entrypoint or a handler"),
+            "{}",
+            body
+        );
+
+        Ok(())
+    }
 }
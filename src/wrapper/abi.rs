@@ -0,0 +1,207 @@
+use anyhow::{anyhow, Result};
+use std::convert::TryFrom;
+use tracing::debug;
+use wasmtime::{ExternType, Instance, Memory, Module, Store, TypedFunc, ValType};
+
+use super::instance::StoreState;
+use super::wasmtime_runtime::WASM_PAGE_SIZE;
+
+/// Exports a plain module allocates and frees the spawn buffers with.
+pub(crate) const ALLOC_EXPORT: &str = "alloc";
+pub(crate) const DEALLOC_EXPORT: &str = "dealloc";
+pub(crate) const REALLOC_EXPORT: &str = "cabi_realloc";
+/// Export of the legacy ABI, the arguments are written at its address.
+pub(crate) const HEAP_BASE_EXPORT: &str = "__heap_base";
+
+/// How the host hands the arguments to a plain module and takes its output
+/// back.
+///
+/// With a guest allocator the arguments are copied into a buffer from
+/// `alloc(len)` (or `cabi_realloc(0, 0, 1, len)`), the output is read from
+/// the `(ptr, len)` the export returns, and both buffers are released with
+/// `dealloc(ptr, len)` when the module exports it. Modules without an
+/// allocator of these signatures get their arguments written at
+/// `__heap_base`.
+pub(crate) enum GuestAbi {
+    Allocator {
+        alloc: Alloc,
+        dealloc: Option<TypedFunc<(i32, i32), ()>>,
+    },
+    HeapBase(i32),
+}
+
+pub(crate) enum Alloc {
+    Alloc(TypedFunc<i32, i32>),
+    Realloc(TypedFunc<(i32, i32, i32, i32), i32>),
+}
+
+impl GuestAbi {
+    /// Find out from the exports of an instance which ABI it speaks.
+    ///
+    /// Allocator exports of other signatures, e.g. the ones of a language
+    /// runtime, are not ours: the module falls back to the legacy ABI.
+    pub(crate) fn detect<T>(store: &mut Store<StoreState<T>>, instance: &Instance) -> Result<Self> {
+        let dealloc = match instance.get_func(&mut *store, DEALLOC_EXPORT) {
+            Some(func) => func.typed(&*store).map(Some),
+            None => Ok(None),
+        };
+        let alloc = match instance.get_func(&mut *store, ALLOC_EXPORT) {
+            Some(func) => func.typed(&*store).map(|alloc| Some(Alloc::Alloc(alloc))),
+            None => match instance.get_func(&mut *store, REALLOC_EXPORT) {
+                Some(func) => func
+                    .typed(&*store)
+                    .map(|realloc| Some(Alloc::Realloc(realloc))),
+                None => Ok(None),
+            },
+        };
+
+        match (alloc, dealloc) {
+            (Ok(Some(alloc)), Ok(dealloc)) => return Ok(GuestAbi::Allocator { alloc, dealloc }),
+            (Ok(None), Ok(_)) => {}
+            (Err(err), _) | (_, Err(err)) => debug!(
+                "allocator exports of another signature, using `{}`: {:#}",
+                HEAP_BASE_EXPORT, err
+            ),
+        }
+
+        let heap_base = instance
+            .get_global(&mut *store, HEAP_BASE_EXPORT)
+            .and_then(|global| global.get(&mut *store).i32())
+            .ok_or_else(|| {
+                anyhow!(
+                    "module exports neither `{}`, `{}` nor `{}` of the expected types",
+                    ALLOC_EXPORT,
+                    REALLOC_EXPORT,
+                    HEAP_BASE_EXPORT
+                )
+            })?;
+        Ok(GuestAbi::HeapBase(heap_base))
+    }
+
+    /// Whether instances of `module` are detected to speak an ABI, before
+    /// instantiating it.
+    pub(crate) fn supported(module: &Module) -> bool {
+        let (alloc, dealloc) = allocator_signatures(module);
+        if alloc == Some(true) && dealloc != Some(false) {
+            return true;
+        }
+
+        matches!(
+            module.get_export(HEAP_BASE_EXPORT),
+            Some(ExternType::Global(global)) if matches!(global.content(), ValType::I32)
+        )
+    }
+
+    /// Whether instances of `module` allocate the spawn buffers without ever
+    /// freeing them, with an allocator but no `dealloc` export.
+    pub(crate) fn leaks(module: &Module) -> bool {
+        allocator_signatures(module) == (Some(true), None)
+    }
+
+    /// Prepare a new instance, the legacy ABI grows a page past `__heap_base`
+    /// for the arguments.
+    pub(crate) fn reserve<T>(
+        &self,
+        store: &mut Store<StoreState<T>>,
+        memory: &Memory,
+    ) -> Result<()> {
+        if let GuestAbi::HeapBase(_) = self {
+            memory.grow(store, 1)?;
+        }
+        Ok(())
+    }
+
+    /// Copy `bytes` into guest memory and return their address.
+    pub(crate) async fn write<T: Send>(
+        &self,
+        store: &mut Store<StoreState<T>>,
+        memory: &Memory,
+        bytes: &[u8],
+    ) -> Result<i32> {
+        let len = guest_len(bytes.len())?;
+        let ptr = match self {
+            GuestAbi::Allocator { alloc, .. } => {
+                let ptr = match alloc {
+                    Alloc::Alloc(alloc) => alloc.call_async(&mut *store, len).await?,
+                    Alloc::Realloc(realloc) => {
+                        realloc.call_async(&mut *store, (0, 0, 1, len)).await?
+                    }
+                };
+                if ptr == 0 {
+                    return Err(anyhow!("guest failed to allocate {} bytes", len));
+                }
+                ptr
+            }
+            GuestAbi::HeapBase(heap_base) => {
+                let end = *heap_base as usize + bytes.len();
+                let size = memory.data_size(&*store);
+                if end > size {
+                    let pages = (end - size).div_ceil(WASM_PAGE_SIZE as usize);
+                    memory.grow(&mut *store, pages as u64)?;
+                }
+                *heap_base
+            }
+        };
+
+        memory.write(&mut *store, ptr as u32 as usize, bytes)?;
+        Ok(ptr)
+    }
+
+    /// Copy `len` bytes at `ptr` out of guest memory.
+    pub(crate) fn read<T>(
+        &self,
+        store: &Store<StoreState<T>>,
+        memory: &Memory,
+        ptr: i32,
+        len: i32,
+    ) -> Result<Vec<u8>> {
+        let start = ptr as u32 as usize;
+        let end = start + len as u32 as usize;
+        memory
+            .data(store)
+            .get(start..end)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| anyhow!("output {}..{} is out of the guest memory", start, end))
+    }
+
+    /// Release a buffer of the guest allocator, a no-op without `dealloc`.
+    pub(crate) async fn free<T: Send>(
+        &self,
+        store: &mut Store<StoreState<T>>,
+        ptr: i32,
+        len: i32,
+    ) -> Result<()> {
+        if let GuestAbi::Allocator {
+            dealloc: Some(dealloc),
+            ..
+        } = self
+        {
+            if len != 0 {
+                dealloc.call_async(store, (ptr, len)).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn guest_len(len: usize) -> Result<i32> {
+    i32::try_from(len).map_err(|_| anyhow!("input of {} bytes is too large for the guest", len))
+}
+
+// Whether the allocator and `dealloc` exports of `module` have the expected
+// signatures, `None` for a missing export
+fn allocator_signatures(module: &Module) -> (Option<bool>, Option<bool>) {
+    let signature =
+        |name: &str, params: &[ValType], results: &[ValType]| match module.get_export(name) {
+            Some(ExternType::Func(func)) => Some(
+                func.params().collect::<Vec<_>>() == params
+                    && func.results().collect::<Vec<_>>() == results,
+            ),
+            _ => None,
+        };
+    use ValType::I32;
+    let alloc = signature(ALLOC_EXPORT, &[I32], &[I32])
+        .or_else(|| signature(REALLOC_EXPORT, &[I32, I32, I32, I32], &[I32]));
+    let dealloc = signature(DEALLOC_EXPORT, &[I32, I32], &[]);
+    (alloc, dealloc)
+}
@@ -0,0 +1,318 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{Map, Value};
+use std::convert::TryFrom;
+use wasmtime::component::{Func, Instance, Resource, ResourceTable, Type, Val};
+use wasmtime::Store;
+use wasmtime_wasi::preview2::{WasiCtx, WasiView};
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+use wasmtime_wasi_http::types::{HostFutureIncomingResponse, OutgoingRequest};
+use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
+
+use super::instance::StoreState;
+use super::typed::{float, float_value};
+
+/// Interface of the `wasi:cli/run` world, run when no export is asked for.
+pub(crate) const RUN_INTERFACE: &str = "wasi:cli/run@0.2.0";
+pub(crate) const RUN_FUNC: &str = "run";
+/// Interface of the `wasi:http/proxy` world, called with the HTTP requests
+/// of the function.
+pub(crate) const HTTP_HANDLER_INTERFACE: &str = "wasi:http/incoming-handler";
+
+/// Host state of a component linked against WASI preview 2 and wasi-http.
+pub struct ComponentCtx {
+    pub wasi: WasiCtx,
+    pub http: WasiHttpCtx,
+    pub table: ResourceTable,
+}
+
+impl ComponentCtx {
+    pub fn new(wasi: WasiCtx) -> Self {
+        Self {
+            wasi,
+            http: WasiHttpCtx,
+            table: ResourceTable::new(),
+        }
+    }
+}
+
+impl WasiView for StoreState<ComponentCtx> {
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.ctx.table
+    }
+
+    fn ctx(&mut self) -> &mut WasiCtx {
+        &mut self.ctx.wasi
+    }
+}
+
+// Components may only serve the requests they are handed, like the WASI
+// contexts deny network access
+impl WasiHttpView for StoreState<ComponentCtx> {
+    fn ctx(&mut self) -> &mut WasiHttpCtx {
+        &mut self.ctx.http
+    }
+
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.ctx.table
+    }
+
+    fn send_request(
+        &mut self,
+        _request: OutgoingRequest,
+    ) -> wasmtime::Result<Resource<HostFutureIncomingResponse>> {
+        let denied = HostFutureIncomingResponse::Ready(Ok(Err(ErrorCode::HttpRequestDenied)));
+        Ok(WasiHttpView::table(self).push(denied)?)
+    }
+}
+
+/// Names of the exports of a component binary, without the ones of the
+/// components and modules nested in it.
+pub(crate) fn exports(wasm: &[u8]) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut depth = 0;
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        match payload? {
+            wasmparser::Payload::Version { .. } => depth += 1,
+            wasmparser::Payload::End(_) => depth -= 1,
+            wasmparser::Payload::ComponentExportSection(reader) if depth == 1 => {
+                for export in reader {
+                    names.push(export?.name.0.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(names)
+}
+
+/// Whether a component binary belongs to the `wasi:http/proxy` world, by
+/// exporting its incoming handler.
+pub(crate) fn is_http_proxy(wasm: &[u8]) -> Result<bool> {
+    Ok(exports(wasm)?
+        .iter()
+        .any(|name| name.split('@').next() == Some(HTTP_HANDLER_INTERFACE)))
+}
+
+/// Find a function export, `export` is either the name of a function of the
+/// root of the component or `<interface>#<function>`.
+pub(crate) fn func<T>(store: &mut Store<T>, instance: &Instance, export: &str) -> Option<Func> {
+    let mut exports = instance.exports(store);
+    match export.split_once('#') {
+        Some((interface, name)) => exports.instance(interface)?.func(name),
+        None => exports.root().func(export),
+    }
+}
+
+/// Convert JSON parameters to the component values of `types`.
+///
+/// Numbers, bools and strings map to themselves, a char is a one character
+/// string, lists and tuples are arrays and records are objects. Enums are
+/// the case name, flags an array of the set names and options `null` or
+/// their value. Variants are `{"<case>": payload}`, or the bare case name
+/// without a payload, and results are `{"ok": value}` or `{"err": value}`.
+pub(crate) fn params(types: &[Type], params: &[Value]) -> Result<Vec<Val>> {
+    if types.len() != params.len() {
+        bail!("expect {} parameters, get {}", types.len(), params.len());
+    }
+
+    types
+        .iter()
+        .zip(params)
+        .enumerate()
+        .map(|(i, (ty, value))| {
+            param(ty, value).with_context(|| format!("parameter {} is not a valid {:?}", i, ty))
+        })
+        .collect()
+}
+
+/// Convert results to JSON, the same way parameters are given.
+pub(crate) fn results(results: &[Val]) -> Result<Vec<Value>> {
+    results.iter().map(result).collect()
+}
+
+fn param(ty: &Type, value: &Value) -> Result<Val> {
+    let invalid = || anyhow!("unexpected {}", value);
+    Ok(match ty {
+        Type::Bool => Val::Bool(value.as_bool().ok_or_else(invalid)?),
+        Type::S8 => Val::S8(int(value)?),
+        Type::U8 => Val::U8(int(value)?),
+        Type::S16 => Val::S16(int(value)?),
+        Type::U16 => Val::U16(int(value)?),
+        Type::S32 => Val::S32(int(value)?),
+        Type::U32 => Val::U32(int(value)?),
+        Type::S64 => Val::S64(int(value)?),
+        Type::U64 => Val::U64(int(value)?),
+        Type::Float32 => Val::Float32(float(value).ok_or_else(invalid)? as f32),
+        Type::Float64 => Val::Float64(float(value).ok_or_else(invalid)?),
+        Type::Char => {
+            let s = value.as_str().ok_or_else(invalid)?;
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Val::Char(c),
+                _ => return Err(invalid()),
+            }
+        }
+        Type::String => Val::String(value.as_str().ok_or_else(invalid)?.into()),
+        Type::List(list) => {
+            let ty = list.ty();
+            let values = value
+                .as_array()
+                .ok_or_else(invalid)?
+                .iter()
+                .map(|v| param(&ty, v))
+                .collect::<Result<_>>()?;
+            list.new_val(values)?
+        }
+        Type::Record(record) => {
+            let object = value.as_object().ok_or_else(invalid)?;
+            let fields = record
+                .fields()
+                .map(|field| {
+                    let value = object
+                        .get(field.name)
+                        .ok_or_else(|| anyhow!("missing field `{}`", field.name))?;
+                    let value = param(&field.ty, value)
+                        .with_context(|| format!("invalid field `{}`", field.name))?;
+                    Ok((field.name, value))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            record.new_val(fields)?
+        }
+        Type::Tuple(tuple) => {
+            let array = value.as_array().ok_or_else(invalid)?;
+            if array.len() != tuple.types().len() {
+                return Err(invalid());
+            }
+            let values = tuple
+                .types()
+                .zip(array)
+                .map(|(ty, v)| param(&ty, v))
+                .collect::<Result<_>>()?;
+            tuple.new_val(values)?
+        }
+        Type::Variant(variant) => {
+            let (name, payload) = case(value).ok_or_else(invalid)?;
+            let ty = variant
+                .cases()
+                .find(|c| c.name == name)
+                .ok_or_else(|| anyhow!("unknown case `{}`", name))?
+                .ty;
+            let payload = match (ty, payload) {
+                (Some(ty), Some(payload)) => Some(param(&ty, payload)?),
+                (None, None | Some(Value::Null)) => None,
+                _ => return Err(invalid()),
+            };
+            variant.new_val(name, payload)?
+        }
+        Type::Enum(enum_) => enum_.new_val(value.as_str().ok_or_else(invalid)?)?,
+        Type::Option(option) => match value {
+            Value::Null => option.new_val(None)?,
+            _ => option.new_val(Some(param(&option.ty(), value)?))?,
+        },
+        Type::Result(result) => {
+            let (name, payload) = case(value).ok_or_else(invalid)?;
+            let payload = |ty: Option<Type>| match (ty, payload) {
+                (Some(ty), Some(payload)) => param(&ty, payload).map(Some),
+                (None, None | Some(Value::Null)) => Ok(None),
+                _ => Err(invalid()),
+            };
+            match name {
+                "ok" => result.new_val(Ok(payload(result.ok())?))?,
+                "err" => result.new_val(Err(payload(result.err())?))?,
+                _ => return Err(invalid()),
+            }
+        }
+        Type::Flags(flags) => {
+            let names = value
+                .as_array()
+                .ok_or_else(invalid)?
+                .iter()
+                .map(|v| v.as_str().ok_or_else(invalid))
+                .collect::<Result<Vec<_>>>()?;
+            flags.new_val(&names)?
+        }
+        Type::Own(_) | Type::Borrow(_) => bail!("resources can't be given as JSON"),
+    })
+}
+
+fn int<N: TryFrom<i128>>(value: &Value) -> Result<N> {
+    let n = match value {
+        Value::String(s) => s.parse::<i128>().ok(),
+        _ => value
+            .as_i64()
+            .map(i128::from)
+            .or_else(|| value.as_u64().map(i128::from)),
+    };
+    n.and_then(|n| N::try_from(n).ok())
+        .ok_or_else(|| anyhow!("integer out of range: {}", value))
+}
+
+// `"<case>"` or `{"<case>": payload}`
+fn case(value: &Value) -> Option<(&str, Option<&Value>)> {
+    match value {
+        Value::String(name) => Some((name, None)),
+        Value::Object(object) if object.len() == 1 => {
+            let (name, payload) = object.iter().next()?;
+            Some((name, Some(payload)))
+        }
+        _ => None,
+    }
+}
+
+fn result(val: &Val) -> Result<Value> {
+    Ok(match val {
+        Val::Bool(b) => Value::from(*b),
+        Val::S8(n) => Value::from(*n),
+        Val::U8(n) => Value::from(*n),
+        Val::S16(n) => Value::from(*n),
+        Val::U16(n) => Value::from(*n),
+        Val::S32(n) => Value::from(*n),
+        Val::U32(n) => Value::from(*n),
+        Val::S64(n) => Value::from(*n),
+        Val::U64(n) => Value::from(*n),
+        Val::Float32(f) => float_value(*f as f64),
+        Val::Float64(f) => float_value(*f),
+        Val::Char(c) => Value::from(c.to_string()),
+        Val::String(s) => Value::from(s.as_ref()),
+        Val::List(list) => Value::Array(list.iter().map(result).collect::<Result<_>>()?),
+        Val::Record(record) => Value::Object(
+            record
+                .fields()
+                .map(|(name, v)| Ok((name.to_string(), result(v)?)))
+                .collect::<Result<Map<_, _>>>()?,
+        ),
+        Val::Tuple(tuple) => {
+            Value::Array(tuple.values().iter().map(result).collect::<Result<_>>()?)
+        }
+        Val::Variant(variant) => case_value(variant.discriminant(), variant.payload())?,
+        Val::Enum(enum_) => Value::from(enum_.discriminant()),
+        Val::Option(option) => match option.value() {
+            Some(v) => result(v)?,
+            None => Value::Null,
+        },
+        Val::Result(res) => match res.value() {
+            Ok(v) => payload_value("ok", v)?,
+            Err(v) => payload_value("err", v)?,
+        },
+        Val::Flags(flags) => Value::Array(flags.flags().map(Value::from).collect()),
+        Val::Resource(_) => bail!("resources can't be returned as JSON"),
+    })
+}
+
+fn case_value(name: &str, payload: Option<&Val>) -> Result<Value> {
+    match payload {
+        Some(_) => payload_value(name, payload),
+        None => Ok(Value::from(name)),
+    }
+}
+
+// `{"<name>": payload}`, with a `null` payload if there is none
+fn payload_value(name: &str, payload: Option<&Val>) -> Result<Value> {
+    let payload = match payload {
+        Some(v) => result(v)?,
+        None => Value::Null,
+    };
+    let mut object = Map::new();
+    object.insert(name.to_string(), payload);
+    Ok(Value::Object(object))
+}
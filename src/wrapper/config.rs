@@ -1,13 +1,43 @@
+use crate::wrapper::input::WasiInput;
+use crate::wrapper::kv::KvHandle;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Bytes of stderr kept per invocation by default, it is returned in the
+/// body of the invocation along with the output.
+pub const DEFAULT_MAX_STDERR_BYTES: usize = 4096;
+
+/// Bytes of stdout a WASI function may write per invocation by default.
+pub const DEFAULT_MAX_OUTPUT_BYTES: usize = 16 << 20;
+
+// argv[0] of a WASI function without a program name
+const DEFAULT_PROGRAM_NAME: &str = "main";
+
 /// Configuration structure for environments.
 #[derive(Clone)]
 pub struct EnvConfig {
-    // Maximum amount of memory that can be used by instances in bytes
+    // Address space reserved up front for each linear memory in bytes, the
+    // memory an instance may actually use is bounded by `limits`
     max_memory: usize,
     // Maximum amount of compute expressed in units of 100k instructions.
     max_fuel: Option<u64>,
     allowed_namespaces: Vec<String>,
     preopened_dirs: Vec<String>,
     wasi_envs: Option<Vec<(String, String)>>,
+    // Preallocate instance resources in pools instead of allocating on demand
+    pooling: Option<PoolingConfig>,
+    // Wall-clock time an invocation may run for
+    timeout: Option<Duration>,
+    // Resources an instance may hold, unless set per function
+    limits: ResourceLimits,
+    // Bytes of the stderr of WASI functions kept per invocation
+    max_stderr_bytes: usize,
+    // Bytes of the stdout of WASI functions an invocation may write
+    max_output_bytes: usize,
+    // Directory of the precompiled artifacts, only the engine may write there
+    cache_dir: Option<PathBuf>,
 }
 
 impl EnvConfig {
@@ -19,6 +49,12 @@ impl EnvConfig {
             allowed_namespaces: Vec::new(),
             preopened_dirs: Vec::new(),
             wasi_envs: None,
+            pooling: None,
+            timeout: None,
+            limits: ResourceLimits::default(),
+            max_stderr_bytes: DEFAULT_MAX_STDERR_BYTES,
+            max_output_bytes: DEFAULT_MAX_OUTPUT_BYTES,
+            cache_dir: None,
         }
     }
 
@@ -30,6 +66,59 @@ impl EnvConfig {
         self.max_fuel
     }
 
+    /// Limit every invocation to `max_fuel` units of compute, unlimited if `None`.
+    pub fn set_max_fuel(&mut self, max_fuel: Option<u64>) {
+        self.max_fuel = max_fuel;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Stop every invocation running longer than `timeout`, never if `None`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn limits(&self) -> &ResourceLimits {
+        &self.limits
+    }
+
+    /// Bound the resources every instance may hold.
+    pub fn set_limits(&mut self, limits: ResourceLimits) {
+        self.limits = limits;
+    }
+
+    pub fn max_stderr_bytes(&self) -> usize {
+        self.max_stderr_bytes
+    }
+
+    /// Keep at most `max_stderr_bytes` of the stderr of each invocation.
+    pub fn set_max_stderr_bytes(&mut self, max_stderr_bytes: usize) {
+        self.max_stderr_bytes = max_stderr_bytes;
+    }
+
+    pub fn max_output_bytes(&self) -> usize {
+        self.max_output_bytes
+    }
+
+    /// Fail the invocations of WASI functions writing more than
+    /// `max_output_bytes` to stdout.
+    pub fn set_max_output_bytes(&mut self, max_output_bytes: usize) {
+        self.max_output_bytes = max_output_bytes;
+    }
+
+    pub fn cache_dir(&self) -> Option<&Path> {
+        self.cache_dir.as_deref()
+    }
+
+    /// Keep the precompiled artifacts of the compiled modules in `cache_dir`,
+    /// not on disk if `None`. The directory must not be reachable by guests
+    /// nor by images.
+    pub fn set_cache_dir(&mut self, cache_dir: Option<PathBuf>) {
+        self.cache_dir = cache_dir;
+    }
+
     pub fn allowed_namespace(&self) -> &[String] {
         &self.allowed_namespaces
     }
@@ -55,16 +144,266 @@ impl EnvConfig {
     pub fn wasi_envs(&self) -> &Option<Vec<(String, String)>> {
         &self.wasi_envs
     }
+
+    /// Use the pooling instance allocator sized by `pooling`.
+    pub fn set_pooling(&mut self, pooling: PoolingConfig) {
+        self.pooling = Some(pooling);
+    }
+
+    pub fn pooling(&self) -> Option<&PoolingConfig> {
+        self.pooling.as_ref()
+    }
 }
 
 impl Default for EnvConfig {
     fn default() -> Self {
         Self {
-            max_memory: 0xA00000000, // = 4 GB in bytes
+            max_memory: 0xA00000000, // = 40 GiB in bytes
             max_fuel: None,
             allowed_namespaces: vec![String::from("wasi_snapshot_preview1::")],
             preopened_dirs: vec![],
             wasi_envs: None,
+            pooling: None,
+            timeout: None,
+            limits: ResourceLimits::default(),
+            max_stderr_bytes: DEFAULT_MAX_STDERR_BYTES,
+            max_output_bytes: DEFAULT_MAX_OUTPUT_BYTES,
+            cache_dir: None,
+        }
+    }
+}
+
+/// Sizing of the pooling instance allocator.
+///
+/// Every slot is reserved up front, so an instantiation fails instead of
+/// waiting once all slots of a kind are in use.
+#[derive(Clone, Debug)]
+pub struct PoolingConfig {
+    // Maximum number of concurrent component instances
+    pub total_instances: u32,
+    // Maximum number of concurrent core module instances
+    pub total_core_instances: u32,
+    // Maximum number of concurrent linear memories
+    pub total_memories: u32,
+    // Maximum number of concurrent tables
+    pub total_tables: u32,
+    // Maximum size of a linear memory in wasm pages (64 KiB)
+    pub memory_pages: u64,
+    // Maximum number of elements of a table
+    pub table_elements: u32,
+}
+
+impl Default for PoolingConfig {
+    fn default() -> Self {
+        Self {
+            total_instances: 1000,
+            total_core_instances: 1000,
+            total_memories: 1000,
+            total_tables: 1000,
+            memory_pages: 160, // = 10 MiB
+            table_elements: 10_000,
+        }
+    }
+}
+
+/// Resources a single instance may hold, unlimited where unset.
+///
+/// Growing a memory or a table past its limit fails as the guest sees it,
+/// an invocation failing after that reports the denied growth.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    /// Size of a linear memory in bytes
+    pub max_memory_bytes: Option<usize>,
+    /// Number of elements of a table
+    pub max_table_elements: Option<u32>,
+    /// Number of instances in the store of an invocation
+    pub max_instances: Option<usize>,
+    /// Number of linear memories in the store of an invocation
+    pub max_memories: Option<usize>,
+}
+
+impl ResourceLimits {
+    /// These limits, with the ones of `defaults` where unset.
+    pub fn or(&self, defaults: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            max_memory_bytes: self.max_memory_bytes.or(defaults.max_memory_bytes),
+            max_table_elements: self.max_table_elements.or(defaults.max_table_elements),
+            max_instances: self.max_instances.or(defaults.max_instances),
+            max_memories: self.max_memories.or(defaults.max_memories),
         }
     }
 }
+
+/// Configuration of a single function, applied on top of the environment
+/// configuration when the function is spawned.
+#[derive(Clone, Debug, Default)]
+pub struct FunctionConfig {
+    // Host directories and the guest paths they are mounted at
+    preopened_dirs: Vec<(String, String)>,
+    // Host directories mounted like `preopened_dirs` that can only be read
+    read_only_dirs: Vec<(String, String)>,
+    // Compute budget in units of 100k instructions, overriding the environment one
+    max_fuel: Option<u64>,
+    // Upper bound of the compute budget set by the request
+    fuel_cap: Option<u64>,
+    // Wall-clock deadline, overriding the environment one
+    timeout: Option<Duration>,
+    // Upper bound of the deadline set by the request
+    timeout_cap: Option<Duration>,
+    // Resources an instance may hold, overriding the environment ones
+    limits: ResourceLimits,
+    // How the arguments are handed to a WASI function
+    wasi_input: WasiInput,
+    // argv[0] of a WASI function, the function name
+    program_name: Option<String>,
+    // Raw input of a single invocation, given on stdin
+    input: Option<Vec<u8>>,
+    // Name of the function, recorded with the lines it logs
+    function_name: String,
+    // Identifier of a single invocation, recorded with the lines it logs
+    invocation_id: u64,
+    // Keep the lines logged by a single invocation for its output
+    capture_logs: bool,
+    // Bucket of the key-value host functions
+    kv_bucket: Option<Arc<dyn KvHandle>>,
+}
+
+impl FunctionConfig {
+    /// Create an empty function configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn preopened_dirs(&self) -> &[(String, String)] {
+        &self.preopened_dirs
+    }
+
+    /// Grant access to the host directory `dir` under the guest path `guest_dir`.
+    pub fn preopen_dir_as<S: Into<String>>(&mut self, dir: S, guest_dir: S) {
+        self.preopened_dirs.push((dir.into(), guest_dir.into()))
+    }
+
+    pub fn read_only_dirs(&self) -> &[(String, String)] {
+        &self.read_only_dirs
+    }
+
+    /// Grant read access to the host directory `dir` under the guest path
+    /// `guest_dir`.
+    pub fn preopen_read_only_dir_as<S: Into<String>>(&mut self, dir: S, guest_dir: S) {
+        self.read_only_dirs.push((dir.into(), guest_dir.into()))
+    }
+
+    /// Give the function its own compute budget in units of 100k instructions.
+    pub fn set_max_fuel(&mut self, max_fuel: u64) {
+        self.max_fuel = Some(max_fuel);
+    }
+
+    /// Lower the compute budget of a single invocation to at most `cap` units.
+    pub fn cap_fuel(&mut self, cap: u64) {
+        self.fuel_cap = capped(self.fuel_cap, Some(cap));
+    }
+
+    /// Compute budget of an invocation in units of 100k instructions, given
+    /// the environment budget `max_fuel`. `None` means unlimited.
+    pub fn fuel_budget(&self, max_fuel: Option<u64>) -> Option<u64> {
+        capped(self.max_fuel.or(max_fuel), self.fuel_cap)
+    }
+
+    /// Give the function its own wall-clock deadline.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    /// Shorten the deadline of a single invocation to at most `cap`.
+    pub fn cap_timeout(&mut self, cap: Duration) {
+        self.timeout_cap = capped(self.timeout_cap, Some(cap));
+    }
+
+    /// Wall-clock deadline of an invocation, given the environment deadline
+    /// `timeout`. `None` means no deadline.
+    pub fn timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        capped(self.timeout.or(timeout), self.timeout_cap)
+    }
+
+    /// Give the function its own resource limits, the unset ones stay those
+    /// of the environment.
+    pub fn set_limits(&mut self, limits: ResourceLimits) {
+        self.limits = limits;
+    }
+
+    /// Resource limits of an instance, given the environment limits `limits`.
+    pub fn limits(&self, limits: &ResourceLimits) -> ResourceLimits {
+        self.limits.or(limits)
+    }
+
+    pub fn wasi_input(&self) -> &WasiInput {
+        &self.wasi_input
+    }
+
+    /// Hand the arguments to a WASI function the `wasi_input` way.
+    pub fn set_wasi_input(&mut self, wasi_input: WasiInput) {
+        self.wasi_input = wasi_input;
+    }
+
+    pub fn program_name(&self) -> &str {
+        self.program_name.as_deref().unwrap_or(DEFAULT_PROGRAM_NAME)
+    }
+
+    /// Name a WASI function gets as argv[0].
+    pub fn set_program_name<S: Into<String>>(&mut self, program_name: S) {
+        self.program_name = Some(program_name.into());
+    }
+
+    pub fn input(&self) -> Option<&[u8]> {
+        self.input.as_deref()
+    }
+
+    /// Give `input` on stdin to a single invocation of a WASI function.
+    pub fn set_input(&mut self, input: Vec<u8>) {
+        self.input = Some(input);
+    }
+
+    pub fn function_name(&self) -> &str {
+        &self.function_name
+    }
+
+    /// Name of the function recorded with the lines it logs.
+    pub fn set_function_name<S: Into<String>>(&mut self, function_name: S) {
+        self.function_name = function_name.into();
+    }
+
+    pub fn invocation_id(&self) -> u64 {
+        self.invocation_id
+    }
+
+    /// Identify a single invocation in the lines it logs.
+    pub fn set_invocation_id(&mut self, invocation_id: u64) {
+        self.invocation_id = invocation_id;
+    }
+
+    pub fn capture_logs(&self) -> bool {
+        self.capture_logs
+    }
+
+    /// Return the lines logged by a single invocation with its output.
+    pub fn set_capture_logs(&mut self, capture_logs: bool) {
+        self.capture_logs = capture_logs;
+    }
+
+    pub fn kv_bucket(&self) -> Option<&Arc<dyn KvHandle>> {
+        self.kv_bucket.as_ref()
+    }
+
+    /// Give the key-value host functions the `bucket` of the function.
+    pub fn set_kv_bucket(&mut self, bucket: Arc<dyn KvHandle>) {
+        self.kv_bucket = Some(bucket);
+    }
+}
+
+// The smaller of two optional limits, where `None` is no limit
+fn capped<T: Ord>(limit: Option<T>, cap: Option<T>) -> Option<T> {
+    match (limit, cap) {
+        (Some(limit), Some(cap)) => Some(limit.min(cap)),
+        (limit, cap) => limit.or(cap),
+    }
+}
@@ -0,0 +1,99 @@
+use std::fmt;
+use std::time::Duration;
+use wasmtime::Trap;
+
+const LIMIT_EXCEEDED_PREFIX: &str = "resource limit exceeded: ";
+
+/// Failures of an invocation that are not caused by the function itself.
+///
+/// They are carried inside `anyhow::Error` and can be told apart with
+/// `downcast_ref::<InvokeError>()`.
+#[derive(Debug)]
+pub enum InvokeError {
+    /// Every slot of the pooling instance allocator is taken
+    PoolExhausted(String),
+    /// The invocation used up its compute budget, in units of 100k instructions
+    FuelExhausted(u64),
+    /// The invocation ran past its wall-clock deadline
+    Timeout(Duration),
+    /// The invocation needed more memory, table elements or instances than
+    /// its resource limits allow
+    LimitExceeded(String),
+    /// The function failed validation, with the reason
+    Unavailable(String),
+}
+
+impl InvokeError {
+    /// Whether the same invocation may succeed when retried later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, InvokeError::PoolExhausted(_))
+    }
+
+    /// Classify an instantiation error, wasmtime reports a full pool as
+    /// "maximum concurrent ... limit of N reached" and too many instances or
+    /// memories in a store as "resource limit exceeded: ...".
+    pub(crate) fn from_instantiate(err: anyhow::Error) -> anyhow::Error {
+        let exhausted = err.chain().any(|cause| {
+            let message = cause.to_string();
+            message.starts_with("maximum concurrent") && message.contains("reached")
+        });
+        if exhausted {
+            return InvokeError::PoolExhausted(err.to_string()).into();
+        }
+
+        let limited = err.chain().find_map(|cause| {
+            cause
+                .to_string()
+                .strip_prefix(LIMIT_EXCEEDED_PREFIX)
+                .map(str::to_string)
+        });
+        match limited {
+            Some(reason) => InvokeError::LimitExceeded(reason).into(),
+            None => err,
+        }
+    }
+
+    /// Classify an error of a guest call, `budget` and `timeout` are the
+    /// compute budget and the deadline the call ran with, `denied` the last
+    /// growth the resource limits refused.
+    pub(crate) fn from_call(
+        err: anyhow::Error,
+        budget: Option<u64>,
+        timeout: Option<Duration>,
+        denied: Option<String>,
+    ) -> anyhow::Error {
+        match (err.downcast_ref::<Trap>(), budget, timeout, denied) {
+            (Some(Trap::OutOfFuel), Some(budget), _, _) => {
+                InvokeError::FuelExhausted(budget).into()
+            }
+            (Some(Trap::Interrupt), _, Some(timeout), _) => InvokeError::Timeout(timeout).into(),
+            // guests usually trap once an allocation fails
+            (_, _, _, Some(denied)) if !err.is::<InvokeError>() => {
+                InvokeError::LimitExceeded(denied).into()
+            }
+            _ => err,
+        }
+    }
+}
+
+impl fmt::Display for InvokeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvokeError::PoolExhausted(reason) => write!(f, "instance pool exhausted: {}", reason),
+            InvokeError::FuelExhausted(budget) => {
+                write!(
+                    f,
+                    "fuel exhausted: compute budget of {} units used up",
+                    budget
+                )
+            }
+            InvokeError::Timeout(timeout) => {
+                write!(f, "timeout: deadline of {}ms exceeded", timeout.as_millis())
+            }
+            InvokeError::LimitExceeded(reason) => write!(f, "{}{}", LIMIT_EXCEEDED_PREFIX, reason),
+            InvokeError::Unavailable(reason) => write!(f, "function unavailable: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for InvokeError {}
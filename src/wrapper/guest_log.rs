@@ -0,0 +1,169 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use wasmtime::{Caller, Extern, Linker};
+
+use super::config::FunctionConfig;
+use super::instance::StoreState;
+
+/// Import modules the host logging function is linked in.
+pub const LOG_MODULES: [&str; 2] = ["env", "wasm_engine"];
+/// Name of the host logging function, `log(level: i32, ptr: i32, len: i32)`.
+pub const LOG_FUNC: &str = "log";
+
+// Bounds of the lines of an invocation, traced and kept for the response
+const MAX_LINES: usize = 1024;
+const MAX_KEPT_LINES: usize = 256;
+const MAX_LINE_BYTES: usize = 4096;
+
+/// Severity of a guest log line, `level` 0 to 4 from trace to error.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl From<i32> for LogLevel {
+    fn from(level: i32) -> Self {
+        match level {
+            i32::MIN..=0 => LogLevel::Trace,
+            1 => LogLevel::Debug,
+            2 => LogLevel::Info,
+            3 => LogLevel::Warn,
+            _ => LogLevel::Error,
+        }
+    }
+}
+
+/// A line logged by a guest during an invocation.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LogLine {
+    pub level: LogLevel,
+    pub message: String,
+}
+
+/// Lines logged by the guest during an invocation, recorded as `tracing`
+/// events and kept for the response when the invocation asks for them.
+#[derive(Debug, Default)]
+pub struct GuestLog {
+    function: String,
+    invocation_id: u64,
+    capture: bool,
+    logged: usize,
+    lines: Vec<LogLine>,
+}
+
+impl GuestLog {
+    /// Start the log of an invocation configured by `func_config`.
+    pub(crate) fn start(&mut self, func_config: &FunctionConfig) {
+        self.function = func_config.function_name().to_string();
+        self.invocation_id = func_config.invocation_id();
+        self.capture = func_config.capture_logs();
+        self.logged = 0;
+        self.lines.clear();
+    }
+
+    /// Take the lines kept since the invocation started.
+    pub(crate) fn take(&mut self) -> Vec<LogLine> {
+        std::mem::take(&mut self.lines)
+    }
+
+    // `message` is at most `MAX_LINE_BYTES` long, lines past `MAX_LINES`
+    // are dropped
+    fn record(&mut self, level: LogLevel, message: &str) {
+        let (function, invocation_id) = (self.function.as_str(), self.invocation_id);
+        self.logged += 1;
+        if self.logged > MAX_LINES {
+            if self.logged == MAX_LINES + 1 {
+                tracing::warn!(
+                    target: "wasm_engine::guest",
+                    function,
+                    invocation_id,
+                    "guest logged more than {} lines, dropping the rest",
+                    MAX_LINES
+                );
+            }
+            return;
+        }
+        // guests can't forge lines or terminal sequences
+        let message = escape_control(message);
+        match level {
+            LogLevel::Trace => {
+                tracing::trace!(target: "wasm_engine::guest", function, invocation_id, "{}", message)
+            }
+            LogLevel::Debug => {
+                tracing::debug!(target: "wasm_engine::guest", function, invocation_id, "{}", message)
+            }
+            LogLevel::Info => {
+                tracing::info!(target: "wasm_engine::guest", function, invocation_id, "{}", message)
+            }
+            LogLevel::Warn => {
+                tracing::warn!(target: "wasm_engine::guest", function, invocation_id, "{}", message)
+            }
+            LogLevel::Error => {
+                tracing::error!(target: "wasm_engine::guest", function, invocation_id, "{}", message)
+            }
+        }
+
+        if self.capture && self.lines.len() < MAX_KEPT_LINES {
+            self.lines.push(LogLine { level, message });
+        }
+    }
+}
+
+// Escape the control characters of `message`, e.g. a new line as `\n`
+fn escape_control(message: &str) -> String {
+    let mut escaped = String::with_capacity(message.len());
+    for c in message.chars() {
+        if c.is_control() {
+            escaped.extend(c.escape_default());
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+// The first `MAX_LINE_BYTES` of `message`, cut on a character boundary
+fn truncate(message: &str) -> &str {
+    let mut end = message.len().min(MAX_LINE_BYTES);
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    &message[..end]
+}
+
+/// Link the host logging function in every module of `LOG_MODULES`.
+pub(crate) fn add_to_linker<T: 'static>(linker: &mut Linker<StoreState<T>>) -> Result<()> {
+    for module in LOG_MODULES {
+        linker.func_wrap(
+            module,
+            LOG_FUNC,
+            |mut caller: Caller<'_, StoreState<T>>, level: i32, ptr: i32, len: i32| {
+                let memory = caller
+                    .get_export("memory")
+                    .and_then(Extern::into_memory)
+                    .ok_or_else(|| anyhow!("failed to find `memory` export"))?;
+                let (data, state) = memory.data_and_store_mut(&mut caller);
+                let start = ptr as u32 as usize;
+                let message = data
+                    .get(start..start.saturating_add(len as u32 as usize))
+                    .ok_or_else(|| anyhow!("log message out of the bounds of memory"))?;
+                // only the kept bytes are decoded, a character they cut is replaced
+                let message = &message[..message.len().min(MAX_LINE_BYTES)];
+                let message = String::from_utf8_lossy(message);
+                state.log.record(LogLevel::from(level), truncate(&message));
+                Ok(())
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns true if the import `module::name` is the host logging function.
+pub(crate) fn provides(module: &str, name: &str) -> bool {
+    LOG_MODULES.contains(&module) && name == LOG_FUNC
+}
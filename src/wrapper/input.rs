@@ -0,0 +1,113 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// How the arguments of an invocation are handed to a WASI function.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum WasiInput {
+    /// The arguments serialized as JSON in a single argv element, without
+    /// the program name.
+    #[default]
+    Legacy,
+    /// The raw input of the invocation, or the arguments serialized as
+    /// JSON, on stdin.
+    Stdin,
+    /// argv built from `template`, whose first element is the program name.
+    /// `{name}` is replaced by the argument `name`.
+    Argv { template: Vec<String> },
+    /// Every argument as an environment variable named `prefix` followed by
+    /// the argument name.
+    Env {
+        #[serde(default)]
+        prefix: String,
+    },
+}
+
+/// argv, environment variables and stdin of a WASI invocation.
+#[derive(Debug, Default)]
+pub(crate) struct WasiArgs {
+    pub(crate) argv: Vec<String>,
+    pub(crate) envs: Vec<(String, String)>,
+    pub(crate) stdin: Option<Vec<u8>>,
+}
+
+impl WasiInput {
+    /// Reject inputs that can never be rendered.
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            WasiInput::Argv { template } if template.is_empty() => {
+                Err(anyhow!("argv template needs at least the program name"))
+            }
+            WasiInput::Argv { template } => template
+                .iter()
+                .try_for_each(|arg| substitute(arg, |_| Ok("")).map(|_| ())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Lay out the arguments of an invocation, `input` is its raw input
+    /// given on stdin in every mode.
+    pub(crate) fn render(
+        &self,
+        program: &str,
+        args: &HashMap<String, String>,
+        input: Option<&[u8]>,
+    ) -> Result<WasiArgs> {
+        let stdin = input.map(<[u8]>::to_vec);
+        Ok(match self {
+            WasiInput::Legacy => WasiArgs {
+                argv: vec![serde_json::to_string(args)?],
+                envs: Vec::new(),
+                stdin,
+            },
+            WasiInput::Stdin => WasiArgs {
+                argv: vec![program.to_string()],
+                envs: Vec::new(),
+                stdin: match stdin {
+                    Some(stdin) => Some(stdin),
+                    None => Some(serde_json::to_vec(args)?),
+                },
+            },
+            WasiInput::Argv { template } => WasiArgs {
+                argv: template
+                    .iter()
+                    .map(|arg| {
+                        substitute(arg, |name| {
+                            args.get(name).map(String::as_str).ok_or_else(|| {
+                                anyhow!("missing argument `{}` of the argv template", name)
+                            })
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+                envs: Vec::new(),
+                stdin,
+            },
+            WasiInput::Env { prefix } => WasiArgs {
+                argv: vec![program.to_string()],
+                envs: args
+                    .iter()
+                    .map(|(name, value)| (format!("{}{}", prefix, name), value.clone()))
+                    .collect(),
+                stdin,
+            },
+        })
+    }
+}
+
+// Replace the `{name}` placeholders of a template argument with `value(name)`
+fn substitute<'a>(arg: &str, value: impl Fn(&str) -> Result<&'a str>) -> Result<String> {
+    let mut substituted = String::new();
+    let mut rest = arg;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| anyhow!("unclosed placeholder in argv template `{}`", arg))?;
+        substituted.push_str(&rest[..start]);
+        substituted.push_str(value(&rest[start + 1..end])?);
+        rest = &rest[end + 1..];
+    }
+    substituted.push_str(rest);
+    Ok(substituted)
+}
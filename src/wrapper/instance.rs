@@ -0,0 +1,185 @@
+use crate::wrapper::component::ComponentCtx;
+use crate::wrapper::guest_log::{GuestLog, LogLine};
+use crate::wrapper::kv::KvHandle;
+use crate::wrapper::limiter::InvocationLimiter;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use wasi_common::WasiCtx;
+use wasmtime::component::{self, Component};
+use wasmtime::{Global, Instance, InstancePre, Module, Store, Val};
+
+/// A compiled function, either a core module or a component.
+#[derive(Clone)]
+pub enum Compiled {
+    Module(Module),
+    Component(Component),
+    /// Component of the `wasi:http/proxy` world
+    HttpProxy(Component),
+}
+
+impl From<Module> for Compiled {
+    fn from(module: Module) -> Self {
+        Compiled::Module(module)
+    }
+}
+
+impl From<Component> for Compiled {
+    fn from(component: Component) -> Self {
+        Compiled::Component(component)
+    }
+}
+
+/// A module with its imports already resolved, so that invocations only have
+/// to create a store and instantiate it.
+#[derive(Clone)]
+pub enum PreparedInstance {
+    /// WASI module linked against the WASI linker
+    Wasi(InstancePre<StoreState<WasiCtx>>),
+    /// Module importing at most the host API
+    Plain(InstancePre<StoreState<()>>),
+    /// Module importing at most the host API, whose instances are reused between invocations
+    Pooled(InstancePool),
+    /// Component linked against WASI preview 2
+    Component(component::InstancePre<StoreState<ComponentCtx>>),
+    /// Component of the `wasi:http/proxy` world, linked against wasi-http
+    HttpProxy(component::InstancePre<StoreState<ComponentCtx>>),
+}
+
+/// Data of the store an instance lives in.
+pub struct StoreState<T> {
+    /// Context of the host functions the instance imports
+    pub ctx: T,
+    pub limiter: InvocationLimiter,
+    /// Lines the instance logged through the host logging function
+    pub log: GuestLog,
+    /// Bucket of the key-value host functions, if the invocation has one
+    pub kv: Option<Arc<dyn KvHandle>>,
+}
+
+impl<T> StoreState<T> {
+    pub fn new(ctx: T) -> Self {
+        Self {
+            ctx,
+            limiter: InvocationLimiter::default(),
+            log: GuestLog::default(),
+            kv: None,
+        }
+    }
+}
+
+/// Output of an invocation and the resources it used.
+#[derive(Clone, Debug, Default)]
+pub struct InvokeOutput {
+    pub output: String,
+    /// Fuel consumed, one unit of fuel is about one instruction
+    pub fuel_consumed: u64,
+    /// Most bytes of linear memory held during the invocation
+    pub peak_memory: usize,
+    /// Exit code of a WASI function, 0 unless it called `proc_exit`
+    pub exit_code: Option<i32>,
+    /// Captured stderr of a WASI function, truncated to `max_stderr_bytes`
+    pub stderr: Option<String>,
+    /// Lines logged by the guest, kept when the invocation captures logs
+    pub logs: Vec<LogLine>,
+}
+
+fn default_pool_size() -> usize {
+    4
+}
+
+/// Reuse of instances between invocations of a stateless function.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReusePolicy {
+    /// Idle instances kept for the next invocations
+    #[serde(default = "default_pool_size")]
+    pub pool_size: usize,
+    /// Invocations after which an instance is dropped, unlimited if unset
+    #[serde(default)]
+    pub max_uses: Option<u32>,
+    /// Restore the linear memory and the mutable globals the module exports
+    /// to their state right after instantiation once an invocation is done.
+    /// Pages grown by the invocation stay allocated but are zeroed. Globals
+    /// the module doesn't export can't be reached, like the stack pointer
+    /// they have to be back to their value when an invocation returns.
+    #[serde(default)]
+    pub reset_memory: bool,
+}
+
+impl Default for ReusePolicy {
+    fn default() -> Self {
+        Self {
+            pool_size: default_pool_size(),
+            max_uses: None,
+            reset_memory: false,
+        }
+    }
+}
+
+/// Instances of a plain module kept warm between invocations.
+///
+/// An instance serves one invocation at a time, concurrent invocations
+/// instantiate extra ones. An instance that trapped is never reused.
+#[derive(Clone)]
+pub struct InstancePool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    instance_pre: InstancePre<StoreState<()>>,
+    policy: ReusePolicy,
+    idle: Mutex<Vec<WarmInstance>>,
+}
+
+/// An instance with the store it lives in.
+pub(crate) struct WarmInstance {
+    pub(crate) store: Store<StoreState<()>>,
+    pub(crate) instance: Instance,
+    pub(crate) uses: u32,
+    // linear memory right after instantiation, when it is reset between uses
+    pub(crate) initial_memory: Option<Vec<u8>>,
+    // exported mutable globals right after instantiation, restored with the memory
+    pub(crate) initial_globals: Vec<(Global, Val)>,
+}
+
+impl InstancePool {
+    pub fn new(instance_pre: InstancePre<StoreState<()>>, policy: ReusePolicy) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                instance_pre,
+                policy,
+                idle: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn policy(&self) -> &ReusePolicy {
+        &self.inner.policy
+    }
+
+    pub fn instance_pre(&self) -> &InstancePre<StoreState<()>> {
+        &self.inner.instance_pre
+    }
+
+    /// Number of idle instances waiting for an invocation.
+    pub fn idle(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    pub(crate) fn take(&self) -> Option<WarmInstance> {
+        self.inner.idle.lock().unwrap().pop()
+    }
+
+    /// Give an instance back after a successful invocation, it is dropped
+    /// once used up or when the pool is full.
+    pub(crate) fn give_back(&self, instance: WarmInstance) {
+        let policy = &self.inner.policy;
+        if policy.max_uses.is_some_and(|max| instance.uses >= max) {
+            return;
+        }
+
+        let mut idle = self.inner.idle.lock().unwrap();
+        if idle.len() < policy.pool_size {
+            idle.push(instance);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+
+/// Shape of a deployed wasm module, which decides how it is invoked.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FunctionKind {
+    /// WASI command module, run through its `_start` export with the
+    /// arguments passed as argv and the result read from stdout.
+    WasiCommand,
+    /// WASI reactor module, initialized through its `_initialize` export
+    /// and then called through a `(i32, i32) -> (i32, i32)` export named like
+    /// the function, or any other export with typed parameters given as JSON.
+    WasiReactor,
+    /// Module without WASI exporting a `(i32, i32) -> (i32, i32)` function
+    /// named like the function, called with the arguments written in memory.
+    Export,
+    /// Module without WASI exporting a function named like the function
+    /// of any other type, called with typed parameters given as JSON.
+    Typed,
+    /// Component linked against WASI preview 2, run through its
+    /// `wasi:cli/run` export like a command or called through one of its
+    /// exports with typed parameters given as JSON.
+    Component,
+    /// Component of the `wasi:http/proxy` world, called through its
+    /// `wasi:http/incoming-handler` export with the HTTP requests sent to
+    /// the function.
+    HttpProxy,
+}
+
+impl FunctionKind {
+    /// Returns true if the module needs the WASI capability.
+    pub fn wasi(&self) -> bool {
+        matches!(
+            self,
+            FunctionKind::WasiCommand
+                | FunctionKind::WasiReactor
+                | FunctionKind::Component
+                | FunctionKind::HttpProxy
+        )
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Result};
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::sync::Arc;
+use wasmtime::{Caller, Extern, Linker, Memory};
+
+use super::instance::StoreState;
+
+/// Import module of the key-value host functions.
+pub const KV_MODULE: &str = "wasm_engine";
+
+/// Returned by `kv_get` and `kv_delete` for a key that isn't set.
+pub const KV_MISSING: i32 = -1;
+/// Returned by `kv_set` when the bucket would exceed its quota.
+pub const KV_QUOTA_EXCEEDED: i32 = -2;
+
+const KV_FUNCS: [&str; 4] = ["kv_get", "kv_set", "kv_delete", "kv_list"];
+
+/// Bucket the key-value host functions of an invocation work on, provided by
+/// the function store. Its methods may block on I/O, they are called on the
+/// blocking threads of the runtime.
+pub trait KvHandle: Debug + Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Set `key` to `value`, returns false without writing if the bucket
+    /// would exceed its quota.
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<bool>;
+
+    /// Remove `key`, returns false if it wasn't set.
+    fn delete(&self, key: &[u8]) -> Result<bool>;
+
+    /// Keys starting with `prefix`, in order.
+    fn keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>>;
+}
+
+/// Link the key-value host functions, working on the bucket of the store:
+///
+/// - `kv_get(key_ptr, key_len, buf_ptr, buf_len) -> i32` returns the length
+///   of the value, written at `buf_ptr` only if it fits in `buf_len` bytes,
+///   or `KV_MISSING`
+/// - `kv_set(key_ptr, key_len, value_ptr, value_len) -> i32` returns 0, or
+///   `KV_QUOTA_EXCEEDED` without writing
+/// - `kv_delete(key_ptr, key_len) -> i32` returns 0 or `KV_MISSING`
+/// - `kv_list(prefix_ptr, prefix_len, buf_ptr, buf_len) -> i32` returns the
+///   length of the keys starting with the prefix, each followed by a NUL
+///   byte, written at `buf_ptr` only if they fit in `buf_len` bytes
+///
+/// Keys are non-empty and don't contain NUL bytes.
+pub(crate) fn add_to_linker<T: Send + 'static>(linker: &mut Linker<StoreState<T>>) -> Result<()> {
+    linker.func_wrap4_async(
+        KV_MODULE,
+        "kv_get",
+        |mut caller: Caller<'_, StoreState<T>>,
+         key_ptr: i32,
+         key_len: i32,
+         buf_ptr: i32,
+         buf_len: i32| {
+            Box::new(async move {
+                let (memory, bucket) = context(&mut caller)?;
+                let key = read_key(&memory, &caller, key_ptr, key_len)?;
+                match blocking(move || bucket.get(&key)).await? {
+                    Some(value) => write_fitting(&memory, &mut caller, &value, buf_ptr, buf_len),
+                    None => Ok(KV_MISSING),
+                }
+            })
+        },
+    )?;
+    linker.func_wrap4_async(
+        KV_MODULE,
+        "kv_set",
+        |mut caller: Caller<'_, StoreState<T>>,
+         key_ptr: i32,
+         key_len: i32,
+         value_ptr: i32,
+         value_len: i32| {
+            Box::new(async move {
+                let (memory, bucket) = context(&mut caller)?;
+                let key = read_key(&memory, &caller, key_ptr, key_len)?;
+                let value = read(&memory, &caller, value_ptr, value_len)?;
+                Ok(if blocking(move || bucket.set(&key, &value)).await? {
+                    0
+                } else {
+                    KV_QUOTA_EXCEEDED
+                })
+            })
+        },
+    )?;
+    linker.func_wrap2_async(
+        KV_MODULE,
+        "kv_delete",
+        |mut caller: Caller<'_, StoreState<T>>, key_ptr: i32, key_len: i32| {
+            Box::new(async move {
+                let (memory, bucket) = context(&mut caller)?;
+                let key = read_key(&memory, &caller, key_ptr, key_len)?;
+                Ok(if blocking(move || bucket.delete(&key)).await? {
+                    0
+                } else {
+                    KV_MISSING
+                })
+            })
+        },
+    )?;
+    linker.func_wrap4_async(
+        KV_MODULE,
+        "kv_list",
+        |mut caller: Caller<'_, StoreState<T>>,
+         prefix_ptr: i32,
+         prefix_len: i32,
+         buf_ptr: i32,
+         buf_len: i32| {
+            Box::new(async move {
+                let (memory, bucket) = context(&mut caller)?;
+                let prefix = read(&memory, &caller, prefix_ptr, prefix_len)?;
+                let mut keys = Vec::new();
+                for key in blocking(move || bucket.keys(&prefix)).await? {
+                    keys.extend_from_slice(&key);
+                    keys.push(0);
+                }
+                write_fitting(&memory, &mut caller, &keys, buf_ptr, buf_len)
+            })
+        },
+    )?;
+    Ok(())
+}
+
+/// Returns true if the import `module::name` is a key-value host function.
+pub(crate) fn provides(module: &str, name: &str) -> bool {
+    module == KV_MODULE && KV_FUNCS.contains(&name)
+}
+
+fn context<T>(caller: &mut Caller<'_, StoreState<T>>) -> Result<(Memory, Arc<dyn KvHandle>)> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| anyhow!("failed to find `memory` export"))?;
+    let bucket = caller
+        .data()
+        .kv
+        .clone()
+        .ok_or_else(|| anyhow!("the invocation has no key-value bucket"))?;
+    Ok((memory, bucket))
+}
+
+// Run a bucket operation off the async workers
+async fn blocking<R, F>(op: F) -> Result<R>
+where
+    R: Send + 'static,
+    F: FnOnce() -> Result<R> + Send + 'static,
+{
+    tokio::task::spawn_blocking(op).await?
+}
+
+fn read<T>(memory: &Memory, caller: &Caller<'_, T>, ptr: i32, len: i32) -> Result<Vec<u8>> {
+    let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+    let bytes = memory
+        .data(caller)
+        .get(ptr..ptr.saturating_add(len))
+        .ok_or_else(|| anyhow!("out of bounds memory access"))?;
+    Ok(bytes.to_vec())
+}
+
+fn read_key<T>(memory: &Memory, caller: &Caller<'_, T>, ptr: i32, len: i32) -> Result<Vec<u8>> {
+    let key = read(memory, caller, ptr, len)?;
+    if key.is_empty() || key.contains(&0) {
+        return Err(anyhow!(
+            "key-value keys must be non-empty and without NUL bytes"
+        ));
+    }
+    Ok(key)
+}
+
+// Write `bytes` if they fit in the buffer, returns their length either way
+fn write_fitting<T>(
+    memory: &Memory,
+    caller: &mut Caller<'_, T>,
+    bytes: &[u8],
+    ptr: i32,
+    len: i32,
+) -> Result<i32> {
+    if bytes.len() <= len as u32 as usize {
+        memory.write(caller, ptr as u32 as usize, bytes)?;
+    }
+    Ok(i32::try_from(bytes.len())?)
+}
@@ -0,0 +1,101 @@
+use crate::wrapper::config::ResourceLimits;
+use wasmtime::ResourceLimiter;
+
+/// Enforces the resource limits of a store and records the linear memory
+/// its instances use.
+#[derive(Debug, Default)]
+pub struct InvocationLimiter {
+    limits: ResourceLimits,
+    // bytes of all the linear memories of the store
+    memory: usize,
+    // most bytes of linear memory held since the invocation started
+    peak_memory: usize,
+    // memory and peak before the last allowed growth, restored if it fails
+    before_growth: (usize, usize),
+    // last growth refused since the invocation started
+    denied: Option<String>,
+}
+
+impl InvocationLimiter {
+    pub fn limits(&self) -> &ResourceLimits {
+        &self.limits
+    }
+
+    /// Most bytes of linear memory held since the invocation started.
+    pub fn peak_memory(&self) -> usize {
+        self.peak_memory
+    }
+
+    /// The last growth refused since the invocation started.
+    pub fn denied(&self) -> Option<&str> {
+        self.denied.as_deref()
+    }
+
+    /// Start a new invocation with `limits`, in a store that may already
+    /// hold instances.
+    pub(crate) fn start(&mut self, limits: ResourceLimits) {
+        self.limits = limits;
+        self.peak_memory = self.memory;
+        self.denied = None;
+    }
+}
+
+impl ResourceLimiter for InvocationLimiter {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        if let Some(limit) = self.limits.max_memory_bytes {
+            if desired > limit {
+                self.denied = Some(format!(
+                    "linear memory of {} bytes requested, limit is {} bytes",
+                    desired, limit
+                ));
+                return Ok(false);
+            }
+        }
+
+        self.before_growth = (self.memory, self.peak_memory);
+        self.memory = self.memory - current + desired;
+        self.peak_memory = self.peak_memory.max(self.memory);
+        Ok(true)
+    }
+
+    fn memory_grow_failed(&mut self, _error: anyhow::Error) -> anyhow::Result<()> {
+        // the growth was accounted for when it was allowed
+        (self.memory, self.peak_memory) = self.before_growth;
+        Ok(())
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: u32,
+        desired: u32,
+        _maximum: Option<u32>,
+    ) -> anyhow::Result<bool> {
+        if let Some(limit) = self.limits.max_table_elements {
+            if desired > limit {
+                self.denied = Some(format!(
+                    "table of {} elements requested, limit is {} elements",
+                    desired, limit
+                ));
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits
+            .max_instances
+            .unwrap_or(wasmtime::DEFAULT_INSTANCE_LIMIT)
+    }
+
+    fn memories(&self) -> usize {
+        self.limits
+            .max_memories
+            .unwrap_or(wasmtime::DEFAULT_MEMORY_LIMIT)
+    }
+}
@@ -1,3 +1,16 @@
+mod abi;
+pub mod component;
 pub mod config;
 pub mod environment;
+pub mod error;
+pub mod guest_log;
+pub mod input;
+pub mod instance;
+pub mod kind;
+pub mod kv;
+pub mod limiter;
+mod output;
+mod read_only;
+mod snapshot;
+mod typed;
 mod wasmtime_runtime;
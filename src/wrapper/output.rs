@@ -0,0 +1,102 @@
+use bytes::Bytes;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use wasmtime_wasi::preview2::{HostOutputStream, StdoutStream, StreamResult, Subscribe};
+
+/// In-memory output stream of a guest keeping at most `cap` bytes.
+///
+/// Writes past the cap still succeed, their bytes are dropped so that a
+/// chatty guest neither fails nor grows the host memory.
+#[derive(Clone, Debug)]
+pub(crate) struct CappedPipe {
+    cap: usize,
+    inner: Arc<Mutex<Captured>>,
+}
+
+#[derive(Debug, Default)]
+struct Captured {
+    bytes: Vec<u8>,
+    truncated: bool,
+}
+
+impl CappedPipe {
+    pub(crate) fn new(cap: usize) -> Self {
+        Self {
+            cap,
+            inner: Arc::new(Mutex::new(Captured::default())),
+        }
+    }
+
+    /// The captured output, marked when bytes were dropped.
+    pub(crate) fn contents(&self) -> String {
+        let captured = self.inner.lock().unwrap();
+        let mut contents = String::from_utf8_lossy(&captured.bytes).into_owned();
+        if captured.truncated {
+            contents.push_str("...");
+        }
+        contents
+    }
+
+    /// The captured bytes, or `None` if bytes were dropped.
+    pub(crate) fn complete(&self) -> Option<Vec<u8>> {
+        let captured = self.inner.lock().unwrap();
+        if captured.truncated {
+            return None;
+        }
+        Some(captured.bytes.clone())
+    }
+
+    fn push(&self, buf: &[u8]) {
+        let mut captured = self.inner.lock().unwrap();
+        let room = self.cap.saturating_sub(captured.bytes.len());
+        if buf.len() > room {
+            captured.truncated = true;
+        }
+        captured
+            .bytes
+            .extend_from_slice(&buf[..buf.len().min(room)]);
+    }
+}
+
+// Stream of WASI preview 1 through `WritePipe`
+impl Write for CappedPipe {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.push(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Stream of WASI preview 2
+impl HostOutputStream for CappedPipe {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        self.push(&bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        Ok(())
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        Ok(usize::MAX)
+    }
+}
+
+#[async_trait::async_trait]
+impl Subscribe for CappedPipe {
+    async fn ready(&mut self) {}
+}
+
+impl StdoutStream for CappedPipe {
+    fn stream(&self) -> Box<dyn HostOutputStream> {
+        Box::new(self.clone())
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
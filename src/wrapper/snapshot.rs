@@ -0,0 +1,373 @@
+use anyhow::{anyhow, bail, Result};
+use std::ops::Range;
+use wasm_encoder::{
+    ConstExpr, DataCountSection, DataSection, ExportKind, ExportSection, GlobalSection, GlobalType,
+    MemorySection, MemoryType, Module, RawSection, SectionId, ValType,
+};
+use wasmparser::{DataKind, Encoding, ExternalKind, Operator, Parser, Payload, TypeRef};
+use wasmtime::{AsContextMut, Instance, Val};
+
+/// Export run once to initialize a module before its state is snapshotted.
+pub const INITIALIZE_EXPORT: &str = "wizer.initialize";
+
+// Exports added to reach the defined globals and memories of the module
+const GLOBAL_EXPORT_PREFIX: &str = "__wasmengine_snapshot_global_";
+const MEMORY_EXPORT_PREFIX: &str = "__wasmengine_snapshot_memory_";
+
+// Runs of zero bytes shorter than this stay inside a data segment instead of
+// splitting it, to keep the number of segments low
+const MIN_SEGMENT_GAP: usize = 64;
+
+/// The sections of a core module and the parts of them a snapshot rewrites.
+pub(crate) struct ModuleLayout<'a> {
+    wasm: &'a [u8],
+    sections: Vec<(u8, Range<usize>)>,
+    imported_globals: u32,
+    globals: Vec<DefinedGlobal>,
+    memories: Vec<wasmparser::MemoryType>,
+    exports: Vec<(&'a str, ExternalKind, u32)>,
+}
+
+struct DefinedGlobal {
+    // encoded global entry in the original module
+    raw: Range<usize>,
+    // type of the mutable globals, whose value is snapshotted
+    mutable: Option<ValType>,
+}
+
+/// State of the defined globals and memories of an initialized instance.
+pub(crate) struct Snapshot {
+    globals: Vec<Option<ConstExpr>>,
+    memories: Vec<MemorySnapshot>,
+}
+
+struct MemorySnapshot {
+    pages: u64,
+    segments: Vec<(u32, Vec<u8>)>,
+}
+
+impl<'a> ModuleLayout<'a> {
+    /// Parse a core module, rejecting the features a snapshot can't carry.
+    pub(crate) fn parse(wasm: &'a [u8]) -> Result<Self> {
+        let mut layout = ModuleLayout {
+            wasm,
+            sections: Vec::new(),
+            imported_globals: 0,
+            globals: Vec::new(),
+            memories: Vec::new(),
+            exports: Vec::new(),
+        };
+
+        for payload in Parser::new(0).parse_all(wasm) {
+            let payload = payload?;
+            match &payload {
+                Payload::Version {
+                    encoding: Encoding::Component,
+                    ..
+                } => bail!("components can't be pre-initialized"),
+                Payload::ImportSection(reader) => {
+                    for import in reader.clone() {
+                        match import?.ty {
+                            TypeRef::Global(_) => layout.imported_globals += 1,
+                            // the host keeps the contents of an imported memory
+                            TypeRef::Memory(_) => {
+                                bail!("modules importing a memory can't be pre-initialized")
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Payload::MemorySection(reader) => {
+                    for memory in reader.clone() {
+                        let memory = memory?;
+                        if memory.memory64 || memory.shared {
+                            bail!("64-bit and shared memories can't be pre-initialized");
+                        }
+                        layout.memories.push(memory);
+                    }
+                }
+                Payload::GlobalSection(reader) => {
+                    let end = reader.range().end;
+                    let globals = reader
+                        .clone()
+                        .into_iter_with_offsets()
+                        .collect::<Result<Vec<_>, _>>()?;
+                    for (i, (offset, global)) in globals.iter().enumerate() {
+                        let next = globals.get(i + 1).map_or(end, |(next, _)| *next);
+                        let mutable = match global.ty.mutable {
+                            true => Some(value_type(global.ty.content_type)?),
+                            false => None,
+                        };
+                        layout.globals.push(DefinedGlobal {
+                            raw: *offset..next,
+                            mutable,
+                        });
+                    }
+                }
+                Payload::ExportSection(reader) => {
+                    for export in reader.clone() {
+                        let export = export?;
+                        layout
+                            .exports
+                            .push((export.name, export.kind, export.index));
+                    }
+                }
+                Payload::DataSection(reader) => {
+                    for data in reader.clone() {
+                        if let DataKind::Passive = data?.kind {
+                            bail!("passive data segments can't be pre-initialized");
+                        }
+                    }
+                }
+                Payload::CodeSectionEntry(body) => {
+                    // the active data segments are replaced by the snapshot
+                    let mut operators = body.get_operators_reader()?;
+                    while !operators.eof() {
+                        if let Operator::MemoryInit { .. } | Operator::DataDrop { .. } =
+                            operators.read()?
+                        {
+                            bail!(
+                                "modules using memory.init or data.drop can't be pre-initialized"
+                            );
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            if let Some(section) = payload.as_section() {
+                layout.sections.push(section);
+            }
+        }
+
+        Ok(layout)
+    }
+
+    pub(crate) fn has_export(&self, name: &str) -> bool {
+        self.exports.iter().any(|(export, _, _)| *export == name)
+    }
+
+    /// Encode the module with its mutable globals and memories exported, so
+    /// that their state can be read after initialization.
+    pub(crate) fn instrument(&self) -> Vec<u8> {
+        let mut module = Module::new();
+        for (id, range) in &self.sections {
+            if *id != SectionId::Export as u8 {
+                module.section(&RawSection {
+                    id: *id,
+                    data: &self.wasm[range.clone()],
+                });
+                continue;
+            }
+
+            let mut exports = self.export_section(|_| true);
+            for (i, global) in self.globals.iter().enumerate() {
+                if global.mutable.is_some() {
+                    exports.export(
+                        &format!("{}{}", GLOBAL_EXPORT_PREFIX, i),
+                        ExportKind::Global,
+                        self.imported_globals + i as u32,
+                    );
+                }
+            }
+            for i in 0..self.memories.len() {
+                exports.export(
+                    &format!("{}{}", MEMORY_EXPORT_PREFIX, i),
+                    ExportKind::Memory,
+                    i as u32,
+                );
+            }
+            module.section(&exports);
+        }
+
+        module.finish()
+    }
+
+    /// Read the state of an instance of the instrumented module.
+    pub(crate) fn snapshot(
+        &self,
+        mut store: impl AsContextMut,
+        instance: &Instance,
+    ) -> Result<Snapshot> {
+        let mut globals = Vec::with_capacity(self.globals.len());
+        for (i, global) in self.globals.iter().enumerate() {
+            if global.mutable.is_none() {
+                globals.push(None);
+                continue;
+            }
+            let name = format!("{}{}", GLOBAL_EXPORT_PREFIX, i);
+            let value = instance
+                .get_global(&mut store, &name)
+                .ok_or_else(|| anyhow!("missing snapshot export {}", name))?
+                .get(&mut store);
+            globals.push(Some(const_expr(&value)?));
+        }
+
+        let mut memories = Vec::with_capacity(self.memories.len());
+        for i in 0..self.memories.len() {
+            let name = format!("{}{}", MEMORY_EXPORT_PREFIX, i);
+            let memory = instance
+                .get_memory(&mut store, &name)
+                .ok_or_else(|| anyhow!("missing snapshot export {}", name))?;
+            memories.push(MemorySnapshot {
+                pages: memory.size(&store),
+                segments: segments(memory.data(&store)),
+            });
+        }
+
+        Ok(Snapshot { globals, memories })
+    }
+
+    /// Encode the module with the snapshot as its initial state. The start
+    /// function and the initialize export are dropped, they already ran.
+    pub(crate) fn rewrite(&self, snapshot: &Snapshot) -> Vec<u8> {
+        let mut data = DataSection::new();
+        for (i, memory) in snapshot.memories.iter().enumerate() {
+            for (offset, bytes) in &memory.segments {
+                data.active(
+                    i as u32,
+                    &ConstExpr::i32_const(*offset as i32),
+                    bytes.iter().copied(),
+                );
+            }
+        }
+
+        let mut module = Module::new();
+        let mut data_written = false;
+        for (id, range) in &self.sections {
+            let raw = &self.wasm[range.clone()];
+            match *id {
+                id if id == SectionId::Global as u8 => {
+                    let mut globals = GlobalSection::new();
+                    for (global, value) in self.globals.iter().zip(&snapshot.globals) {
+                        match (global.mutable, value) {
+                            (Some(val_type), Some(value)) => globals.global(
+                                GlobalType {
+                                    val_type,
+                                    mutable: true,
+                                },
+                                value,
+                            ),
+                            _ => globals.raw(&self.wasm[global.raw.clone()]),
+                        };
+                    }
+                    module.section(&globals);
+                }
+                id if id == SectionId::Memory as u8 => {
+                    let mut memories = MemorySection::new();
+                    for (memory, state) in self.memories.iter().zip(&snapshot.memories) {
+                        memories.memory(MemoryType {
+                            minimum: state.pages,
+                            maximum: memory.maximum,
+                            memory64: false,
+                            shared: false,
+                        });
+                    }
+                    module.section(&memories);
+                }
+                id if id == SectionId::Export as u8 => {
+                    module.section(&self.export_section(|name| name != INITIALIZE_EXPORT));
+                }
+                id if id == SectionId::Start as u8 => {}
+                id if id == SectionId::DataCount as u8 => {
+                    module.section(&DataCountSection { count: data.len() });
+                }
+                id if id == SectionId::Data as u8 => {
+                    module.section(&data);
+                    data_written = true;
+                }
+                id => {
+                    // custom sections may follow the data section, keep the
+                    // data section ahead of them
+                    if id == SectionId::Custom as u8 && !data_written && self.after_data(range) {
+                        module.section(&data);
+                        data_written = true;
+                    }
+                    module.section(&RawSection { id, data: raw });
+                }
+            }
+        }
+
+        if !data_written && !data.is_empty() {
+            module.section(&data);
+        }
+
+        module.finish()
+    }
+
+    fn export_section(&self, keep: impl Fn(&str) -> bool) -> ExportSection {
+        let mut exports = ExportSection::new();
+        for (name, kind, index) in &self.exports {
+            if keep(name) {
+                exports.export(name, export_kind(*kind), *index);
+            }
+        }
+        exports
+    }
+
+    // Whether a section comes after every non custom section of the module
+    fn after_data(&self, range: &Range<usize>) -> bool {
+        self.sections
+            .iter()
+            .filter(|(id, _)| *id != SectionId::Custom as u8)
+            .all(|(_, other)| other.end <= range.start)
+    }
+}
+
+fn value_type(ty: wasmparser::ValType) -> Result<ValType> {
+    Ok(match ty {
+        wasmparser::ValType::I32 => ValType::I32,
+        wasmparser::ValType::I64 => ValType::I64,
+        wasmparser::ValType::F32 => ValType::F32,
+        wasmparser::ValType::F64 => ValType::F64,
+        wasmparser::ValType::V128 => ValType::V128,
+        wasmparser::ValType::Ref(_) => {
+            bail!("mutable reference globals can't be pre-initialized")
+        }
+    })
+}
+
+fn export_kind(kind: ExternalKind) -> ExportKind {
+    match kind {
+        ExternalKind::Func => ExportKind::Func,
+        ExternalKind::Table => ExportKind::Table,
+        ExternalKind::Memory => ExportKind::Memory,
+        ExternalKind::Global => ExportKind::Global,
+        ExternalKind::Tag => ExportKind::Tag,
+    }
+}
+
+fn const_expr(value: &Val) -> Result<ConstExpr> {
+    Ok(match value {
+        Val::I32(v) => ConstExpr::i32_const(*v),
+        Val::I64(v) => ConstExpr::i64_const(*v),
+        Val::F32(bits) => ConstExpr::f32_const(f32::from_bits(*bits)),
+        Val::F64(bits) => ConstExpr::f64_const(f64::from_bits(*bits)),
+        Val::V128(v) => ConstExpr::v128_const(v.as_u128() as i128),
+        _ => bail!("unsupported global value {:?}", value),
+    })
+}
+
+// Split the non zero bytes of a memory into data segments
+fn segments(memory: &[u8]) -> Vec<(u32, Vec<u8>)> {
+    let mut segments = Vec::new();
+    let mut i = 0;
+    while i < memory.len() {
+        if memory[i] == 0 {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        let mut end = i + 1;
+        i = end;
+        while i < memory.len() && i - end < MIN_SEGMENT_GAP {
+            if memory[i] != 0 {
+                end = i + 1;
+            }
+            i += 1;
+        }
+        segments.push((start as u32, memory[start..end].to_vec()));
+    }
+    segments
+}
@@ -0,0 +1,98 @@
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Number, Value};
+use wasmtime::{FuncType, Val, ValType, V128};
+
+/// Convert JSON parameters to the parameter types of `ty`.
+///
+/// Integers are JSON numbers, i64 may also be given as a decimal string to
+/// keep its precision. Floats are JSON numbers or `"NaN"`, `"inf"` and
+/// `"-inf"`. A v128 is a `"0x"` prefixed hex string and references can only
+/// be `null`.
+pub(crate) fn params(ty: &FuncType, params: &[Value]) -> Result<Vec<Val>> {
+    if ty.params().len() != params.len() {
+        bail!(
+            "expect {} parameters, get {}",
+            ty.params().len(),
+            params.len()
+        );
+    }
+
+    ty.params()
+        .zip(params)
+        .enumerate()
+        .map(|(i, (ty, value))| {
+            param(&ty, value).ok_or_else(|| anyhow!("parameter {} is not a valid {}", i, ty))
+        })
+        .collect()
+}
+
+/// Convert results to JSON, the same way parameters are given.
+pub(crate) fn results(results: &[Val]) -> Result<Vec<Value>> {
+    results.iter().map(result).collect()
+}
+
+fn param(ty: &ValType, value: &Value) -> Option<Val> {
+    Some(match ty {
+        ValType::I32 => {
+            let n = value.as_i64()?;
+            // unsigned values are reinterpreted
+            if n < i32::MIN as i64 || n > u32::MAX as i64 {
+                return None;
+            }
+            Val::I32(n as i32)
+        }
+        ValType::I64 => match value {
+            Value::String(s) => Val::I64(s.parse::<i64>().ok()?),
+            _ => Val::I64(
+                value
+                    .as_i64()
+                    .or_else(|| value.as_u64().map(|n| n as i64))?,
+            ),
+        },
+        ValType::F32 => Val::F32((float(value)? as f32).to_bits()),
+        ValType::F64 => Val::F64(float(value)?.to_bits()),
+        ValType::V128 => {
+            let hex = value.as_str()?.strip_prefix("0x")?;
+            Val::V128(V128::from(u128::from_str_radix(hex, 16).ok()?))
+        }
+        ValType::FuncRef if value.is_null() => Val::FuncRef(None),
+        ValType::ExternRef if value.is_null() => Val::ExternRef(None),
+        _ => return None,
+    })
+}
+
+// JSON numbers, or `"NaN"`, `"inf"` and `"-inf"` which JSON can't encode
+pub(super) fn float(value: &Value) -> Option<f64> {
+    match value {
+        Value::String(s) => match s.as_str() {
+            "NaN" => Some(f64::NAN),
+            "inf" => Some(f64::INFINITY),
+            "-inf" => Some(f64::NEG_INFINITY),
+            _ => None,
+        },
+        _ => value.as_f64(),
+    }
+}
+
+fn result(val: &Val) -> Result<Value> {
+    Ok(match val {
+        Val::I32(n) => Value::from(*n),
+        Val::I64(n) => Value::from(*n),
+        Val::F32(bits) => float_value(f32::from_bits(*bits) as f64),
+        Val::F64(bits) => float_value(f64::from_bits(*bits)),
+        Val::V128(v) => Value::from(format!("0x{:032x}", v.as_u128())),
+        Val::FuncRef(None) | Val::ExternRef(None) => Value::Null,
+        Val::FuncRef(Some(_)) | Val::ExternRef(Some(_)) => {
+            bail!("non-null reference results can't be returned")
+        }
+    })
+}
+
+pub(super) fn float_value(f: f64) -> Value {
+    match Number::from_f64(f) {
+        Some(n) => Value::Number(n),
+        None if f.is_nan() => Value::from("NaN"),
+        None if f > 0.0 => Value::from("inf"),
+        None => Value::from("-inf"),
+    }
+}
@@ -1,22 +1,64 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use tracing::info;
-use wasi_common::pipe::WritePipe;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::time::Duration;
+use tracing::{debug, warn};
+use wasi_common::pipe::{ReadPipe, WritePipe};
 use wasi_common::sync::ambient_authority;
 use wasi_common::sync::Dir;
 use wasi_common::tokio::WasiCtxBuilder;
 use wasi_common::WasiCtx;
+use wasmtime::component::{self, Component};
 use wasmtime::*;
+use wasmtime_wasi::preview2::{self, DirPerms, FilePerms};
+use wasmtime_wasi_http::body::{HyperIncomingBody, HyperOutgoingBody};
+use wasmtime_wasi_http::proxy::Proxy;
+use wasmtime_wasi_http::WasiHttpView;
 
-use super::config::EnvConfig;
+use super::abi::{GuestAbi, ALLOC_EXPORT, DEALLOC_EXPORT, HEAP_BASE_EXPORT, REALLOC_EXPORT};
+use super::component::{self as wit, ComponentCtx, RUN_FUNC, RUN_INTERFACE};
+use super::config::{EnvConfig, FunctionConfig};
+use super::environment::UNIT_OF_COMPUTE_IN_INSTRUCTIONS;
+use super::error::InvokeError;
+use super::guest_log;
+use super::instance::{
+    Compiled, InstancePool, InvokeOutput, PreparedInstance, ReusePolicy, StoreState, WarmInstance,
+};
+use super::kind::FunctionKind;
+use super::kv;
+use super::output::CappedPipe;
+use super::read_only::ReadOnlyDir;
+use super::snapshot::{ModuleLayout, INITIALIZE_EXPORT};
+use super::typed;
 
-const WASM_PAGE_SIZE: u32 = 0x10000;
+pub(crate) const WASM_PAGE_SIZE: u32 = 0x10000;
+
+// Extension of the precompiled module artifacts in the cache directory
+const PRECOMPILED_EXTENSION: &str = "cwasm";
+
+// Period of the engine epoch, the granularity of invocation deadlines
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+// Export a WASI reactor module runs once instantiated
+const REACTOR_INITIALIZE_EXPORT: &str = "_initialize";
+
+// Epoch deadline of stores without a deadline, far enough to never be
+// reached while leaving room for the current epoch to be added to it
+const NO_DEADLINE_TICKS: u64 = u64::MAX / 2;
 
 #[derive(Clone)]
 pub struct WasmtimeRuntime {
     pub(crate) engine: Engine,
-    pub(crate) linker: Linker<WasiCtx>,
+    pub(crate) linker: Linker<StoreState<WasiCtx>>,
+    // Host API linker of the modules without WASI
+    pub(crate) plain_linker: Linker<StoreState<()>>,
+    // WASI preview 2 linker of the components
+    pub(crate) component_linker: component::Linker<StoreState<ComponentCtx>>,
     pub(crate) config: EnvConfig,
+    // Hash of the wasmtime version and the compilation settings of the engine
+    pub(crate) engine_hash: String,
 }
 
 impl WasmtimeRuntime {
@@ -25,81 +67,737 @@ impl WasmtimeRuntime {
         wasmtime_config
             .async_support(true)
             .debug_info(false)
+            // Deadlines are checked against the epoch advanced by a ticker thread
+            .epoch_interruption(true)
             // The behaviour of fuel running out is defined on the Store
             .consume_fuel(true)
             .wasm_reference_types(true)
             .wasm_bulk_memory(true)
             .wasm_multi_value(true)
             .wasm_multi_memory(true)
+            .wasm_component_model(true)
             // Disable profiler
             .profiler(ProfilingStrategy::None)
             .cranelift_opt_level(OptLevel::SpeedAndSize)
-            // Allocate resources on demand because we can't predict how many instances will exist
-            .allocation_strategy(InstanceAllocationStrategy::OnDemand)
-            // Memories are always static (can't be bigger than max_memory)
-            .static_memory_maximum_size(config.max_memory() as u64)
             // Set memory guards to 4 Mb
             .static_memory_guard_size(0x400000)
             .dynamic_memory_guard_size(0x400000);
+        match config.pooling() {
+            Some(pooling) => {
+                let mut pooling_config = PoolingAllocationConfig::default();
+                pooling_config
+                    .total_component_instances(pooling.total_instances)
+                    .total_core_instances(pooling.total_core_instances)
+                    // Every async instance runs on its own fiber stack
+                    .total_stacks(pooling.total_core_instances)
+                    .total_memories(pooling.total_memories)
+                    .total_tables(pooling.total_tables)
+                    .memory_pages(pooling.memory_pages)
+                    .table_elements(pooling.table_elements);
+                wasmtime_config
+                    .allocation_strategy(InstanceAllocationStrategy::Pooling(pooling_config))
+                    // Each memory slot reserves the static memory size, keep it
+                    // to the pooled memory size instead of max_memory
+                    .static_memory_maximum_size(pooling.memory_pages * WASM_PAGE_SIZE as u64);
+            }
+            None => {
+                wasmtime_config
+                    // Allocate resources on demand because we can't predict how many instances will exist
+                    .allocation_strategy(InstanceAllocationStrategy::OnDemand)
+                    // Memories are always static (can't be bigger than max_memory)
+                    .static_memory_maximum_size(config.max_memory() as u64);
+            }
+        }
         let engine = Engine::new(&wasmtime_config)?;
         let mut linker = Linker::new(&engine);
         // Allow plugins to shadow host functions
         linker.allow_shadowing(true);
 
-        wasmtime_wasi::tokio::add_to_linker(&mut linker, |ctx| ctx)?;
+        wasmtime_wasi::tokio::add_to_linker(&mut linker, |state: &mut StoreState<WasiCtx>| {
+            &mut state.ctx
+        })?;
+        guest_log::add_to_linker(&mut linker)?;
+        kv::add_to_linker(&mut linker)?;
+
+        let mut plain_linker = Linker::new(&engine);
+        guest_log::add_to_linker(&mut plain_linker)?;
+        kv::add_to_linker(&mut plain_linker)?;
+
+        let mut component_linker = component::Linker::new(&engine);
+        preview2::command::add_to_linker(&mut component_linker)?;
+        wasmtime_wasi_http::bindings::http::types::add_to_linker(&mut component_linker, |s| s)?;
+        wasmtime_wasi_http::bindings::http::outgoing_handler::add_to_linker(
+            &mut component_linker,
+            |s| s,
+        )?;
+
+        // The ticker stops with the last clone of the engine
+        let weak_engine = engine.weak();
+        std::thread::Builder::new()
+            .name("wasmengine-epoch".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(EPOCH_TICK);
+                match weak_engine.upgrade() {
+                    Some(engine) => engine.increment_epoch(),
+                    None => break,
+                }
+            })?;
+
+        let mut hasher = Sha256Hasher(Sha256::new());
+        engine.precompile_compatibility_hash().hash(&mut hasher);
+        let engine_hash = hex(&hasher.0.finalize()[..8]);
 
         Ok(Self {
             engine,
             linker,
+            plain_linker,
+            component_linker,
             config: config.to_owned(),
+            engine_hash,
+        })
+    }
+
+    /// Compile the wasm module file at `path`.
+    ///
+    /// With a cache directory configured, the compiled artifact is kept there
+    /// keyed by the module digest and the engine hash, so later calls only
+    /// map it into memory. Artifacts of other engine settings or wasmtime
+    /// versions are never used and get replaced.
+    pub fn compile_cached(&self, path: &str) -> Result<Module> {
+        let wasm = std::fs::read(path).with_context(|| format!("failed to read {}", path))?;
+        Ok(self.compile_artifact(&wasm)?.0)
+    }
+
+    /// Compile the wasm file at `path` like `compile_cached`, as a component
+    /// if it is a component binary and as a core module otherwise. Components
+    /// of the `wasi:http/proxy` world are told apart as `HttpProxy`.
+    pub fn compile(&self, path: &str) -> Result<Compiled> {
+        Ok(self.compile_sized(path)?.0)
+    }
+
+    /// Compile the wasm file at `path` like `compile`, along with the size in
+    /// bytes of its compiled artifact: the precompiled file when a cache
+    /// directory is configured, the wasm binary otherwise.
+    pub fn compile_sized(&self, path: &str) -> Result<(Compiled, usize)> {
+        let wasm = std::fs::read(path).with_context(|| format!("failed to read {}", path))?;
+        Ok(if wasmparser::Parser::is_component(&wasm) {
+            let (component, size) = self.compile_artifact::<Component>(&wasm)?;
+            if wit::is_http_proxy(&wasm)? {
+                (Compiled::HttpProxy(component), size)
+            } else {
+                (Compiled::Component(component), size)
+            }
+        } else {
+            let (module, size) = self.compile_artifact::<Module>(&wasm)?;
+            (Compiled::Module(module), size)
+        })
+    }
+
+    // the artifact with its size, without serializing it only to measure it
+    fn compile_artifact<A: Artifact>(&self, wasm: &[u8]) -> Result<(A, usize)> {
+        let cache_dir = match self.config.cache_dir() {
+            Some(cache_dir) => cache_dir,
+            None => return Ok((A::new(&self.engine, wasm)?, wasm.len())),
+        };
+        let digest = hex(&Sha256::digest(wasm));
+        let precompiled = cache_dir.join(format!(
+            "{}-{}.{}",
+            digest, self.engine_hash, PRECOMPILED_EXTENSION
+        ));
+
+        if precompiled.exists() {
+            // SAFETY: the cache directory is owned by the engine, outside of
+            // the images and the directories preopened for guests, so the
+            // artifact was written by `write_precompiled` from `serialize`
+            // with an engine of the same compatibility hash, named after it.
+            match unsafe { A::deserialize_file(&self.engine, &precompiled) } {
+                Ok(artifact) => {
+                    debug!("loaded precompiled module {}", precompiled.display());
+                    let size =
+                        std::fs::metadata(&precompiled).map_or(wasm.len(), |m| m.len() as usize);
+                    return Ok((artifact, size));
+                }
+                Err(err) => warn!(
+                    "failed to load precompiled module {}, recompiling: {:?}",
+                    precompiled.display(),
+                    err
+                ),
+            }
+        }
+
+        let artifact = A::new(&self.engine, wasm)?;
+        let mut size = wasm.len();
+        let written = artifact.serialize().and_then(|bytes| {
+            size = bytes.len();
+            write_precompiled(cache_dir, &digest, &precompiled, &bytes)
+        });
+        if let Err(err) = written {
+            warn!(
+                "failed to cache precompiled module {}: {:?}",
+                precompiled.display(),
+                err
+            );
+        }
+
+        Ok((artifact, size))
+    }
+
+    /// Resolve the imports of a module once, `wasi` selects the WASI linker.
+    /// Plain modules with a `reuse` policy keep their instances warm.
+    pub fn prepare(
+        &self,
+        module: &Module,
+        wasi: bool,
+        reuse: Option<&ReusePolicy>,
+    ) -> Result<PreparedInstance> {
+        if wasi {
+            if reuse.is_some() {
+                return Err(anyhow!("instances of WASI command modules can't be reused"));
+            }
+            return Ok(PreparedInstance::Wasi(self.linker.instantiate_pre(module)?));
+        }
+
+        if let Some(policy) = reuse {
+            // buffers nothing frees would pile up in memory kept between uses
+            if !policy.reset_memory && GuestAbi::leaks(module) {
+                return Err(anyhow!(
+                    "instances exporting `{}` or `{}` without `{}` can only be reused with reset_memory",
+                    ALLOC_EXPORT,
+                    REALLOC_EXPORT,
+                    DEALLOC_EXPORT
+                ));
+            }
+        }
+        let instance_pre = self.plain_linker.instantiate_pre(module)?;
+        Ok(match reuse {
+            Some(policy) => {
+                PreparedInstance::Pooled(InstancePool::new(instance_pre, policy.clone()))
+            }
+            None => PreparedInstance::Plain(instance_pre),
         })
     }
 
+    /// Resolve the imports of a component against WASI preview 2 once.
+    pub fn prepare_component(&self, component: &Component) -> Result<PreparedInstance> {
+        Ok(PreparedInstance::Component(
+            self.component_linker.instantiate_pre(component)?,
+        ))
+    }
+
+    /// Resolve the imports of a module or a component, see `prepare`.
+    pub fn prepare_compiled(
+        &self,
+        compiled: &Compiled,
+        wasi: bool,
+        reuse: Option<&ReusePolicy>,
+    ) -> Result<PreparedInstance> {
+        match compiled {
+            Compiled::Module(module) => self.prepare(module, wasi, reuse),
+            Compiled::Component(_) | Compiled::HttpProxy(_) if reuse.is_some() => {
+                Err(anyhow!("instances of components can't be reused"))
+            }
+            Compiled::Component(component) => self.prepare_component(component),
+            Compiled::HttpProxy(component) => Ok(PreparedInstance::HttpProxy(
+                self.component_linker.instantiate_pre(component)?,
+            )),
+        }
+    }
+
+    /// Run the `wizer.initialize` export of a module once and return a copy of
+    /// the module whose initial memories and globals are the resulting state.
+    ///
+    /// WASI modules are initialized without arguments, environment or
+    /// preopened directories, so the snapshot can't depend on a request.
+    /// The initialization runs with the compute budget, deadline and resource
+    /// limits of an invocation of the function and fails when one is hit.
+    pub async fn pre_initialize(
+        &self,
+        wasm: &[u8],
+        wasi: bool,
+        func_config: &FunctionConfig,
+    ) -> Result<Vec<u8>> {
+        let layout = ModuleLayout::parse(wasm)?;
+        if !layout.has_export(INITIALIZE_EXPORT) {
+            return Err(anyhow!("module doesn't export `{}`", INITIALIZE_EXPORT));
+        }
+        let module = Module::new(&self.engine, layout.instrument())?;
+
+        let snapshot = if wasi {
+            let mut store =
+                Store::new(&self.engine, StoreState::new(WasiCtxBuilder::new().build()));
+            let limits = self.set_limits(&mut store, func_config)?;
+            let instance = self
+                .linker
+                .instantiate_async(&mut store, &module)
+                .await
+                .map_err(InvokeError::from_instantiate)
+                .map_err(|err| limits.error(&store, err))?;
+            initialize(&mut store, &instance)
+                .await
+                .map_err(|err| limits.error(&store, err))?;
+            layout.snapshot(&mut store, &instance)?
+        } else {
+            let mut store = Store::new(&self.engine, StoreState::new(()));
+            let limits = self.set_limits(&mut store, func_config)?;
+            let instance = self
+                .plain_linker
+                .instantiate_async(&mut store, &module)
+                .await
+                .map_err(InvokeError::from_instantiate)
+                .map_err(|err| limits.error(&store, err))?;
+            initialize(&mut store, &instance)
+                .await
+                .map_err(|err| limits.error(&store, err))?;
+            layout.snapshot(&mut store, &instance)?
+        };
+
+        Ok(layout.rewrite(&snapshot))
+    }
+
     pub async fn spawn_wasi(
         &self,
         module: Module,
         data: HashMap<String, String>,
+        func_config: &FunctionConfig,
     ) -> Result<String> {
+        let instance_pre = self.linker.instantiate_pre(&module)?;
+        let invocation = self
+            .spawn_wasi_pre(&instance_pre, data, func_config)
+            .await?;
+        Ok(invocation.output)
+    }
+
+    /// Run a WASI command module prepared by `prepare`, the arguments are
+    /// handed to it as set by the `wasi_input` of the function.
+    pub async fn spawn_wasi_pre(
+        &self,
+        instance_pre: &InstancePre<StoreState<WasiCtx>>,
+        data: HashMap<String, String>,
+        func_config: &FunctionConfig,
+    ) -> Result<InvokeOutput> {
+        let (mut wasi, stderr) = self.wasi_builder(func_config)?;
+        let stdout = self.wasi_input(&mut wasi, &data, func_config)?;
+        let wasictx = wasi.build();
+        self.preopen_read_only(&wasictx, func_config)?;
+
+        let mut store = Store::new(&self.engine, StoreState::new(wasictx));
+
+        let limits = self.set_limits(&mut store, func_config)?;
+
+        let instance = instance_pre
+            .instantiate_async(&mut store)
+            .await
+            .map_err(InvokeError::from_instantiate)
+            .map_err(|err| limits.error(&store, err))?;
+        let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+        // `proc_exit` unwinds the guest, any other failure is an error
+        let exit_code = match start.call_async(&mut store, ()).await {
+            Ok(()) => 0,
+            Err(err) => match err.downcast_ref::<wasi_common::I32Exit>() {
+                Some(exit) => exit.0,
+                None => return Err(limits.error(&store, err)),
+            },
+        };
+        let fuel_consumed = limits.fuel_consumed(&store)?;
+        let peak_memory = store.data().limiter.peak_memory();
+        let logs = store.data_mut().log.take();
+
+        drop(store);
+
+        Ok(InvokeOutput {
+            output: self.stdout_output(&stdout)?,
+            fuel_consumed,
+            peak_memory,
+            exit_code: Some(exit_code),
+            stderr: Some(stderr.contents()),
+            logs,
+        })
+    }
+
+    /// Call the `function` export of a WASI reactor module prepared by
+    /// `prepare` with the spawn ABI, once its `_initialize` export ran.
+    pub async fn spawn_reactor_pre(
+        &self,
+        instance_pre: &InstancePre<StoreState<WasiCtx>>,
+        function: &str,
+        args: HashMap<String, String>,
+        func_config: &FunctionConfig,
+    ) -> Result<InvokeOutput> {
+        self.call_reactor_pre(instance_pre, Call::Spawn { function, args }, func_config)
+            .await
+    }
+
+    /// Call the `export` of a WASI reactor module prepared by `prepare` with
+    /// typed parameters, once its `_initialize` export ran.
+    pub async fn call_typed_reactor_pre(
+        &self,
+        instance_pre: &InstancePre<StoreState<WasiCtx>>,
+        export: &str,
+        params: &[serde_json::Value],
+        func_config: &FunctionConfig,
+    ) -> Result<InvokeOutput> {
+        self.call_reactor_pre(instance_pre, Call::Typed { export, params }, func_config)
+            .await
+    }
+
+    async fn call_reactor_pre(
+        &self,
+        instance_pre: &InstancePre<StoreState<WasiCtx>>,
+        call: Call<'_>,
+        func_config: &FunctionConfig,
+    ) -> Result<InvokeOutput> {
+        let (mut wasi, stderr) = self.wasi_builder(func_config)?;
+        let no_args = HashMap::new();
+        let args = match &call {
+            Call::Spawn { args, .. } => args,
+            Call::Typed { .. } => &no_args,
+        };
+        let stdout = self.wasi_input(&mut wasi, args, func_config)?;
+        let wasictx = wasi.build();
+        self.preopen_read_only(&wasictx, func_config)?;
+        let mut store = Store::new(&self.engine, StoreState::new(wasictx));
+
+        let limits = self.set_limits(&mut store, func_config)?;
+
+        let instance = instance_pre
+            .instantiate_async(&mut store)
+            .await
+            .map_err(InvokeError::from_instantiate)
+            .map_err(|err| limits.error(&store, err))?;
+        // `proc_exit` unwinds the guest, any other failure is an error. The
+        // export is still called once `_initialize` exits with 0
+        let initialized = instance
+            .get_typed_func::<(), ()>(&mut store, REACTOR_INITIALIZE_EXPORT)?
+            .call_async(&mut store, ())
+            .await;
+        let exit_code = match initialized {
+            Ok(()) => None,
+            Err(err) => match err.downcast_ref::<wasi_common::I32Exit>() {
+                Some(exit) if exit.0 == 0 => None,
+                Some(exit) => Some(exit.0),
+                None => return Err(limits.error(&store, err)),
+            },
+        };
+        let (output, exit_code) = match exit_code {
+            Some(exit_code) => (None, exit_code),
+            None => {
+                call.prepare(&mut store, &instance)
+                    .map_err(|err| limits.error(&store, err))?;
+                match call.run(&mut store, &instance).await {
+                    Ok(output) => (Some(output), 0),
+                    Err(err) => match err.downcast_ref::<wasi_common::I32Exit>() {
+                        Some(exit) => (None, exit.0),
+                        None => return Err(limits.error(&store, err)),
+                    },
+                }
+            }
+        };
+        let fuel_consumed = limits.fuel_consumed(&store)?;
+        let peak_memory = store.data().limiter.peak_memory();
+        let logs = store.data_mut().log.take();
+
+        drop(store);
+
+        // a guest exiting has no result, its output is what it wrote
+        let output = match output {
+            Some(output) => output,
+            None => self.stdout_output(&stdout)?,
+        };
+        Ok(InvokeOutput {
+            output,
+            fuel_consumed,
+            peak_memory,
+            exit_code: Some(exit_code),
+            stderr: Some(stderr.contents()),
+            logs,
+        })
+    }
+
+    // WASI context builder of a module with the environment and the
+    // preopened directories, its stderr captured
+    fn wasi_builder(&self, func_config: &FunctionConfig) -> Result<(WasiCtxBuilder, CappedPipe)> {
         let mut wasi = WasiCtxBuilder::new();
-        wasi.inherit_stdio();
         if let Some(envs) = self.config.wasi_envs() {
             wasi.envs(envs)?;
         }
-        let stdout = WritePipe::new_in_memory();
-        wasi.stdout(Box::new(stdout.clone()));
-        let serialized = serde_json::to_string(&data)?;
-        wasi.args(&[serialized])?;
+        // the guest never reaches the engine's own stdio, its stdin is empty
+        // and its stdout discarded unless set
+        wasi.stdin(Box::new(ReadPipe::from(Vec::new())));
+        wasi.stdout(Box::new(WritePipe::new(std::io::sink())));
+        let stderr = CappedPipe::new(self.config.max_stderr_bytes());
+        wasi.stderr(Box::new(WritePipe::new(stderr.clone())));
         for preopen_dir_path in self.config.preopened_dirs() {
             let preopen_dir = Dir::open_ambient_dir(preopen_dir_path, ambient_authority())?;
             wasi.preopened_dir(preopen_dir, preopen_dir_path)?;
         }
-        let wasictx = wasi.build();
+        for (host_dir, guest_dir) in func_config.preopened_dirs() {
+            let preopen_dir = Dir::open_ambient_dir(host_dir, ambient_authority())?;
+            wasi.preopened_dir(preopen_dir, guest_dir)?;
+        }
+        Ok((wasi, stderr))
+    }
 
-        let mut store = Store::new(&self.engine, wasictx);
+    // Hand the arguments of an invocation to a WASI module as laid out by the
+    // `wasi_input` of the function, and capture its stdout
+    fn wasi_input(
+        &self,
+        wasi: &mut WasiCtxBuilder,
+        args: &HashMap<String, String>,
+        func_config: &FunctionConfig,
+    ) -> Result<CappedPipe> {
+        let input = func_config.wasi_input().render(
+            func_config.program_name(),
+            args,
+            func_config.input(),
+        )?;
+        wasi.args(&input.argv)?;
+        wasi.envs(&input.envs)?;
+        if let Some(stdin) = input.stdin {
+            wasi.stdin(Box::new(ReadPipe::from(stdin)));
+        }
+        let stdout = CappedPipe::new(self.config.max_output_bytes());
+        wasi.stdout(Box::new(WritePipe::new(stdout.clone())));
+        Ok(stdout)
+    }
 
-        // Define maximum fuel
-        let _ = match self.config.max_fuel() {
-            Some(max_fuel) => store.fuel_async_yield_interval(Some(max_fuel)),
-            // If no limit is specified use maximum
-            None => store.set_fuel(u64::MAX),
-        };
+    // The stdout of an invocation without its final new line, which fails if
+    // it wrote past the cap
+    fn stdout_output(&self, stdout: &CappedPipe) -> Result<String> {
+        let contents = stdout.complete().ok_or_else(|| {
+            InvokeError::LimitExceeded(format!(
+                "stdout exceeds {} bytes",
+                self.config.max_output_bytes()
+            ))
+        })?;
+        let output = String::from_utf8(contents)?;
+        Ok(match output.strip_suffix('\n') {
+            Some(output) => output.to_string(),
+            None => output,
+        })
+    }
 
-        let instance = self.linker.instantiate_async(&mut store, &module).await?;
-        instance
-            .get_typed_func::<(), ()>(&mut store, "_start")?
-            .call_async(&mut store, ())
-            .await?;
+    // Push the read-only directories of a function into a built WASI context,
+    // the builder only preopens writable ones
+    fn preopen_read_only(&self, wasi: &WasiCtx, func_config: &FunctionConfig) -> Result<()> {
+        for (host_dir, guest_dir) in func_config.read_only_dirs() {
+            let preopen_dir = Dir::open_ambient_dir(host_dir, ambient_authority())?;
+            let preopen_dir = wasi_common::tokio::Dir::from_cap_std(preopen_dir);
+            wasi.push_preopened_dir(Box::new(ReadOnlyDir(Box::new(preopen_dir))), guest_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Run the `wasi:cli/run` export of a component prepared by
+    /// `prepare_component`, with the arguments handed to it and the result
+    /// read from stdout like WASI command modules. A failed run exits with
+    /// code 1.
+    pub async fn run_component(
+        &self,
+        instance_pre: &component::InstancePre<StoreState<ComponentCtx>>,
+        data: HashMap<String, String>,
+        func_config: &FunctionConfig,
+    ) -> Result<InvokeOutput> {
+        let stdout = CappedPipe::new(self.config.max_output_bytes());
+        let (mut wasi, stderr) = self.component_wasi(func_config)?;
+        wasi.stdout(stdout.clone());
+        let input = func_config.wasi_input().render(
+            func_config.program_name(),
+            &data,
+            func_config.input(),
+        )?;
+        wasi.args(&input.argv);
+        wasi.envs(&input.envs);
+        if let Some(stdin) = input.stdin {
+            wasi.stdin(preview2::pipe::MemoryInputPipe::new(stdin.into()));
+        }
+
+        let mut store = Store::new(
+            &self.engine,
+            StoreState::new(ComponentCtx::new(wasi.build())),
+        );
+        let limits = self.set_limits(&mut store, func_config)?;
+
+        let instance = instance_pre
+            .instantiate_async(&mut store)
+            .await
+            .map_err(InvokeError::from_instantiate)
+            .map_err(|err| limits.error(&store, err))?;
+        let run = instance
+            .exports(&mut store)
+            .instance(RUN_INTERFACE)
+            .ok_or_else(|| anyhow!("component doesn't export `{}`", RUN_INTERFACE))?
+            .typed_func::<(), (Result<(), ()>,)>(RUN_FUNC)?;
+        let exit_code = match run.call_async(&mut store, ()).await {
+            Ok((status,)) => {
+                run.post_return_async(&mut store).await?;
+                if status.is_ok() {
+                    0
+                } else {
+                    1
+                }
+            }
+            Err(err) => match err.downcast_ref::<preview2::I32Exit>() {
+                Some(exit) => exit.0,
+                None => return Err(limits.error(&store, err)),
+            },
+        };
+        let fuel_consumed = limits.fuel_consumed(&store)?;
+        let peak_memory = store.data().limiter.peak_memory();
 
         drop(store);
 
-        let contents = stdout
-            .try_into_inner()
-            .map_err(|_| anyhow::format_err!("failed to get stdout content"))?;
-        let contents: Vec<u8> = contents.into_inner();
-        let result = std::str::from_utf8(&contents)?;
-        let result = result.strip_suffix("\n").unwrap_or(result);
+        Ok(InvokeOutput {
+            output: self.stdout_output(&stdout)?,
+            fuel_consumed,
+            peak_memory,
+            exit_code: Some(exit_code),
+            stderr: Some(stderr.contents()),
+            ..Default::default()
+        })
+    }
+
+    /// Call the `export` of a component prepared by `prepare_component` with
+    /// typed parameters, the output is the JSON array of its results.
+    ///
+    /// Functions exported by an interface are named `<interface>#<function>`.
+    pub async fn call_component(
+        &self,
+        instance_pre: &component::InstancePre<StoreState<ComponentCtx>>,
+        export: &str,
+        params: &[serde_json::Value],
+        func_config: &FunctionConfig,
+    ) -> Result<InvokeOutput> {
+        let (mut wasi, stderr) = self.component_wasi(func_config)?;
+        let mut store = Store::new(
+            &self.engine,
+            StoreState::new(ComponentCtx::new(wasi.build())),
+        );
+        let limits = self.set_limits(&mut store, func_config)?;
+
+        let instance = instance_pre
+            .instantiate_async(&mut store)
+            .await
+            .map_err(InvokeError::from_instantiate)
+            .map_err(|err| limits.error(&store, err))?;
+        let func = wit::func(&mut store, &instance, export)
+            .ok_or_else(|| anyhow!("component doesn't export function `{}`", export))?;
+        let params = wit::params(&func.params(&store), params)
+            .with_context(|| format!("invalid parameters of `{}`", export))?;
+        let mut results = vec![component::Val::Bool(false); func.results(&store).len()];
+
+        func.call_async(&mut store, &params, &mut results)
+            .await
+            .map_err(|err| limits.error(&store, err))?;
+        func.post_return_async(&mut store).await?;
 
-        Ok(result.to_string())
+        Ok(InvokeOutput {
+            output: serde_json::to_string(&wit::results(&results)?)?,
+            fuel_consumed: limits.fuel_consumed(&store)?,
+            peak_memory: store.data().limiter.peak_memory(),
+            stderr: Some(stderr.contents()),
+            ..Default::default()
+        })
+    }
+
+    /// Hand `request` to the `wasi:http/incoming-handler` export of a
+    /// component prepared by `prepare_compiled`.
+    ///
+    /// The response is returned as soon as the component sets it, its body
+    /// streams while the handler keeps running in the returned task, which
+    /// ends with the output of the invocation once the handler returned.
+    /// Requests of the component itself are denied.
+    pub async fn serve_http(
+        &self,
+        instance_pre: &component::InstancePre<StoreState<ComponentCtx>>,
+        request: hyper::Request<HyperIncomingBody>,
+        func_config: &FunctionConfig,
+    ) -> Result<(
+        hyper::Response<HyperOutgoingBody>,
+        tokio::task::JoinHandle<Result<InvokeOutput>>,
+    )> {
+        let (mut wasi, stderr) = self.component_wasi(func_config)?;
+        let mut store = Store::new(
+            &self.engine,
+            StoreState::new(ComponentCtx::new(wasi.build())),
+        );
+        let limits = self.set_limits(&mut store, func_config)?;
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let request = store.data_mut().new_incoming_request(request)?;
+        let response = store.data_mut().new_response_outparam(sender)?;
+
+        let instance_pre = instance_pre.clone();
+        let timeout = func_config.timeout(self.config.timeout());
+        let handle = async move {
+            let (proxy, _) = Proxy::instantiate_pre(&mut store, &instance_pre)
+                .await
+                .map_err(InvokeError::from_instantiate)
+                .map_err(|err| limits.error(&store, err))?;
+            proxy
+                .wasi_http_incoming_handler()
+                .call_handle(&mut store, request, response)
+                .await
+                .map_err(|err| limits.error(&store, err))?;
+
+            Ok(InvokeOutput {
+                fuel_consumed: limits.fuel_consumed(&store)?,
+                peak_memory: store.data().limiter.peak_memory(),
+                stderr: Some(stderr.contents()),
+                logs: store.data_mut().log.take(),
+                ..Default::default()
+            })
+        };
+        // the handler may keep writing the body past the response
+        let handled = tokio::spawn(async move {
+            match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, handle)
+                    .await
+                    .unwrap_or_else(|_| Err(InvokeError::Timeout(timeout).into())),
+                None => handle.await,
+            }
+        });
+
+        match receiver.await {
+            Ok(Ok(response)) => Ok((response, handled)),
+            Ok(Err(code)) => Err(anyhow!(
+                "component failed to handle the request: {:?}",
+                code
+            )),
+            // the handler returned or failed without setting a response
+            Err(_) => match handled.await? {
+                Ok(_) => Err(anyhow!("component didn't set a response")),
+                Err(err) => Err(err),
+            },
+        }
+    }
+
+    // WASI preview 2 context of a component, with the environment and the
+    // preopened directories of WASI command modules, and its captured stderr.
+    // Its stdin is closed and its stdout discarded unless set.
+    fn component_wasi(
+        &self,
+        func_config: &FunctionConfig,
+    ) -> Result<(preview2::WasiCtxBuilder, CappedPipe)> {
+        let mut wasi = preview2::WasiCtxBuilder::new();
+        if let Some(envs) = self.config.wasi_envs() {
+            wasi.envs(envs);
+        }
+        let stderr = CappedPipe::new(self.config.max_stderr_bytes());
+        wasi.stderr(stderr.clone());
+        let preopened = self
+            .config
+            .preopened_dirs()
+            .iter()
+            .map(|dir| (dir, dir))
+            .chain(func_config.preopened_dirs().iter().map(|(h, g)| (h, g)));
+        for (host_dir, guest_dir) in preopened {
+            let preopen_dir = Dir::open_ambient_dir(host_dir, ambient_authority())?;
+            wasi.preopened_dir(preopen_dir, DirPerms::all(), FilePerms::all(), guest_dir);
+        }
+        for (host_dir, guest_dir) in func_config.read_only_dirs() {
+            let preopen_dir = Dir::open_ambient_dir(host_dir, ambient_authority())?;
+            wasi.preopened_dir(preopen_dir, DirPerms::READ, FilePerms::READ, guest_dir);
+        }
+        Ok((wasi, stderr))
     }
 
     pub async fn spawn(
@@ -108,57 +806,357 @@ impl WasmtimeRuntime {
         function: &str,
         args: HashMap<String, String>,
     ) -> Result<String> {
-        let serialized = serde_json::to_string(&args)?;
-        let mut store = Store::new(&self.engine, ());
-
-        // Define maximum fuel
-        let _ = match self.config.max_fuel() {
-            Some(max_fuel) => store.fuel_async_yield_interval(Some(max_fuel)),
-            // If no limit is specified use maximum
-            None => store.set_fuel(u64::MAX),
+        let instance_pre = self.plain_linker.instantiate_pre(&module)?;
+        let invocation = self
+            .spawn_pre(&instance_pre, function, args, &FunctionConfig::default())
+            .await?;
+        Ok(invocation.output)
+    }
+
+    /// Run the invocation future `call` made with `func_config`, failing with
+    /// `InvokeError::Timeout` once its deadline passed. The epoch only stops
+    /// running guest code, this also gives up on a guest waiting in a host
+    /// call.
+    pub async fn with_deadline<T>(
+        &self,
+        func_config: &FunctionConfig,
+        call: impl std::future::Future<Output = Result<T>>,
+    ) -> Result<T> {
+        match func_config.timeout(self.config.timeout()) {
+            Some(timeout) => tokio::time::timeout(timeout, call)
+                .await
+                .unwrap_or_else(|_| Err(InvokeError::Timeout(timeout).into())),
+            None => call.await,
+        }
+    }
+
+    /// Call the `function` export of a plain module prepared by `prepare`.
+    pub async fn spawn_pre(
+        &self,
+        instance_pre: &InstancePre<StoreState<()>>,
+        function: &str,
+        args: HashMap<String, String>,
+        func_config: &FunctionConfig,
+    ) -> Result<InvokeOutput> {
+        self.call_pre(instance_pre, Call::Spawn { function, args }, func_config)
+            .await
+    }
+
+    /// Call the `export` of a plain module prepared by `prepare` with typed
+    /// parameters, the output is the JSON array of its results.
+    pub async fn call_typed_pre(
+        &self,
+        instance_pre: &InstancePre<StoreState<()>>,
+        export: &str,
+        params: &[serde_json::Value],
+        func_config: &FunctionConfig,
+    ) -> Result<InvokeOutput> {
+        self.call_pre(instance_pre, Call::Typed { export, params }, func_config)
+            .await
+    }
+
+    async fn call_pre(
+        &self,
+        instance_pre: &InstancePre<StoreState<()>>,
+        call: Call<'_>,
+        func_config: &FunctionConfig,
+    ) -> Result<InvokeOutput> {
+        let mut store = Store::new(&self.engine, StoreState::new(()));
+
+        let limits = self.set_limits(&mut store, func_config)?;
+
+        let instance = instance_pre
+            .instantiate_async(&mut store)
+            .await
+            .map_err(InvokeError::from_instantiate)
+            .map_err(|err| limits.error(&store, err))?;
+        call.prepare(&mut store, &instance)
+            .map_err(|err| limits.error(&store, err))?;
+
+        let output = call
+            .run(&mut store, &instance)
+            .await
+            .map_err(|err| limits.error(&store, err))?;
+
+        Ok(InvokeOutput {
+            output,
+            fuel_consumed: limits.fuel_consumed(&store)?,
+            peak_memory: store.data().limiter.peak_memory(),
+            logs: store.data_mut().log.take(),
+            ..Default::default()
+        })
+    }
+
+    /// Call the `function` export like `spawn_pre`, on an idle instance of
+    /// the pool when there is one.
+    pub async fn spawn_pooled(
+        &self,
+        pool: &InstancePool,
+        function: &str,
+        args: HashMap<String, String>,
+        func_config: &FunctionConfig,
+    ) -> Result<InvokeOutput> {
+        self.call_pooled(pool, Call::Spawn { function, args }, func_config)
+            .await
+    }
+
+    /// Call the `export` like `call_typed_pre`, on an idle instance of the
+    /// pool when there is one.
+    pub async fn call_typed_pooled(
+        &self,
+        pool: &InstancePool,
+        export: &str,
+        params: &[serde_json::Value],
+        func_config: &FunctionConfig,
+    ) -> Result<InvokeOutput> {
+        self.call_pooled(pool, Call::Typed { export, params }, func_config)
+            .await
+    }
+
+    async fn call_pooled(
+        &self,
+        pool: &InstancePool,
+        call: Call<'_>,
+        func_config: &FunctionConfig,
+    ) -> Result<InvokeOutput> {
+        let mut warm = match pool.take() {
+            Some(warm) => warm,
+            None => self.instantiate_warm(pool, func_config).await?,
         };
 
-        let instance = Instance::new_async(&mut store, &module, &[]).await?;
-        //let wasm_function = instance.get_func(&mut store, function).unwrap();
-        let wasm_function =
-            instance.get_typed_func::<(i32, i32), (i32, i32)>(&mut store, function)?;
-
-        if serialized.len() > WASM_PAGE_SIZE as usize {
-            return Err(anyhow!("input args size larger than {}", WASM_PAGE_SIZE));
-        }
-        info!("serialized.len() is {}", serialized.len() as usize);
-        let memory = instance
-            .get_memory(&mut store, "memory")
-            .ok_or(anyhow::format_err!("failed to find `memory` export"))?;
-        let heap_base_global = instance
-            .get_global(&mut store, "__heap_base")
-            .ok_or(anyhow::format_err!("failed to find `__heap_base` export"))?;
-        let heap_base = heap_base_global
-            .get(&mut store)
-            .i32()
-            .ok_or(anyhow::format_err!("failed to find `__heap_base` export"))?;
-        info!("heap_base is {}", heap_base as usize);
-
-        memory.grow(&mut store, 1)?;
-        memory.write(&mut store, heap_base as usize, serialized.as_bytes())?;
-
-        let (pointer, length) = wasm_function
-            .call_async(&mut store, (heap_base, serialized.len() as i32))
-            .await?;
+        let limits = self.set_limits(&mut warm.store, func_config)?;
+
+        // a trapped instance is dropped with its store
+        let output = call
+            .run(&mut warm.store, &warm.instance)
+            .await
+            .map_err(|err| limits.error(&warm.store, err))?;
+        let result = InvokeOutput {
+            output,
+            fuel_consumed: limits.fuel_consumed(&warm.store)?,
+            peak_memory: warm.store.data().limiter.peak_memory(),
+            logs: warm.store.data_mut().log.take(),
+            ..Default::default()
+        };
+        warm.uses += 1;
+
+        if let Some(initial) = &warm.initial_memory {
+            let memory = warm
+                .instance
+                .get_memory(&mut warm.store, "memory")
+                .ok_or(anyhow::format_err!("failed to find `memory` export"))?;
+            // a memory can't shrink, the pages grown since are zeroed instead
+            let data = memory.data_mut(&mut warm.store);
+            data[..initial.len()].copy_from_slice(initial);
+            data[initial.len()..].fill(0);
+        }
+        for (global, initial) in &warm.initial_globals {
+            global.set(&mut warm.store, initial.clone())?;
+        }
+        pool.give_back(warm);
+
+        Ok(result)
+    }
+
+    /// Fill the store with the compute budget of one invocation, arm its
+    /// wall-clock deadline, bound the resources it may hold, start its log
+    /// and hand it its key-value bucket.
+    fn set_limits<T>(
+        &self,
+        store: &mut Store<StoreState<T>>,
+        func_config: &FunctionConfig,
+    ) -> Result<InvocationLimits> {
+        let limits = InvocationLimits {
+            budget: func_config.fuel_budget(self.config.max_fuel()),
+            timeout: func_config.timeout(self.config.timeout()),
+        };
+
+        store.set_fuel(budget_fuel(limits.budget))?;
+        // The budget alone doesn't let other invocations run, yield regularly
+        store.fuel_async_yield_interval(Some(UNIT_OF_COMPUTE_IN_INSTRUCTIONS))?;
+
+        // Round up, the current epoch is already partly over
+        let ticks = limits.timeout.map_or(NO_DEADLINE_TICKS, |timeout| {
+            (timeout.as_nanos() / EPOCH_TICK.as_nanos()) as u64 + 1
+        });
+        store.set_epoch_deadline(ticks);
+
+        let resources = func_config.limits(self.config.limits());
+        store.data_mut().limiter.start(resources);
+        store.limiter(|state| &mut state.limiter);
+        store.data_mut().log.start(func_config);
+        store.data_mut().kv = func_config.kv_bucket().cloned();
 
-        let mut total_buffer: [u8; WASM_PAGE_SIZE as usize] = [0; WASM_PAGE_SIZE as usize];
-        let buffer = &mut total_buffer[0..length as usize];
-        memory.read(&store, pointer as usize, buffer)?;
-        let s = std::str::from_utf8(&buffer)?;
+        Ok(limits)
+    }
+
+    async fn instantiate_warm(
+        &self,
+        pool: &InstancePool,
+        func_config: &FunctionConfig,
+    ) -> Result<WarmInstance> {
+        let mut store = Store::new(&self.engine, StoreState::new(()));
+        let limits = self.set_limits(&mut store, func_config)?;
+        let instance = pool
+            .instance_pre()
+            .instantiate_async(&mut store)
+            .await
+            .map_err(InvokeError::from_instantiate)
+            .map_err(|err| limits.error(&store, err))?;
+        // modules speaking the spawn ABI get room for their arguments, the
+        // others are only called with typed parameters
+        let memory = instance.get_memory(&mut store, "memory");
+        if let (Some(memory), Ok(abi)) = (memory, GuestAbi::detect(&mut store, &instance)) {
+            abi.reserve(&mut store, &memory)
+                .map_err(|err| limits.error(&store, err))?;
+        }
 
-        Ok(String::from(s))
+        let initial_memory = match (pool.policy().reset_memory, memory) {
+            (true, Some(memory)) => Some(memory.data(&store).to_vec()),
+            _ => None,
+        };
+        let mut initial_globals = Vec::new();
+        if pool.policy().reset_memory {
+            let globals: Vec<Global> = instance
+                .exports(&mut store)
+                .filter_map(|export| export.into_global())
+                .collect();
+            for global in globals {
+                if global.ty(&store).mutability() == Mutability::Var {
+                    initial_globals.push((global, global.get(&mut store)));
+                }
+            }
+        }
+
+        Ok(WarmInstance {
+            store,
+            instance,
+            uses: 0,
+            initial_memory,
+            initial_globals,
+        })
+    }
+
+    /// Inspect the imports and exports of a compiled module to find out how
+    /// it has to be invoked, `function` is the export called for plain and reactor modules.
+    ///
+    /// Fails if the module imports can't be satisfied, the entry export is
+    /// missing or a spawn ABI export lacks the memory exports it needs.
+    pub fn inspect(&self, module: &Module, function: &str) -> Result<FunctionKind> {
+        if let Some(start) = module.get_export("_start") {
+            let start = start
+                .func()
+                .ok_or_else(|| anyhow!("`_start` export is not a function"))?;
+            if start.params().len() != 0 || start.results().len() != 0 {
+                return Err(anyhow!(
+                    "`_start` export must have the `() -> ()` signature"
+                ));
+            }
+            self.linker
+                .instantiate_pre(module)
+                .context("module imports can't be satisfied by the WASI linker")?;
+
+            return Ok(FunctionKind::WasiCommand);
+        }
+
+        let reactor = match module.get_export(REACTOR_INITIALIZE_EXPORT) {
+            Some(initialize) => {
+                let initialize = initialize.func().ok_or_else(|| {
+                    anyhow!("`{}` export is not a function", REACTOR_INITIALIZE_EXPORT)
+                })?;
+                if initialize.params().len() != 0 || initialize.results().len() != 0 {
+                    return Err(anyhow!(
+                        "`{}` export must have the `() -> ()` signature",
+                        REACTOR_INITIALIZE_EXPORT
+                    ));
+                }
+                self.linker
+                    .instantiate_pre(module)
+                    .context("module imports can't be satisfied by the WASI linker")?;
+                true
+            }
+            None => false,
+        };
+
+        if !reactor {
+            // plain modules may only import the host API
+            let import = module
+                .imports()
+                .find(|import| !host_import(import.module(), import.name()));
+            if let Some(import) = import {
+                return Err(anyhow!(
+                    "module without `_start` or `_initialize` can't import `{}::{}`",
+                    import.module(),
+                    import.name()
+                ));
+            }
+            self.plain_linker
+                .instantiate_pre(module)
+                .context("module imports can't be satisfied by the host API")?;
+        }
+
+        let func = module
+            .get_export(function)
+            .ok_or_else(|| anyhow!("module doesn't export `_start` or `{}`", function))?;
+        let func = func
+            .func()
+            .ok_or_else(|| anyhow!("`{}` export is not a function", function))?;
+        let params: Vec<ValType> = func.params().collect();
+        let results: Vec<ValType> = func.results().collect();
+        if params != [ValType::I32, ValType::I32] || results != [ValType::I32, ValType::I32] {
+            return Ok(if reactor {
+                FunctionKind::WasiReactor
+            } else {
+                FunctionKind::Typed
+            });
+        }
+        if !matches!(module.get_export("memory"), Some(ExternType::Memory(_))) {
+            return Err(anyhow!("module doesn't export `memory`"));
+        }
+        if !GuestAbi::supported(module) {
+            return Err(anyhow!(
+                "module exports neither `{}`, `{}` nor `{}` of the expected types",
+                ALLOC_EXPORT,
+                REALLOC_EXPORT,
+                HEAP_BASE_EXPORT
+            ));
+        }
+
+        Ok(if reactor {
+            FunctionKind::WasiReactor
+        } else {
+            FunctionKind::Export
+        })
+    }
+
+    /// Check that the imports of a component can be satisfied by WASI
+    /// preview 2 and wasi-http. Its exports are only looked up when it is invoked.
+    pub fn inspect_component(&self, component: &Component) -> Result<FunctionKind> {
+        self.component_linker
+            .instantiate_pre(component)
+            .context("component imports can't be satisfied by WASI preview 2 and wasi-http")?;
+
+        Ok(FunctionKind::Component)
+    }
+
+    /// Inspect a module with `inspect` or a component with
+    /// `inspect_component`.
+    pub fn inspect_compiled(&self, compiled: &Compiled, function: &str) -> Result<FunctionKind> {
+        match compiled {
+            Compiled::Module(module) => self.inspect(module, function),
+            Compiled::Component(component) => self.inspect_component(component),
+            Compiled::HttpProxy(component) => {
+                self.inspect_component(component)?;
+                Ok(FunctionKind::HttpProxy)
+            }
+        }
     }
 
     pub fn get_engine(&self) -> &Engine {
         &self.engine
     }
 
-    pub fn get_liker(&self) -> &Linker<WasiCtx> {
+    pub fn get_liker(&self) -> &Linker<StoreState<WasiCtx>> {
         &self.linker
     }
 
@@ -166,3 +1164,222 @@ impl WasmtimeRuntime {
         &self.config
     }
 }
+
+// Imports satisfied by the host API linked in every linker
+fn host_import(module: &str, name: &str) -> bool {
+    guest_log::provides(module, name) || kv::provides(module, name)
+}
+
+async fn initialize<T: Send>(store: &mut Store<T>, instance: &Instance) -> Result<()> {
+    instance
+        .get_typed_func::<(), ()>(&mut *store, INITIALIZE_EXPORT)?
+        .call_async(&mut *store, ())
+        .await
+        .with_context(|| format!("`{}` failed", INITIALIZE_EXPORT))
+}
+
+// Store `bytes` as the `precompiled` artifact of the module of `digest` in
+// `cache_dir`, replacing the artifacts of the module built by other engines
+fn write_precompiled(
+    cache_dir: &Path,
+    digest: &str,
+    precompiled: &Path,
+    bytes: &[u8],
+) -> Result<()> {
+    std::fs::create_dir_all(cache_dir)?;
+
+    let prefix = format!("{}-", digest);
+    for entry in std::fs::read_dir(cache_dir)? {
+        let stale = entry?.path();
+        let name = stale.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with(&prefix)
+            && stale
+                .extension()
+                .is_some_and(|e| e == PRECOMPILED_EXTENSION)
+        {
+            std::fs::remove_file(&stale)?;
+        }
+    }
+
+    let tmp = precompiled.with_extension("tmp");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(&tmp, precompiled)?;
+
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Feeds what a `Hash` implementation writes into SHA-256, which unlike
+// `DefaultHasher` is stable across Rust releases
+struct Sha256Hasher(Sha256);
+
+impl Hasher for Sha256Hasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        let digest = self.0.clone().finalize();
+        digest[..8]
+            .iter()
+            .fold(0, |hash, byte| hash << 8 | u64::from(*byte))
+    }
+}
+
+// A compiled artifact cached by `compile_artifact`
+trait Artifact: Sized {
+    fn new(engine: &Engine, wasm: &[u8]) -> Result<Self>;
+    unsafe fn deserialize_file(engine: &Engine, path: &Path) -> Result<Self>;
+    fn serialize(&self) -> Result<Vec<u8>>;
+}
+
+impl Artifact for Module {
+    fn new(engine: &Engine, wasm: &[u8]) -> Result<Self> {
+        Module::new(engine, wasm)
+    }
+
+    unsafe fn deserialize_file(engine: &Engine, path: &Path) -> Result<Self> {
+        Module::deserialize_file(engine, path)
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Module::serialize(self)
+    }
+}
+
+impl Artifact for Component {
+    fn new(engine: &Engine, wasm: &[u8]) -> Result<Self> {
+        Component::new(engine, wasm)
+    }
+
+    unsafe fn deserialize_file(engine: &Engine, path: &Path) -> Result<Self> {
+        Component::deserialize_file(engine, path)
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Component::serialize(self)
+    }
+}
+
+// An export call of an invocation
+enum Call<'a> {
+    // the spawn ABI, with the arguments serialized in guest memory
+    Spawn {
+        function: &'a str,
+        args: HashMap<String, String>,
+    },
+    // typed parameters and results, given and returned as JSON
+    Typed {
+        export: &'a str,
+        params: &'a [serde_json::Value],
+    },
+}
+
+impl Call<'_> {
+    // Prepare a new instance for the call, the legacy spawn ABI needs room
+    // for the arguments
+    fn prepare<T>(&self, store: &mut Store<StoreState<T>>, instance: &Instance) -> Result<()> {
+        if let Call::Spawn { .. } = self {
+            let memory = instance
+                .get_memory(&mut *store, "memory")
+                .ok_or(anyhow::format_err!("failed to find `memory` export"))?;
+            GuestAbi::detect(store, instance)?.reserve(store, &memory)?;
+        }
+        Ok(())
+    }
+
+    async fn run<T: Send>(
+        self,
+        store: &mut Store<StoreState<T>>,
+        instance: &Instance,
+    ) -> Result<String> {
+        match self {
+            Call::Spawn { function, args } => call_export(store, instance, function, args).await,
+            Call::Typed { export, params } => call_typed(store, instance, export, params).await,
+        }
+    }
+}
+
+// Call an export with typed parameters, checked against its type
+async fn call_typed<T: Send>(
+    store: &mut Store<StoreState<T>>,
+    instance: &Instance,
+    export: &str,
+    params: &[serde_json::Value],
+) -> Result<String> {
+    let func = instance
+        .get_func(&mut *store, export)
+        .ok_or_else(|| anyhow!("module doesn't export function `{}`", export))?;
+    let ty = func.ty(&*store);
+    let params = typed::params(&ty, params)
+        .with_context(|| format!("invalid parameters of `{}`", export))?;
+    let mut results = vec![Val::I32(0); ty.results().len()];
+
+    func.call_async(&mut *store, &params, &mut results).await?;
+
+    Ok(serde_json::to_string(&typed::results(&results)?)?)
+}
+
+// Call an export with the spawn ABI: the export takes the pointer and length
+// of the serialized arguments and returns the pointer and length of the output.
+async fn call_export<T: Send>(
+    store: &mut Store<StoreState<T>>,
+    instance: &Instance,
+    function: &str,
+    args: HashMap<String, String>,
+) -> Result<String> {
+    let serialized = serde_json::to_string(&args)?;
+    let wasm_function = instance.get_typed_func::<(i32, i32), (i32, i32)>(&mut *store, function)?;
+    let memory = instance
+        .get_memory(&mut *store, "memory")
+        .ok_or(anyhow::format_err!("failed to find `memory` export"))?;
+    let abi = GuestAbi::detect(store, instance)?;
+
+    debug!("serialized.len() is {}", serialized.len());
+    let input = abi.write(store, &memory, serialized.as_bytes()).await?;
+    let input_len = serialized.len() as i32;
+
+    let (pointer, length) = wasm_function
+        .call_async(&mut *store, (input, input_len))
+        .await?;
+
+    // freeing the input may reuse it, read the output first, it may even be
+    // the input buffer itself
+    let output = abi.read(store, &memory, pointer, length)?;
+    abi.free(store, input, input_len).await?;
+    if pointer != input {
+        abi.free(store, pointer, length).await?;
+    }
+
+    Ok(String::from_utf8(output)?)
+}
+
+// Fuel of a compute budget, unlimited budgets get all the fuel there is
+fn budget_fuel(budget: Option<u64>) -> u64 {
+    budget.map_or(u64::MAX, |units| {
+        units.saturating_mul(UNIT_OF_COMPUTE_IN_INSTRUCTIONS)
+    })
+}
+
+// Limits one invocation runs with, set on its store by `set_limits`
+struct InvocationLimits {
+    // compute budget in units of 100k instructions
+    budget: Option<u64>,
+    timeout: Option<Duration>,
+}
+
+impl InvocationLimits {
+    // Tell apart the failures caused by the limits
+    fn error<T>(&self, store: &Store<StoreState<T>>, err: anyhow::Error) -> anyhow::Error {
+        let denied = store.data().limiter.denied().map(str::to_string);
+        InvokeError::from_call(err, self.budget, self.timeout, denied)
+    }
+
+    // Fuel used since the limits were set on the store
+    fn fuel_consumed<T>(&self, store: &Store<T>) -> Result<u64> {
+        Ok(budget_fuel(self.budget) - store.get_fuel()?)
+    }
+}
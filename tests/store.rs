@@ -1,12 +1,52 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use wasm_engine::{
     function_store::{
-        local_store::{FunctionEntries, FunctionStore},
-        module_store::ModuleStore,
+        events::{EventLog, FunctionEventKind},
+        kv_store::KvStore,
+        local_store::{
+            select_module, FunctionEntries, FunctionEntry, FunctionStatus, FunctionStore,
+            MODULE_PATH_ANNOTATION,
+        },
+        module_store::{ModuleEntry, ModuleLimits, ModuleStore},
+        selector::LabelSelector,
     },
-    wrapper::{config::EnvConfig, environment::Environment},
+    wrapper::{config::EnvConfig, environment::Environment, kv::KvHandle},
 };
 use wasmtime::Module;
 
+#[tokio::test]
+async fn event_log() -> anyhow::Result<()> {
+    let events = EventLog::new();
+    let store = ModuleStore::new().with_events(events.clone());
+    let wasm_runtime = Environment::new(EnvConfig::default()).unwrap();
+    let module = Module::from_file(
+        wasm_runtime.runtime().get_engine(),
+        "./tests/authentication.wasm",
+    )?;
+
+    store.insert("authentication", module, false)?;
+    assert_eq!(events.version(), 1);
+
+    let (replay, mut receiver) = events.subscribe(0)?;
+    assert_eq!(replay.len(), 1);
+    assert_eq!(replay[0].kind, FunctionEventKind::Loaded);
+
+    store.remove("authentication")?;
+    let event = receiver.recv().await?;
+    assert_eq!(event.version, 2);
+    assert_eq!(event.kind, FunctionEventKind::Evicted);
+    assert_eq!(event.function, "authentication");
+
+    let (replay, _) = events.subscribe(2)?;
+    assert!(replay.is_empty());
+    assert!(events.subscribe(3).is_err());
+
+    Ok(())
+}
+
 #[test]
 fn module_store() -> anyhow::Result<()> {
     let wasm_runtime = Environment::new(EnvConfig::default()).unwrap();
@@ -30,6 +70,91 @@ fn module_store() -> anyhow::Result<()> {
     assert_eq!(authentication_module.name(), "authentication");
     assert_eq!(authentication_module.capability(), false);
 
+    // every clone of an entry sees it marked used again
+    let used = authentication_module.last_used();
+    std::thread::sleep(Duration::from_millis(1));
+    store.get("authentication")?;
+    assert!(authentication_module.last_used() > used);
+
+    Ok(())
+}
+
+#[test]
+fn module_store_eviction() -> anyhow::Result<()> {
+    let wasm_runtime = Environment::new(EnvConfig::default()).unwrap();
+    let runtime = wasm_runtime.runtime();
+    let module = &Module::from_file(runtime.get_engine(), "./tests/authentication.wasm")?;
+    let store = ModuleStore::new();
+    store.set_limits(ModuleLimits {
+        max_entries: Some(2),
+        ..Default::default()
+    });
+
+    store.insert("a", module.clone(), false)?;
+    store.insert("b", module.clone(), false)?;
+    // `a` is used last, so `b` is the one evicted
+    store.get("a")?;
+    store.insert("c", module.clone(), false)?;
+    assert!(store.exist("a"));
+    assert!(!store.exist("b"));
+    assert!(store.exist("c"));
+
+    let stats = store.stats();
+    assert_eq!(stats.resident_entries, 2);
+    assert_eq!(stats.resident_bytes, 2 * store.get("a")?.size());
+    assert_eq!(stats.lru_evictions, 1);
+
+    store.remove("c")?;
+    store.insert_entry(
+        ModuleEntry::new("d".to_string(), module.clone(), false)
+            .with_idle_timeout(Some(Duration::ZERO)),
+    )?;
+    assert_eq!(store.evict_idle(), vec!["d".to_string()]);
+    assert!(store.exist("a"));
+    assert_eq!(store.stats().idle_evictions, 1);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn module_store_single_flight() -> anyhow::Result<()> {
+    let wasm_runtime = Environment::new(EnvConfig::default()).unwrap();
+    let module = Module::from_file(
+        wasm_runtime.runtime().get_engine(),
+        "./tests/authentication.wasm",
+    )?;
+    let store = ModuleStore::new();
+    let loads = Arc::new(AtomicUsize::new(0));
+
+    let mut tasks = Vec::new();
+    for _ in 0..8 {
+        let (store, module, loads) = (store.clone(), module.clone(), loads.clone());
+        tasks.push(tokio::spawn(async move {
+            let inserter = store.clone();
+            store
+                .get_or_load("authentication", move || async move {
+                    loads.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    inserter.insert("authentication", module, false)
+                })
+                .await
+        }));
+    }
+    for task in tasks {
+        assert_eq!(task.await??.name(), "authentication");
+    }
+    assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+    // a failed load is reported to every waiter, and retried by the next caller
+    let failing = || async { Err(anyhow::anyhow!("broken module")) };
+    let (first, second) = tokio::join!(
+        store.get_or_load("broken", failing),
+        store.get_or_load("broken", failing)
+    );
+    assert!(first.err().unwrap().to_string().contains("broken module"));
+    assert!(second.err().unwrap().to_string().contains("broken module"));
+    assert!(store.get_or_load("broken", failing).await.is_err());
+
     Ok(())
 }
 
@@ -100,3 +225,261 @@ async fn local_store() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn module_selection() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join("wasmengine-test-module-selection");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("bin"))?;
+    std::fs::create_dir_all(dir.join("lib"))?;
+    let wasm = std::fs::read("./tests/authentication.wasm")?;
+
+    // a module shipped with sidecar files, detected by its magic bytes
+    // whatever its name
+    std::fs::write(dir.join("LICENSE"), "MIT")?;
+    std::fs::write(dir.join("config.wasm"), "{\"not\": \"a module\"}")?;
+    std::fs::write(dir.join("bin/handler"), &wasm)?;
+    let no_annotations = HashMap::new();
+    let selected = select_module(&dir, None, &no_annotations)?;
+    assert_eq!(selected, dir.join("bin/handler").canonicalize()?);
+
+    // several modules need to be told apart
+    std::fs::write(dir.join("lib/helper.wasm"), &wasm)?;
+    assert!(select_module(&dir, None, &no_annotations).is_err());
+
+    // the deploy request wins over the image annotation
+    let annotations = HashMap::from([(
+        MODULE_PATH_ANNOTATION.to_string(),
+        "lib/helper.wasm".to_string(),
+    )]);
+    let selected = select_module(&dir, None, &annotations)?;
+    assert_eq!(selected, dir.join("lib/helper.wasm").canonicalize()?);
+    let selected = select_module(&dir, Some("/bin/handler"), &annotations)?;
+    assert_eq!(selected, dir.join("bin/handler").canonicalize()?);
+
+    // explicit paths must stay inside the image and name a file
+    std::fs::write(
+        std::env::temp_dir().join("wasmengine-test-outside.wasm"),
+        &wasm,
+    )?;
+    assert!(select_module(
+        &dir,
+        Some("../wasmengine-test-outside.wasm"),
+        &no_annotations
+    )
+    .is_err());
+    assert!(select_module(
+        &dir,
+        Some("bin/../../wasmengine-test-outside.wasm"),
+        &no_annotations
+    )
+    .is_err());
+    assert!(select_module(&dir, Some("lib"), &no_annotations).is_err());
+    assert!(select_module(&dir, Some("missing.wasm"), &no_annotations).is_err());
+
+    std::fs::remove_file(std::env::temp_dir().join("wasmengine-test-outside.wasm"))?;
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn label_selector() -> anyhow::Result<()> {
+    let labels = HashMap::from([
+        ("team".to_string(), "payments".to_string()),
+        ("env".to_string(), "prod".to_string()),
+    ]);
+
+    let selector: LabelSelector = "team=payments,env!=dev".parse()?;
+    assert!(selector.matches(&labels));
+    assert_eq!(selector.to_string(), "team=payments,env!=dev");
+
+    let selector: LabelSelector = "team==search".parse()?;
+    assert!(!selector.matches(&labels));
+
+    let selector: LabelSelector = "env, !owner".parse()?;
+    assert!(selector.matches(&labels));
+
+    let selector: LabelSelector = "!env".parse()?;
+    assert!(!selector.matches(&labels));
+
+    let selector: LabelSelector = "".parse()?;
+    assert!(selector.is_empty());
+    assert!(selector.matches(&labels));
+
+    assert!("=payments".parse::<LabelSelector>().is_err());
+    assert!("team payments".parse::<LabelSelector>().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn function_handlers() -> anyhow::Result<()> {
+    let mut entry = FunctionEntry {
+        func_name: "math".to_string(),
+        ..FunctionEntry::default()
+    };
+    assert_eq!(entry.handler_export(None)?, "math");
+
+    entry.entrypoint = Some("add".to_string());
+    entry
+        .handlers
+        .insert("halve".to_string(), "half".to_string());
+    assert_eq!(entry.handler_export(None)?, "add");
+    assert_eq!(entry.handler_export(Some("halve"))?, "half");
+    assert!(entry.handler_export(Some("half")).is_err());
+
+    // functions and namespaces have their own bucket names
+    assert_eq!(entry.kv_bucket(), "fn/math");
+    entry.kv_namespace = Some("math".to_string());
+    assert_eq!(entry.kv_bucket(), "ns/math");
+
+    Ok(())
+}
+
+#[test]
+fn kv_store() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join("wasmengine-test-kv-store");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    {
+        let store = KvStore::open(&dir, 16)?;
+        let bucket = store.bucket("counter")?;
+        assert!(bucket.set(b"count", b"1")?);
+        assert!(bucket.set(b"count", b"12")?);
+        // 5 + 2 bytes held, 7 + 5 would exceed the quota
+        assert!(!bucket.set(b"another", b"value")?);
+        assert!(bucket.set(b"other", b"x")?);
+        assert_eq!(bucket.get(b"count")?, Some(b"12".to_vec()));
+        assert_eq!(bucket.keys(b"c")?, vec![b"count".to_vec()]);
+        assert!(store.bucket("").is_err());
+    }
+
+    // buckets are durable, their usage is recomputed when reopened; sled
+    // holds its lock until the flusher thread of the dropped store is done
+    let mut reopened = KvStore::open(&dir, 16);
+    for _ in 0..100 {
+        if reopened.is_ok() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        reopened = KvStore::open(&dir, 16);
+    }
+    let store = reopened?;
+    assert!(store.exist("counter"));
+    assert!(!store.exist("missing"));
+    let info = store.buckets()?;
+    assert_eq!(info.len(), 1);
+    assert_eq!((info[0].keys, info[0].bytes), (2, 13));
+
+    let bucket = store.bucket("counter")?;
+    assert!(bucket.delete(b"other")?);
+    assert!(!bucket.delete(b"other")?);
+    assert_eq!(bucket.info().bytes, 7);
+    bucket.clear()?;
+    assert_eq!(bucket.info().bytes, 0);
+    assert_eq!(bucket.get(b"count")?, None);
+
+    // a lazy bucket is only created by a write
+    let lazy = store.lazy_bucket("lazy");
+    assert_eq!(KvHandle::get(&lazy, b"count")?, None);
+    assert!(KvHandle::keys(&lazy, b"")?.is_empty());
+    assert!(!KvHandle::delete(&lazy, b"count")?);
+    assert!(!store.exist("lazy"));
+    assert!(KvHandle::set(&lazy, b"count", b"1")?);
+    assert!(store.exist("lazy"));
+    assert_eq!(store.bucket("lazy")?.get(b"count")?, Some(b"1".to_vec()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn warm_up() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join("wasmengine-test-warm-up");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let wasm_runtime = Environment::new(EnvConfig::default())?;
+
+    // a function restored from a previous run and one whose module is gone
+    let mut funcs = HashMap::new();
+    for (name, path) in [
+        ("authentication", "./tests/authentication.wasm"),
+        ("missing", "./tests/missing.wasm"),
+    ] {
+        funcs.insert(
+            name.to_string(),
+            FunctionEntry {
+                func_name: name.to_string(),
+                func_local_path: path.to_string(),
+                ..Default::default()
+            },
+        );
+    }
+    std::fs::write(dir.join("persist.json"), serde_json::to_vec(&funcs)?)?;
+    let events = EventLog::new();
+    let store = FunctionStore::new(dir.to_str().unwrap()).with_events(events.clone());
+    store.restore().await?;
+
+    let failed = store
+        .warm_up(2, |func| {
+            let runtime = wasm_runtime.runtime();
+            async move { runtime.compile(&func.func_local_path).map(|_| ()) }
+        })
+        .await?;
+    assert_eq!(failed, vec!["missing".to_string()]);
+    assert_eq!(
+        store.query("authentication").await?.status,
+        FunctionStatus::Available
+    );
+    assert!(matches!(
+        store.query("missing").await?.status,
+        FunctionStatus::Unavailable(reason) if reason.contains("missing.wasm")
+    ));
+
+    let (replay, _) = events.subscribe(0)?;
+    assert_eq!(replay.len(), 1);
+    assert_eq!(replay[0].kind, FunctionEventKind::Failed);
+    assert_eq!(replay[0].function, "missing");
+
+    // the status is evaluated again on the next start
+    let store = FunctionStore::new(dir.to_str().unwrap());
+    store.restore().await?;
+    assert_eq!(
+        store.query("missing").await?.status,
+        FunctionStatus::Available
+    );
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reservation() -> anyhow::Result<()> {
+    let store = FunctionStore::new("/var/lib/wasmengine-test/reservation/");
+
+    let reservation = store.reserve("hello").await?;
+    assert_eq!(reservation.name(), "hello");
+    // a concurrent deploy of the same name fails until the first one is done
+    assert!(store.reserve("hello").await.is_err());
+    assert!(store.reserve("world").await.is_ok());
+
+    store
+        .insert(FunctionEntry {
+            func_name: reservation.name().to_string(),
+            ..Default::default()
+        })
+        .await?;
+    drop(reservation);
+    assert!(store.reserve("hello").await.is_err());
+
+    // deleting a function removes the files of its image
+    let image = std::path::Path::new("/var/lib/wasmengine-test/reservation/hello/image");
+    std::fs::create_dir_all(image)?;
+    std::fs::write(image.join("stale.wasm"), b"\0asm")?;
+    store.delete("hello").await?;
+    assert!(!image.exists());
+    assert!(store.reserve("hello").await.is_ok());
+
+    Ok(())
+}
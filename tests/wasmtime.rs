@@ -1,6 +1,17 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use wasm_engine::wrapper::{config::EnvConfig, environment::Environment};
+use http_body_util::{BodyExt, Empty};
+use serde_json::json;
+use wasm_engine::function_store::kv_store::KvStore;
+use wasm_engine::wrapper::{
+    config::{EnvConfig, FunctionConfig, PoolingConfig, ResourceLimits},
+    environment::Environment,
+    error::InvokeError,
+    guest_log::{LogLevel, LogLine},
+    input::WasiInput,
+    instance::{PreparedInstance, ReusePolicy},
+    kind::FunctionKind,
+};
 use wasmtime::Module;
 
 #[tokio::test(flavor = "multi_thread")]
@@ -60,7 +71,9 @@ async fn authentication() -> anyhow::Result<()> {
                 .spawn(module.clone(), "authentication", t_args)
                 .await?;
         } else {
-            result = runtime.spawn_wasi(module_wasi.clone(), t_args).await?;
+            result = runtime
+                .spawn_wasi(module_wasi.clone(), t_args, &FunctionConfig::default())
+                .await?;
         }
         assert!(
             result.contains(t.contains.as_str()),
@@ -72,3 +85,1571 @@ async fn authentication() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn inspect() -> anyhow::Result<()> {
+    let wasm_runtime = Environment::new(EnvConfig::default()).unwrap();
+    let runtime = wasm_runtime.runtime();
+    let module = Module::from_file(runtime.get_engine(), "./tests/authentication.wasm")?;
+    let module_wasi = Module::from_file(runtime.get_engine(), "./tests/authentication-wasi.wasm")?;
+    let gcd = Module::from_file(runtime.get_engine(), "./benches/gcd.wat")?;
+
+    assert_eq!(
+        runtime.inspect(&module, "authentication")?,
+        FunctionKind::Export
+    );
+    assert_eq!(
+        runtime.inspect(&module_wasi, "authentication-wasi")?,
+        FunctionKind::WasiCommand
+    );
+    // exports of other signatures are called with typed parameters
+    assert_eq!(runtime.inspect(&gcd, "gcd")?, FunctionKind::Typed);
+    // export missing
+    assert!(runtime.inspect(&module, "authentication-none").is_err());
+    assert!(runtime.inspect(&gcd, "lcm").is_err());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn compile_cached() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join("wasmengine-test-compile-cached");
+    let _ = std::fs::remove_dir_all(&dir);
+    let image_dir = dir.join("image");
+    let cache_dir = dir.join("cache");
+    std::fs::create_dir_all(&image_dir)?;
+    let path = image_dir.join("authentication.wasm");
+    std::fs::copy("./tests/authentication.wasm", &path)?;
+    let path = path.to_str().unwrap();
+
+    let precompiled = |dir: &std::path::Path| -> anyhow::Result<usize> {
+        Ok(std::fs::read_dir(dir)?
+            .filter(|e| {
+                e.as_ref()
+                    .map(|e| e.path().extension().is_some_and(|x| x == "cwasm"))
+                    .unwrap_or(false)
+            })
+            .count())
+    };
+
+    // without a cache directory nothing is written
+    let wasm_runtime = Environment::new(EnvConfig::default())?;
+    wasm_runtime.runtime().compile_cached(path)?;
+    assert_eq!(precompiled(&image_dir)?, 0);
+    assert!(!cache_dir.exists());
+
+    let mut config = EnvConfig::default();
+    config.set_cache_dir(Some(cache_dir.clone()));
+    let wasm_runtime = Environment::new(config)?;
+    let runtime = wasm_runtime.runtime();
+
+    runtime.compile_cached(path)?;
+    assert_eq!(precompiled(&cache_dir)?, 1);
+    assert_eq!(precompiled(&image_dir)?, 0);
+
+    // an artifact shipped in the image is never loaded, even named like a
+    // cached one: this one is of another module
+    let artifact = std::fs::read_dir(&cache_dir)?.next().unwrap()?.file_name();
+    let other = Module::from_file(runtime.get_engine(), "./tests/authentication-wasi.wasm")?;
+    std::fs::write(image_dir.join(&artifact), other.serialize()?)?;
+    std::fs::write(
+        format!("{}.{}", path, artifact.to_string_lossy()),
+        other.serialize()?,
+    )?;
+
+    // the second load maps the cached artifact
+    let module = runtime.compile_cached(path)?;
+    assert_eq!(precompiled(&cache_dir)?, 1);
+
+    let mut args = HashMap::new();
+    args.insert("arg_uri".to_string(), "uri".to_string());
+    args.insert("arg_body".to_string(), "body".to_string());
+    args.insert("arg_secret".to_string(), "secret".to_string());
+    let result = runtime.spawn(module, "authentication", args).await?;
+    assert!(result.contains("Auth Forbidden!"));
+
+    // a changed module gets its own artifact, both are kept for the functions
+    // still using the first one
+    std::fs::copy("./tests/authentication-wasi.wasm", path)?;
+    runtime.compile_cached(path)?;
+    assert_eq!(precompiled(&cache_dir)?, 2);
+
+    // the artifact of another engine build is replaced
+    let stale = cache_dir.join(artifact.to_string_lossy().replacen('-', "-stale", 1));
+    std::fs::rename(cache_dir.join(&artifact), &stale)?;
+    std::fs::copy("./tests/authentication.wasm", path)?;
+    runtime.compile_cached(path)?;
+    assert_eq!(precompiled(&cache_dir)?, 2);
+    assert!(!stale.exists());
+    assert!(cache_dir.join(&artifact).exists());
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pooling() -> anyhow::Result<()> {
+    let mut args = HashMap::new();
+    args.insert("arg_uri".to_string(), "uri".to_string());
+    args.insert("arg_body".to_string(), "body".to_string());
+    args.insert("arg_secret".to_string(), "secret".to_string());
+
+    let mut config = EnvConfig::default();
+    config.set_pooling(PoolingConfig {
+        total_core_instances: 1,
+        ..PoolingConfig::default()
+    });
+    let wasm_runtime = Environment::new(config)?;
+    let runtime = wasm_runtime.runtime();
+    let module = Module::from_file(runtime.get_engine(), "./tests/authentication.wasm")?;
+
+    // the slot is given back once the invocation is done
+    for _ in 0..2 {
+        let result = runtime
+            .spawn(module.clone(), "authentication", args.clone())
+            .await?;
+        assert!(result.contains("Auth Forbidden!"));
+    }
+
+    let mut config = EnvConfig::default();
+    config.set_pooling(PoolingConfig {
+        total_core_instances: 0,
+        ..PoolingConfig::default()
+    });
+    let wasm_runtime = Environment::new(config)?;
+    let runtime = wasm_runtime.runtime();
+    let module = Module::from_file(runtime.get_engine(), "./tests/authentication.wasm")?;
+
+    let err = runtime
+        .spawn(module, "authentication", args)
+        .await
+        .err()
+        .unwrap();
+    let err = err.downcast_ref::<InvokeError>().unwrap();
+    assert!(matches!(err, InvokeError::PoolExhausted(_)));
+    assert!(err.is_retryable());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pre_initialize() -> anyhow::Result<()> {
+    let wasm_runtime = Environment::new(EnvConfig::default())?;
+    let runtime = wasm_runtime.runtime();
+
+    let wasm = wat::parse_str(
+        r#"
+        (module
+          (memory (export "memory") 1)
+          (global $counter (mut i32) (i32.const 0))
+          (func (export "wizer.initialize")
+            (global.set $counter (i32.const 42))
+            (memory.grow (i32.const 1))
+            drop
+            (i32.store (i32.const 70000) (i32.const 0x64636261)))
+          (func (export "counter") (result i32)
+            global.get $counter)
+          (data (i32.const 4096) "xyz"))
+        "#,
+    )?;
+    let initialized = runtime
+        .pre_initialize(&wasm, false, &FunctionConfig::default())
+        .await?;
+
+    let module = Module::new(runtime.get_engine(), &initialized)?;
+    assert!(module.get_export("wizer.initialize").is_none());
+
+    let mut store = wasmtime::Store::new(runtime.get_engine(), ());
+    store.set_fuel(u64::MAX)?;
+    store.set_epoch_deadline(u64::MAX / 2);
+    let instance = wasmtime::Instance::new_async(&mut store, &module, &[]).await?;
+    let counter = instance.get_typed_func::<(), i32>(&mut store, "counter")?;
+    assert_eq!(counter.call_async(&mut store, ()).await?, 42);
+
+    let memory = instance.get_memory(&mut store, "memory").unwrap();
+    assert_eq!(memory.size(&store), 2);
+    assert_eq!(&memory.data(&store)[4096..4099], b"xyz");
+    assert_eq!(&memory.data(&store)[70000..70004], b"abcd");
+
+    // the initialize export is required
+    let wasm = wat::parse_str("(module (memory 1))")?;
+    assert!(runtime
+        .pre_initialize(&wasm, false, &FunctionConfig::default())
+        .await
+        .is_err());
+
+    // the host keeps the contents of an imported memory
+    let wasm = wat::parse_str(
+        r#"
+        (module
+          (import "env" "memory" (memory 1))
+          (func (export "wizer.initialize")))
+        "#,
+    )?;
+    let err = runtime
+        .pre_initialize(&wasm, false, &FunctionConfig::default())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("importing a memory"));
+
+    // the initialization is bound like an invocation of the function
+    let wasm = wat::parse_str(
+        r#"
+        (module
+          (memory 1)
+          (func (export "wizer.initialize")
+            (if (i32.eq (memory.grow (i32.const 16)) (i32.const -1))
+              (then unreachable))
+            (loop $spin (br $spin))))
+        "#,
+    )?;
+    let mut func_config = FunctionConfig::default();
+    func_config.set_limits(ResourceLimits {
+        max_memory_bytes: Some(4 * 0x10000),
+        ..Default::default()
+    });
+    let err = runtime
+        .pre_initialize(&wasm, false, &func_config)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InvokeError>(),
+        Some(InvokeError::LimitExceeded(_))
+    ));
+
+    let mut func_config = FunctionConfig::default();
+    func_config.set_max_fuel(1);
+    let err = runtime
+        .pre_initialize(&wasm, false, &func_config)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InvokeError>(),
+        Some(InvokeError::FuelExhausted(1))
+    ));
+
+    let mut func_config = FunctionConfig::default();
+    func_config.set_timeout(Duration::from_millis(50));
+    let err = runtime
+        .pre_initialize(&wasm, true, &func_config)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InvokeError>(),
+        Some(InvokeError::Timeout(_))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn instance_reuse() -> anyhow::Result<()> {
+    let wasm_runtime = Environment::new(EnvConfig::default())?;
+    let runtime = wasm_runtime.runtime();
+    let module = Module::from_file(runtime.get_engine(), "./tests/authentication.wasm")?;
+    let module_wasi = Module::from_file(runtime.get_engine(), "./tests/authentication-wasi.wasm")?;
+
+    let policy = ReusePolicy {
+        pool_size: 1,
+        max_uses: Some(2),
+        reset_memory: true,
+    };
+    assert!(runtime.prepare(&module_wasi, true, Some(&policy)).is_err());
+    let pool = match runtime.prepare(&module, false, Some(&policy))? {
+        PreparedInstance::Pooled(pool) => pool,
+        _ => panic!("expect a pooled instance"),
+    };
+
+    let mut args = HashMap::new();
+    args.insert("arg_uri".to_string(), "uri".to_string());
+    args.insert("arg_body".to_string(), "body".to_string());
+
+    // the instance is dropped after its second use
+    for (secret, contains, idle) in [
+        ("32af198911cb4a9727dca0aaf9149020", "Auth Pass", 1),
+        ("secret", "Auth Forbidden!", 0),
+        ("32af198911cb4a9727dca0aaf9149020", "Auth Pass", 1),
+    ] {
+        let mut args = args.clone();
+        args.insert("arg_secret".to_string(), secret.to_string());
+        let result = runtime
+            .spawn_pooled(&pool, "authentication", args, &FunctionConfig::default())
+            .await?
+            .output;
+        assert!(
+            result.contains(contains),
+            "expect {}, get {}",
+            contains,
+            result
+        );
+        assert_eq!(pool.idle(), idle);
+    }
+
+    // exported mutable globals are restored along with the memory
+    let counter = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (global $count (export "count") (mut i32) (i32.const 0))
+          (func (export "next") (result i32)
+            (global.set $count (i32.add (global.get $count) (i32.const 1)))
+            global.get $count))
+        "#,
+    )?;
+    for (reset_memory, expect) in [(true, ["[1]", "[1]"]), (false, ["[1]", "[2]"])] {
+        let policy = ReusePolicy {
+            pool_size: 1,
+            max_uses: None,
+            reset_memory,
+        };
+        let PreparedInstance::Pooled(pool) = runtime.prepare(&counter, false, Some(&policy))?
+        else {
+            panic!("expect a pooled instance");
+        };
+        for expect in expect {
+            let invocation = runtime
+                .call_typed_pooled(&pool, "next", &[], &FunctionConfig::default())
+                .await?;
+            assert_eq!(invocation.output, expect);
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fuel() -> anyhow::Result<()> {
+    let mut config = EnvConfig::default();
+    config.set_max_fuel(Some(1));
+    let wasm_runtime = Environment::new(config)?;
+    let runtime = wasm_runtime.runtime();
+
+    let module = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (memory (export "memory") 1)
+          (global (export "__heap_base") i32 (i32.const 1024))
+          (func (export "spin") (param i32 i32) (result i32 i32)
+            (loop br 0)
+            unreachable))
+        "#,
+    )?;
+    let PreparedInstance::Plain(spin) = runtime.prepare(&module, false, None)? else {
+        panic!("expect a plain instance");
+    };
+
+    // the environment budget, overridden per function and capped per request
+    let mut overridden = FunctionConfig::default();
+    overridden.set_max_fuel(5);
+    let mut capped = overridden.clone();
+    capped.cap_fuel(3);
+
+    for (func_config, expect) in [(FunctionConfig::default(), 1), (overridden, 5), (capped, 3)] {
+        let err = runtime
+            .spawn_pre(&spin, "spin", HashMap::new(), &func_config)
+            .await
+            .err()
+            .unwrap();
+        match err.downcast_ref::<InvokeError>() {
+            Some(InvokeError::FuelExhausted(budget)) => assert_eq!(*budget, expect),
+            _ => panic!("expect fuel exhausted, get {:?}", err),
+        }
+    }
+
+    let module = Module::from_file(runtime.get_engine(), "./tests/authentication.wasm")?;
+    let PreparedInstance::Plain(authentication) = runtime.prepare(&module, false, None)? else {
+        panic!("expect a plain instance");
+    };
+    let mut func_config = FunctionConfig::default();
+    func_config.set_max_fuel(u64::MAX);
+    let mut args = HashMap::new();
+    args.insert("arg_uri".to_string(), "uri".to_string());
+    args.insert("arg_body".to_string(), "body".to_string());
+    args.insert("arg_secret".to_string(), "secret".to_string());
+    let invocation = runtime
+        .spawn_pre(&authentication, "authentication", args, &func_config)
+        .await?;
+    assert!(invocation.output.contains("Auth Forbidden!"));
+    assert!(invocation.fuel_consumed > 0);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn timeout() -> anyhow::Result<()> {
+    let mut config = EnvConfig::default();
+    config.set_timeout(Some(Duration::from_millis(500)));
+    let wasm_runtime = Environment::new(config)?;
+    let runtime = wasm_runtime.runtime();
+
+    let module = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (memory (export "memory") 1)
+          (global (export "__heap_base") i32 (i32.const 1024))
+          (func (export "spin") (param i32 i32) (result i32 i32)
+            (loop br 0)
+            unreachable))
+        "#,
+    )?;
+    let PreparedInstance::Plain(spin) = runtime.prepare(&module, false, None)? else {
+        panic!("expect a plain instance");
+    };
+
+    // the environment deadline, overridden per function and capped per request
+    let mut overridden = FunctionConfig::default();
+    overridden.set_timeout(Duration::from_millis(100));
+    let mut capped = overridden.clone();
+    capped.cap_timeout(Duration::from_millis(20));
+
+    for (func_config, expect) in [
+        (FunctionConfig::default(), 500),
+        (overridden, 100),
+        (capped, 20),
+    ] {
+        let err = runtime
+            .spawn_pre(&spin, "spin", HashMap::new(), &func_config)
+            .await
+            .err()
+            .unwrap();
+        match err.downcast_ref::<InvokeError>() {
+            Some(InvokeError::Timeout(timeout)) => {
+                assert_eq!(*timeout, Duration::from_millis(expect))
+            }
+            _ => panic!("expect timeout, get {:?}", err),
+        }
+    }
+
+    // a guest sleeping in a host call isn't reached by the epoch
+    let sleep = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (import "wasi_snapshot_preview1" "poll_oneoff"
+            (func $poll_oneoff (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (func (export "_start")
+            ;; one subscription to the monotonic clock, 10s from now
+            (i32.store (i32.const 16) (i32.const 1))
+            (i64.store (i32.const 24) (i64.const 10000000000))
+            (drop (call $poll_oneoff (i32.const 0) (i32.const 64) (i32.const 1) (i32.const 128)))))
+        "#,
+    )?;
+    let PreparedInstance::Wasi(sleep) = runtime.prepare(&sleep, true, None)? else {
+        panic!("expect a WASI instance");
+    };
+    let mut func_config = FunctionConfig::default();
+    func_config.set_timeout(Duration::from_millis(100));
+    let started = std::time::Instant::now();
+    let err = runtime
+        .with_deadline(
+            &func_config,
+            runtime.spawn_wasi_pre(&sleep, HashMap::new(), &func_config),
+        )
+        .await
+        .err()
+        .unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(matches!(
+        err.downcast_ref::<InvokeError>(),
+        Some(InvokeError::Timeout(_))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resource_limits() -> anyhow::Result<()> {
+    const PAGE: usize = 0x10000;
+    let mut config = EnvConfig::default();
+    config.set_limits(ResourceLimits {
+        max_memory_bytes: Some(3 * PAGE),
+        ..ResourceLimits::default()
+    });
+    let wasm_runtime = Environment::new(config)?;
+    let runtime = wasm_runtime.runtime();
+
+    // one page, one more for the arguments, then two grown by the export
+    let module = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (memory (export "memory") 1)
+          (global (export "__heap_base") i32 (i32.const 1024))
+          (func (export "grow") (param i32 i32) (result i32 i32)
+            (if (i32.eq (memory.grow (i32.const 2)) (i32.const -1))
+              (then unreachable))
+            (i32.const 0)
+            (i32.const 0)))
+        "#,
+    )?;
+    let PreparedInstance::Plain(grow) = runtime.prepare(&module, false, None)? else {
+        panic!("expect a plain instance");
+    };
+
+    let err = runtime
+        .spawn_pre(&grow, "grow", HashMap::new(), &FunctionConfig::default())
+        .await
+        .err()
+        .unwrap();
+    match err.downcast_ref::<InvokeError>() {
+        Some(InvokeError::LimitExceeded(reason)) => assert!(reason.contains("linear memory")),
+        _ => panic!("expect limit exceeded, get {:?}", err),
+    }
+
+    // the function limits win over the environment ones
+    let mut func_config = FunctionConfig::default();
+    func_config.set_limits(ResourceLimits {
+        max_memory_bytes: Some(4 * PAGE),
+        ..ResourceLimits::default()
+    });
+    let invocation = runtime
+        .spawn_pre(&grow, "grow", HashMap::new(), &func_config)
+        .await?;
+    assert_eq!(invocation.peak_memory, 4 * PAGE);
+
+    func_config.set_limits(ResourceLimits {
+        max_memory_bytes: Some(4 * PAGE),
+        max_instances: Some(0),
+        ..ResourceLimits::default()
+    });
+    let err = runtime
+        .spawn_pre(&grow, "grow", HashMap::new(), &func_config)
+        .await
+        .err()
+        .unwrap();
+    match err.downcast_ref::<InvokeError>() {
+        Some(InvokeError::LimitExceeded(reason)) => assert!(reason.contains("instance")),
+        _ => panic!("expect limit exceeded, get {:?}", err),
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn spawn_abi() -> anyhow::Result<()> {
+    let wasm_runtime = Environment::new(EnvConfig::default())?;
+    let runtime = wasm_runtime.runtime();
+
+    // a bump allocator counting the buffers freed, `echo` traps unless the
+    // two buffers of every previous invocation were freed
+    let allocator = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (global $calls (mut i32) (i32.const 0))
+          (global $freed (mut i32) (i32.const 0))
+          (func $alloc (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (local.get $ptr) (local.get $len)))
+            (if (i32.gt_u (global.get $next) (i32.shl (memory.size) (i32.const 16)))
+              (then
+                (drop (memory.grow
+                  (i32.add
+                    (i32.shr_u
+                      (i32.sub (global.get $next) (i32.shl (memory.size) (i32.const 16)))
+                      (i32.const 16))
+                    (i32.const 1))))))
+            (local.get $ptr))
+          (func (export "dealloc") (param i32 i32)
+            (global.set $freed (i32.add (global.get $freed) (i32.const 1))))
+          (func (export "echo") (param $ptr i32) (param $len i32) (result i32 i32)
+            (local $out i32)
+            (if (i32.ne (global.get $freed) (i32.shl (global.get $calls) (i32.const 1)))
+              (then unreachable))
+            (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+            (local.set $out (call $alloc (local.get $len)))
+            (memory.copy (local.get $out) (local.get $ptr) (local.get $len))
+            (local.get $out)
+            (local.get $len)))
+        "#,
+    )?;
+    // the legacy ABI, the arguments are echoed from `__heap_base`
+    let legacy = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (memory (export "memory") 1)
+          (global (export "__heap_base") i32 (i32.const 1024))
+          (func (export "echo") (param i32 i32) (result i32 i32)
+            (local.get 0)
+            (local.get 1)))
+        "#,
+    )?;
+
+    // arguments and output well past a wasm page
+    let mut args = HashMap::new();
+    args.insert("arg".to_string(), "x".repeat(200_000));
+    let expect = serde_json::to_string(&args)?;
+
+    let policy = ReusePolicy {
+        pool_size: 1,
+        ..ReusePolicy::default()
+    };
+    let PreparedInstance::Pooled(pool) = runtime.prepare(&allocator, false, Some(&policy))? else {
+        panic!("expect a pooled instance");
+    };
+    for _ in 0..3 {
+        let invocation = runtime
+            .spawn_pooled(&pool, "echo", args.clone(), &FunctionConfig::default())
+            .await?;
+        assert_eq!(invocation.output, expect);
+    }
+
+    let PreparedInstance::Plain(legacy) = runtime.prepare(&legacy, false, None)? else {
+        panic!("expect a plain instance");
+    };
+    let invocation = runtime
+        .spawn_pre(&legacy, "echo", args.clone(), &FunctionConfig::default())
+        .await?;
+    assert_eq!(invocation.output, expect);
+
+    // the output read before its buffers are freed, here the input buffer
+    // itself that `dealloc` clobbers and must only free once
+    let in_place = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (memory (export "memory") 8)
+          (global $freed (mut i32) (i32.const -1))
+          (func (export "alloc") (param i32) (result i32)
+            (i32.const 1024))
+          (func (export "dealloc") (param $ptr i32) (param $len i32)
+            (if (i32.eq (global.get $freed) (local.get $ptr))
+              (then unreachable))
+            (global.set $freed (local.get $ptr))
+            (memory.fill (local.get $ptr) (i32.const 0) (local.get $len)))
+          (func (export "echo") (param i32 i32) (result i32 i32)
+            (local.get 0)
+            (local.get 1)))
+        "#,
+    )?;
+    // an allocator of a language runtime, not of the spawn ABI
+    let foreign = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (memory (export "memory") 1)
+          (global (export "__heap_base") i32 (i32.const 1024))
+          (func (export "alloc") (param i64) (result i64)
+            unreachable)
+          (func (export "echo") (param i32 i32) (result i32 i32)
+            (local.get 0)
+            (local.get 1)))
+        "#,
+    )?;
+    assert_eq!(runtime.inspect(&foreign, "echo")?, FunctionKind::Export);
+    for module in [in_place, foreign] {
+        let PreparedInstance::Plain(module) = runtime.prepare(&module, false, None)? else {
+            panic!("expect a plain instance");
+        };
+        let invocation = runtime
+            .spawn_pre(&module, "echo", args.clone(), &FunctionConfig::default())
+            .await?;
+        assert_eq!(invocation.output, expect);
+    }
+
+    // without `dealloc` the buffers are only dropped with a memory reset
+    let bump = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (memory (export "memory") 8)
+          (global $next (export "next") (mut i32) (i32.const 1024))
+          (func (export "alloc") (param $len i32) (result i32)
+            (global.get $next)
+            (global.set $next (i32.add (global.get $next) (local.get $len))))
+          (func (export "echo") (param i32 i32) (result i32 i32)
+            (local.get 0)
+            (local.get 1)))
+        "#,
+    )?;
+    assert!(runtime.prepare(&bump, false, Some(&policy)).is_err());
+    let policy = ReusePolicy {
+        reset_memory: true,
+        ..policy
+    };
+    let PreparedInstance::Pooled(pool) = runtime.prepare(&bump, false, Some(&policy))? else {
+        panic!("expect a pooled instance");
+    };
+    for _ in 0..3 {
+        let invocation = runtime
+            .spawn_pooled(&pool, "echo", args.clone(), &FunctionConfig::default())
+            .await?;
+        assert_eq!(invocation.output, expect);
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn typed_call() -> anyhow::Result<()> {
+    let wasm_runtime = Environment::new(EnvConfig::default())?;
+    let runtime = wasm_runtime.runtime();
+
+    let gcd = Module::from_file(runtime.get_engine(), "./benches/gcd.wat")?;
+    let PreparedInstance::Plain(gcd) = runtime.prepare(&gcd, false, None)? else {
+        panic!("expect a plain instance");
+    };
+    let func_config = FunctionConfig::default();
+    let invocation = runtime
+        .call_typed_pre(&gcd, "gcd", &[json!(12), json!(18)], &func_config)
+        .await?;
+    assert_eq!(invocation.output, "[6]");
+
+    // parameters not matching the export type
+    for params in [
+        vec![json!(12)],
+        vec![json!(12), json!(1.5)],
+        vec![json!(12), json!("18")],
+    ] {
+        assert!(runtime
+            .call_typed_pre(&gcd, "gcd", &params, &func_config)
+            .await
+            .is_err());
+    }
+    assert!(runtime
+        .call_typed_pre(&gcd, "lcm", &[json!(12), json!(18)], &func_config)
+        .await
+        .is_err());
+
+    let identity = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (func (export "identity") (param i32 i64 f32 f64 v128 externref)
+            (result i32 i64 f32 f64 v128 externref)
+            (local.get 0) (local.get 1) (local.get 2)
+            (local.get 3) (local.get 4) (local.get 5)))
+        "#,
+    )?;
+    let PreparedInstance::Plain(identity) = runtime.prepare(&identity, false, None)? else {
+        panic!("expect a plain instance");
+    };
+    let params = [
+        json!(u32::MAX),
+        json!("-9007199254740993"),
+        json!(1.5),
+        json!("-inf"),
+        json!("0x000102030405060708090a0b0c0d0e0f"),
+        json!(null),
+    ];
+    let invocation = runtime
+        .call_typed_pre(&identity, "identity", &params, &func_config)
+        .await?;
+    let results: serde_json::Value = serde_json::from_str(&invocation.output)?;
+    assert_eq!(
+        results,
+        json!([
+            -1,
+            -9007199254740993i64,
+            1.5,
+            "-inf",
+            "0x000102030405060708090a0b0c0d0e0f",
+            null
+        ])
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn component() -> anyhow::Result<()> {
+    let wasm_runtime = Environment::new(EnvConfig::default())?;
+    let runtime = wasm_runtime.runtime();
+
+    let wasm = wat::parse_str(
+        r#"
+        (component
+          (core module $m
+            (memory (export "memory") 1)
+            (func (export "add") (param i32 i32) (result i32)
+              (i32.add (local.get 0) (local.get 1)))
+            ;; none for odd numbers, some half otherwise
+            (func (export "half") (param i32) (result i32)
+              (i32.store8 (i32.const 16)
+                (i32.eqz (i32.and (local.get 0) (i32.const 1))))
+              (i32.store (i32.const 20) (i32.div_s (local.get 0) (i32.const 2)))
+              (i32.const 16))
+            (func (export "run") (result i32) (i32.const 0)))
+          (core instance $i (instantiate $m))
+          (alias core export $i "memory" (core memory $memory))
+          (func (export "add") (param "a" u32) (param "b" u32) (result u32)
+            (canon lift (core func $i "add")))
+          (func (export "half") (param "n" s32) (result (option s32))
+            (canon lift (core func $i "half") (memory $memory)))
+          (func $run (result (result))
+            (canon lift (core func $i "run")))
+          (instance $cli (export "run" (func $run)))
+          (export "wasi:cli/run@0.2.0" (instance $cli)))
+        "#,
+    )?;
+    let dir = std::env::temp_dir().join("wasmengine-test-component");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("component.wasm");
+    std::fs::write(&path, wasm)?;
+
+    let compiled = runtime.compile(path.to_str().unwrap())?;
+    assert_eq!(
+        runtime.inspect_compiled(&compiled, "component")?,
+        FunctionKind::Component
+    );
+    let PreparedInstance::Component(component) = runtime.prepare_compiled(&compiled, true, None)?
+    else {
+        panic!("expect a component instance");
+    };
+
+    let func_config = FunctionConfig::default();
+    let invocation = runtime
+        .call_component(&component, "add", &[json!(2), json!(40)], &func_config)
+        .await?;
+    assert_eq!(invocation.output, "[42]");
+    let invocation = runtime
+        .call_component(&component, "half", &[json!(8)], &func_config)
+        .await?;
+    assert_eq!(invocation.output, "[4]");
+    let invocation = runtime
+        .call_component(&component, "half", &[json!(7)], &func_config)
+        .await?;
+    assert_eq!(invocation.output, "[null]");
+    let invocation = runtime
+        .call_component(&component, "wasi:cli/run@0.2.0#run", &[], &func_config)
+        .await?;
+    assert_eq!(invocation.output, r#"[{"ok":null}]"#);
+
+    // parameters not matching the export type
+    for params in [vec![json!(2)], vec![json!(2), json!(-1)]] {
+        assert!(runtime
+            .call_component(&component, "add", &params, &func_config)
+            .await
+            .is_err());
+    }
+    assert!(runtime
+        .call_component(&component, "sub", &[json!(2), json!(1)], &func_config)
+        .await
+        .is_err());
+
+    let invocation = runtime
+        .run_component(&component, HashMap::new(), &func_config)
+        .await?;
+    assert_eq!(invocation.output, "");
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn http_proxy() -> anyhow::Result<()> {
+    let wasm_runtime = Environment::new(EnvConfig::default())?;
+    let runtime = wasm_runtime.runtime();
+
+    // answers every request with 418 and an empty body
+    let proxy = wat::parse_str(
+        r#"
+        (component
+          (import "wasi:http/types@0.2.0" (instance $types
+            (export $fields "fields" (type (sub resource)))
+            (export "incoming-request" (type (sub resource)))
+            (export $response "outgoing-response" (type (sub resource)))
+            (export $outparam "response-outparam" (type (sub resource)))
+            (type $dns (record (field "rcode" (option string)) (field "info-code" (option u16))))
+            (export $dns' "DNS-error-payload" (type (eq $dns)))
+            (type $tls (record
+              (field "alert-id" (option u8)) (field "alert-message" (option string))))
+            (export $tls' "TLS-alert-received-payload" (type (eq $tls)))
+            (type $size (record
+              (field "field-name" (option string)) (field "field-size" (option u32))))
+            (export $size' "field-size-payload" (type (eq $size)))
+            (type $error (variant
+              (case "DNS-timeout")
+              (case "DNS-error" $dns')
+              (case "destination-not-found")
+              (case "destination-unavailable")
+              (case "destination-IP-prohibited")
+              (case "destination-IP-unroutable")
+              (case "connection-refused")
+              (case "connection-terminated")
+              (case "connection-timeout")
+              (case "connection-read-timeout")
+              (case "connection-write-timeout")
+              (case "connection-limit-reached")
+              (case "TLS-protocol-error")
+              (case "TLS-certificate-error")
+              (case "TLS-alert-received" $tls')
+              (case "HTTP-request-denied")
+              (case "HTTP-request-length-required")
+              (case "HTTP-request-body-size" (option u64))
+              (case "HTTP-request-method-invalid")
+              (case "HTTP-request-URI-invalid")
+              (case "HTTP-request-URI-too-long")
+              (case "HTTP-request-header-section-size" (option u32))
+              (case "HTTP-request-header-size" (option $size'))
+              (case "HTTP-request-trailer-section-size" (option u32))
+              (case "HTTP-request-trailer-size" $size')
+              (case "HTTP-response-incomplete")
+              (case "HTTP-response-header-section-size" (option u32))
+              (case "HTTP-response-header-size" $size')
+              (case "HTTP-response-body-size" (option u64))
+              (case "HTTP-response-trailer-section-size" (option u32))
+              (case "HTTP-response-trailer-size" $size')
+              (case "HTTP-response-transfer-coding" (option string))
+              (case "HTTP-response-content-coding" (option string))
+              (case "HTTP-response-timeout")
+              (case "HTTP-upgrade-failed")
+              (case "HTTP-protocol-error")
+              (case "loop-detected")
+              (case "configuration-error")
+              (case "internal-error" (option string))))
+            (export $error' "error-code" (type (eq $error)))
+            (export "[constructor]fields" (func (result (own $fields))))
+            (export "[constructor]outgoing-response"
+              (func (param "headers" (own $fields)) (result (own $response))))
+            (export "[method]outgoing-response.set-status-code"
+              (func (param "self" (borrow $response)) (param "status-code" u16) (result (result))))
+            (export "[static]response-outparam.set"
+              (func (param "param" (own $outparam))
+                (param "response" (result (own $response) (error $error')))))))
+          (alias export $types "incoming-request" (type $request))
+          (alias export $types "response-outparam" (type $outparam))
+
+          (core module $mem (memory (export "memory") 1))
+          (core instance $mem (instantiate $mem))
+          (alias core export $mem "memory" (core memory $memory))
+          (core func $new_fields (canon lower (func $types "[constructor]fields")))
+          (core func $new_response (canon lower (func $types "[constructor]outgoing-response")))
+          (core func $set_status
+            (canon lower (func $types "[method]outgoing-response.set-status-code")))
+          (core func $set
+            (canon lower (func $types "[static]response-outparam.set") (memory $memory)))
+
+          (core module $m
+            (import "types" "new-fields" (func $new_fields (result i32)))
+            (import "types" "new-response" (func $new_response (param i32) (result i32)))
+            (import "types" "set-status" (func $set_status (param i32 i32) (result i32)))
+            (import "types" "set" (func $set (param i32 i32 i32 i32 i64 i32 i32 i32 i32)))
+            (func (export "handle") (param $request i32) (param $outparam i32)
+              (local $response i32)
+              (local.set $response (call $new_response (call $new_fields)))
+              (drop (call $set_status (local.get $response) (i32.const 418)))
+              (call $set (local.get $outparam) (i32.const 0) (local.get $response) (i32.const 0)
+                (i64.const 0) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0))))
+          (core instance $i (instantiate $m
+            (with "types" (instance
+              (export "new-fields" (func $new_fields))
+              (export "new-response" (func $new_response))
+              (export "set-status" (func $set_status))
+              (export "set" (func $set))))))
+          (func $handle (param "request" (own $request)) (param "response-out" (own $outparam))
+            (canon lift (core func $i "handle")))
+          (instance $handler (export "handle" (func $handle)))
+          (export "wasi:http/incoming-handler@0.2.0" (instance $handler)))
+        "#,
+    )?;
+    let dir = std::env::temp_dir().join("wasmengine-test-http-proxy");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("proxy.wasm");
+    std::fs::write(&path, proxy)?;
+
+    let compiled = runtime.compile(path.to_str().unwrap())?;
+    assert_eq!(
+        runtime.inspect_compiled(&compiled, "proxy")?,
+        FunctionKind::HttpProxy
+    );
+    let PreparedInstance::HttpProxy(proxy) = runtime.prepare_compiled(&compiled, true, None)?
+    else {
+        panic!("expect a wasi:http proxy instance");
+    };
+
+    let request = hyper::Request::builder()
+        .uri("http://localhost/teapot")
+        .body(Empty::new().map_err(|_| unreachable!()).boxed())?;
+    let (response, handled) = runtime
+        .serve_http(&proxy, request, &FunctionConfig::default())
+        .await?;
+    assert_eq!(response.status(), 418);
+    assert!(response.into_body().collect().await?.to_bytes().is_empty());
+    assert!(handled.await??.fuel_consumed > 0);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn wasi_exit() -> anyhow::Result<()> {
+    let wat = r#"
+        (module
+          (import "wasi_snapshot_preview1" "fd_write"
+            (func $fd_write (param i32 i32 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 16) "failed\n")
+          (func (export "_start")
+            (i32.store (i32.const 0) (i32.const 16))
+            (i32.store (i32.const 4) (i32.const 7))
+            (drop (call $fd_write (i32.const 2) (i32.const 0) (i32.const 1) (i32.const 8)))
+            (call $proc_exit (i32.const 3))))
+        "#;
+
+    let mut config = EnvConfig::default();
+    for (max_stderr_bytes, stderr) in [(1024, "failed\n"), (4, "fail...")] {
+        config.set_max_stderr_bytes(max_stderr_bytes);
+        let wasm_runtime = Environment::new(config.clone())?;
+        let runtime = wasm_runtime.runtime();
+        let module = Module::new(runtime.get_engine(), wat)?;
+        let PreparedInstance::Wasi(exit) = runtime.prepare(&module, true, None)? else {
+            panic!("expect a WASI instance");
+        };
+
+        let invocation = runtime
+            .spawn_wasi_pre(&exit, HashMap::new(), &FunctionConfig::default())
+            .await?;
+        assert_eq!(invocation.exit_code, Some(3));
+        assert_eq!(invocation.stderr.as_deref(), Some(stderr));
+    }
+
+    // stdout past `max_output_bytes` fails the invocation
+    let wat = wat.replace("(i32.const 2) (i32.const 0)", "(i32.const 1) (i32.const 0)");
+    for (max_output_bytes, output) in [(1024, Some("failed")), (4, None)] {
+        config.set_max_output_bytes(max_output_bytes);
+        let wasm_runtime = Environment::new(config.clone())?;
+        let runtime = wasm_runtime.runtime();
+        let module = Module::new(runtime.get_engine(), &wat)?;
+        let PreparedInstance::Wasi(exit) = runtime.prepare(&module, true, None)? else {
+            panic!("expect a WASI instance");
+        };
+
+        let result = runtime
+            .spawn_wasi_pre(&exit, HashMap::new(), &FunctionConfig::default())
+            .await;
+        match output {
+            Some(output) => assert_eq!(result?.output, output),
+            None => assert!(matches!(
+                result.err().unwrap().downcast_ref::<InvokeError>(),
+                Some(InvokeError::LimitExceeded(reason)) if reason.contains("stdout")
+            )),
+        }
+    }
+
+    // a module returning from `_start` exits with 0
+    let wasm_runtime = Environment::new(EnvConfig::default())?;
+    let runtime = wasm_runtime.runtime();
+    let module = Module::from_file(runtime.get_engine(), "./tests/authentication-wasi.wasm")?;
+    let PreparedInstance::Wasi(authentication) = runtime.prepare(&module, true, None)? else {
+        panic!("expect a WASI instance");
+    };
+    let mut args = HashMap::new();
+    args.insert("arg_uri".to_string(), "uri".to_string());
+    args.insert("arg_body".to_string(), "body".to_string());
+    args.insert("arg_secret".to_string(), "secret".to_string());
+    let invocation = runtime
+        .spawn_wasi_pre(&authentication, args, &FunctionConfig::default())
+        .await?;
+    assert_eq!(invocation.exit_code, Some(0));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn read_only_dir() -> anyhow::Result<()> {
+    let wasm_runtime = Environment::new(EnvConfig::default())?;
+    let runtime = wasm_runtime.runtime();
+
+    // opens `data.txt` for reading then creates `new.txt` in the first
+    // preopened directory, exits with the errno of both as `read * 1000 + create`
+    let module = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (import "wasi_snapshot_preview1" "path_open"
+            (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 16) "data.txt")
+          (data (i32.const 32) "new.txt")
+          (func (export "_start")
+            (call $proc_exit
+              (i32.add
+                (i32.mul
+                  (call $path_open (i32.const 3) (i32.const 0) (i32.const 16) (i32.const 8)
+                    (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 0))
+                  (i32.const 1000))
+                (call $path_open (i32.const 3) (i32.const 0) (i32.const 32) (i32.const 7)
+                  (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 0))))))
+        "#,
+    )?;
+    let PreparedInstance::Wasi(open) = runtime.prepare(&module, true, None)? else {
+        panic!("expect a WASI instance");
+    };
+
+    let dir = std::env::temp_dir().join("wasmengine-test-read-only-dir");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("data.txt"), "data")?;
+    let host_dir = dir.to_str().unwrap();
+
+    // 63 is EPERM
+    let mut func_config = FunctionConfig::default();
+    func_config.preopen_read_only_dir_as(host_dir, "/data");
+    let invocation = runtime
+        .spawn_wasi_pre(&open, HashMap::new(), &func_config)
+        .await?;
+    assert_eq!(invocation.exit_code, Some(63));
+    assert!(!dir.join("new.txt").exists());
+
+    let mut func_config = FunctionConfig::default();
+    func_config.preopen_dir_as(host_dir, "/data");
+    let invocation = runtime
+        .spawn_wasi_pre(&open, HashMap::new(), &func_config)
+        .await?;
+    assert_eq!(invocation.exit_code, Some(0));
+    assert!(dir.join("new.txt").exists());
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn wasi_input() -> anyhow::Result<()> {
+    let wasm_runtime = Environment::new(EnvConfig::default())?;
+    let runtime = wasm_runtime.runtime();
+
+    // prints argv and the environment one per line, then copies stdin
+    let module = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (import "wasi_snapshot_preview1" "args_sizes_get"
+            (func $args_sizes_get (param i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "args_get"
+            (func $args_get (param i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "environ_sizes_get"
+            (func $environ_sizes_get (param i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "environ_get"
+            (func $environ_get (param i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "fd_read"
+            (func $fd_read (param i32 i32 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "fd_write"
+            (func $fd_write (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          ;; write `len` bytes at `ptr` to stdout, NULs as new lines
+          (func $print (param $ptr i32) (param $len i32)
+            (local $i i32)
+            (block $done
+              (loop $next
+                (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+                (if (i32.eqz (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+                  (then (i32.store8 (i32.add (local.get $ptr) (local.get $i)) (i32.const 10))))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $next)))
+            (i32.store (i32.const 32) (local.get $ptr))
+            (i32.store (i32.const 36) (local.get $len))
+            (drop (call $fd_write (i32.const 1) (i32.const 32) (i32.const 1) (i32.const 40))))
+          (func (export "_start")
+            (drop (call $args_sizes_get (i32.const 0) (i32.const 4)))
+            (drop (call $args_get (i32.const 1024) (i32.const 2048)))
+            (call $print (i32.const 2048) (i32.load (i32.const 4)))
+            (drop (call $environ_sizes_get (i32.const 8) (i32.const 12)))
+            (drop (call $environ_get (i32.const 1536) (i32.const 4096)))
+            (call $print (i32.const 4096) (i32.load (i32.const 12)))
+            (i32.store (i32.const 48) (i32.const 8192))
+            (i32.store (i32.const 52) (i32.const 4096))
+            (drop (call $fd_read (i32.const 0) (i32.const 48) (i32.const 1) (i32.const 16)))
+            (call $print (i32.const 8192) (i32.load (i32.const 16)))))
+        "#,
+    )?;
+    let PreparedInstance::Wasi(echo) = runtime.prepare(&module, true, None)? else {
+        panic!("expect a WASI instance");
+    };
+    let mut args = HashMap::new();
+    args.insert("a".to_string(), "1".to_string());
+
+    for (wasi_input, input, expect) in [
+        (
+            WasiInput::Stdin,
+            None,
+            r#"echo
+{"a":"1"}"#,
+        ),
+        (WasiInput::Stdin, Some("raw"), "echo\nraw"),
+        (
+            WasiInput::Argv {
+                template: vec!["echo".to_string(), "-a".to_string(), "{a}!".to_string()],
+            },
+            Some(""),
+            "echo\n-a\n1!",
+        ),
+        // stdin is empty without input
+        (
+            WasiInput::Argv {
+                template: vec!["echo".to_string()],
+            },
+            None,
+            "echo",
+        ),
+        (
+            WasiInput::Env {
+                prefix: "ARG_".to_string(),
+            },
+            Some(""),
+            "echo\nARG_a=1",
+        ),
+    ] {
+        wasi_input.validate()?;
+        let mut func_config = FunctionConfig::default();
+        func_config.set_wasi_input(wasi_input);
+        func_config.set_program_name("echo");
+        if let Some(input) = input {
+            func_config.set_input(input.as_bytes().to_vec());
+        }
+        let invocation = runtime
+            .spawn_wasi_pre(&echo, args.clone(), &func_config)
+            .await?;
+        assert_eq!(invocation.output, expect);
+    }
+
+    // templates without program name, unclosed or of missing arguments
+    for template in [vec![], vec!["echo".to_string(), "{a".to_string()]] {
+        assert!(WasiInput::Argv { template }.validate().is_err());
+    }
+    let mut func_config = FunctionConfig::default();
+    func_config.set_wasi_input(WasiInput::Argv {
+        template: vec!["echo".to_string(), "{b}".to_string()],
+    });
+    func_config.set_input(Vec::new());
+    assert!(runtime
+        .spawn_wasi_pre(&echo, args, &func_config)
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn wasi_reactor() -> anyhow::Result<()> {
+    let wasm_runtime = Environment::new(EnvConfig::default())?;
+    let runtime = wasm_runtime.runtime();
+
+    // `echo` traps unless `_initialize` ran first
+    let reactor = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (import "wasi_snapshot_preview1" "fd_write"
+            (func $fd_write (param i32 i32 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "args_sizes_get"
+            (func $args_sizes_get (param i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+          (memory (export "memory") 1)
+          (global (export "__heap_base") i32 (i32.const 1024))
+          (global $ready (mut i32) (i32.const 0))
+          (data (i32.const 16) "ready\n")
+          (data (i32.const 32) "bye\n")
+          (func (export "_initialize")
+            (i32.store (i32.const 0) (i32.const 16))
+            (i32.store (i32.const 4) (i32.const 6))
+            (drop (call $fd_write (i32.const 2) (i32.const 0) (i32.const 1) (i32.const 8)))
+            (global.set $ready (i32.const 1)))
+          (func (export "echo") (param i32 i32) (result i32 i32)
+            (if (i32.eqz (global.get $ready))
+              (then unreachable))
+            (local.get 0)
+            (local.get 1))
+          (func (export "ready") (result i32)
+            (global.get $ready))
+          (func (export "argc") (result i32)
+            (drop (call $args_sizes_get (i32.const 40) (i32.const 44)))
+            (i32.load (i32.const 40)))
+          (func (export "exit") (param i32)
+            (i32.store (i32.const 0) (i32.const 32))
+            (i32.store (i32.const 4) (i32.const 4))
+            (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+            (call $proc_exit (local.get 0))))
+        "#,
+    )?;
+    assert_eq!(
+        runtime.inspect(&reactor, "echo")?,
+        FunctionKind::WasiReactor
+    );
+    assert_eq!(
+        runtime.inspect(&reactor, "ready")?,
+        FunctionKind::WasiReactor
+    );
+    assert!(runtime.inspect(&reactor, "missing").is_err());
+
+    let PreparedInstance::Wasi(reactor) = runtime.prepare(&reactor, true, None)? else {
+        panic!("expect a WASI instance");
+    };
+    let mut args = HashMap::new();
+    args.insert("a".to_string(), "1".to_string());
+    let invocation = runtime
+        .spawn_reactor_pre(&reactor, "echo", args.clone(), &FunctionConfig::default())
+        .await?;
+    assert_eq!(invocation.output, serde_json::to_string(&args)?);
+    assert_eq!(invocation.exit_code, Some(0));
+    assert_eq!(invocation.stderr.as_deref(), Some("ready\n"));
+
+    let invocation = runtime
+        .call_typed_reactor_pre(&reactor, "ready", &[], &FunctionConfig::default())
+        .await?;
+    assert_eq!(invocation.output, json!([1]).to_string());
+
+    let invocation = runtime
+        .call_typed_reactor_pre(&reactor, "argc", &[], &FunctionConfig::default())
+        .await?;
+    assert_eq!(invocation.output, json!([1]).to_string());
+
+    // a guest exiting returns what it wrote to stdout
+    let invocation = runtime
+        .call_typed_reactor_pre(&reactor, "exit", &[json!(2)], &FunctionConfig::default())
+        .await?;
+    assert_eq!(invocation.exit_code, Some(2));
+    assert_eq!(invocation.output, "bye");
+
+    // `_initialize` exiting with 0 still runs the export
+    let exiting = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+          (memory (export "memory") 1)
+          (func (export "_initialize")
+            (call $proc_exit (i32.const 0)))
+          (func (export "seven") (result i32)
+            (i32.const 7)))
+        "#,
+    )?;
+    let PreparedInstance::Wasi(exiting) = runtime.prepare(&exiting, true, None)? else {
+        panic!("expect a WASI instance");
+    };
+    let invocation = runtime
+        .call_typed_reactor_pre(&exiting, "seven", &[], &FunctionConfig::default())
+        .await?;
+    assert_eq!(invocation.output, json!([7]).to_string());
+    assert_eq!(invocation.exit_code, Some(0));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn guest_log() -> anyhow::Result<()> {
+    let wasm_runtime = Environment::new(EnvConfig::default())?;
+    let runtime = wasm_runtime.runtime();
+
+    let logger = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (import "wasm_engine" "log" (func $log (param i32 i32 i32)))
+          (import "env" "log" (func $env_log (param i32 i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 16) "hello")
+          (func (export "hello") (result i32)
+            (call $log (i32.const 2) (i32.const 16) (i32.const 5))
+            (call $env_log (i32.const 4) (i32.const 16) (i32.const 4))
+            (i32.const 0)))
+        "#,
+    )?;
+    assert_eq!(runtime.inspect(&logger, "hello")?, FunctionKind::Typed);
+    let expect = vec![
+        LogLine {
+            level: LogLevel::Info,
+            message: "hello".to_string(),
+        },
+        LogLine {
+            level: LogLevel::Error,
+            message: "hell".to_string(),
+        },
+    ];
+
+    let mut func_config = FunctionConfig::default();
+    func_config.set_function_name("logger");
+    func_config.set_invocation_id(1);
+    let PreparedInstance::Plain(plain) = runtime.prepare(&logger, false, None)? else {
+        panic!("expect a plain instance");
+    };
+    let invocation = runtime
+        .call_typed_pre(&plain, "hello", &[], &func_config)
+        .await?;
+    assert!(invocation.logs.is_empty());
+
+    func_config.set_capture_logs(true);
+    let invocation = runtime
+        .call_typed_pre(&plain, "hello", &[], &func_config)
+        .await?;
+    assert_eq!(invocation.logs, expect);
+    // pooled instances only return the lines of the current invocation
+    let policy = ReusePolicy {
+        pool_size: 1,
+        ..ReusePolicy::default()
+    };
+    let PreparedInstance::Pooled(pool) = runtime.prepare(&logger, false, Some(&policy))? else {
+        panic!("expect a pooled instance");
+    };
+    for _ in 0..2 {
+        let invocation = runtime
+            .call_typed_pooled(&pool, "hello", &[], &func_config)
+            .await?;
+        assert_eq!(invocation.logs, expect);
+    }
+
+    // WASI modules get the logging function too
+    let command = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (import "wasm_engine" "log" (func $log (param i32 i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 16) "bye")
+          (func (export "_start")
+            (call $log (i32.const 3) (i32.const 16) (i32.const 3))))
+        "#,
+    )?;
+    assert_eq!(
+        runtime.inspect(&command, "command")?,
+        FunctionKind::WasiCommand
+    );
+    let PreparedInstance::Wasi(command) = runtime.prepare(&command, true, None)? else {
+        panic!("expect a WASI instance");
+    };
+    let invocation = runtime
+        .spawn_wasi_pre(&command, HashMap::new(), &func_config)
+        .await?;
+    assert_eq!(
+        invocation.logs,
+        vec![LogLine {
+            level: LogLevel::Warn,
+            message: "bye".to_string(),
+        }]
+    );
+
+    // lines are cut to 4096 bytes and their control characters escaped
+    let noisy = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (import "wasm_engine" "log" (func $log (param i32 i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 16) "a\nb\1b[2J")
+          (func (export "noisy") (result i32)
+            (call $log (i32.const 2) (i32.const 16) (i32.const 7))
+            (memory.fill (i32.const 1024) (i32.const 0x78) (i32.const 8000))
+            (call $log (i32.const 2) (i32.const 1024) (i32.const 8000))
+            (i32.const 0)))
+        "#,
+    )?;
+    let PreparedInstance::Plain(noisy) = runtime.prepare(&noisy, false, None)? else {
+        panic!("expect a plain instance");
+    };
+    let invocation = runtime
+        .call_typed_pre(&noisy, "noisy", &[], &func_config)
+        .await?;
+    assert_eq!(invocation.logs[0].message, "a\\nb\\u{1b}[2J");
+    assert_eq!(invocation.logs[1].message, "x".repeat(4096));
+
+    // other imports of plain modules and mismatched signatures are rejected
+    let invalid = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (import "wasm_engine" "log" (func (param i32)))
+          (memory (export "memory") 1)
+          (func (export "hello") (result i32) (i32.const 0)))
+        "#,
+    )?;
+    assert!(runtime.inspect(&invalid, "hello").is_err());
+    let invalid = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (import "env" "print" (func (param i32 i32)))
+          (memory (export "memory") 1)
+          (func (export "hello") (result i32) (i32.const 0)))
+        "#,
+    )?;
+    assert!(runtime.inspect(&invalid, "hello").is_err());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn kv() -> anyhow::Result<()> {
+    let wasm_runtime = Environment::new(EnvConfig::default())?;
+    let runtime = wasm_runtime.runtime();
+
+    let counter = Module::new(
+        runtime.get_engine(),
+        r#"
+        (module
+          (import "wasm_engine" "kv_get" (func $get (param i32 i32 i32 i32) (result i32)))
+          (import "wasm_engine" "kv_set" (func $set (param i32 i32 i32 i32) (result i32)))
+          (import "wasm_engine" "kv_delete" (func $delete (param i32 i32) (result i32)))
+          (import "wasm_engine" "kv_list" (func $list (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "count")
+          (data (i32.const 8) "big")
+          ;; increment the counter, returns its new value
+          (func (export "incr") (result i32)
+            (if (i32.lt_s (call $get (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 4))
+                          (i32.const 0))
+              (then (i32.store (i32.const 16) (i32.const 0))))
+            (i32.store (i32.const 16) (i32.add (i32.load (i32.const 16)) (i32.const 1)))
+            (drop (call $set (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 4)))
+            (i32.load (i32.const 16)))
+          (func (export "big") (param $len i32) (result i32)
+            (call $set (i32.const 8) (i32.const 3) (i32.const 1024) (local.get $len)))
+          (func (export "list") (result i32)
+            (call $list (i32.const 0) (i32.const 0) (i32.const 32) (i32.const 64)))
+          (func (export "reset") (result i32)
+            (call $delete (i32.const 0) (i32.const 5))))
+        "#,
+    )?;
+    assert_eq!(runtime.inspect(&counter, "incr")?, FunctionKind::Typed);
+    let PreparedInstance::Plain(counter) = runtime.prepare(&counter, false, None)? else {
+        panic!("expect a plain instance");
+    };
+
+    let call = |export: &'static str, params: Vec<serde_json::Value>, config: FunctionConfig| {
+        let counter = counter.clone();
+        async move {
+            runtime
+                .call_typed_pre(&counter, export, &params, &config)
+                .await
+                .map(|invocation| invocation.output)
+        }
+    };
+    // without a bucket the host functions trap
+    assert!(call("incr", vec![], FunctionConfig::default())
+        .await
+        .is_err());
+
+    let dir = std::env::temp_dir().join("wasmengine-test-kv");
+    let _ = std::fs::remove_dir_all(&dir);
+    let store = KvStore::open(&dir, 64)?;
+    let mut func_config = FunctionConfig::default();
+    func_config.set_kv_bucket(Arc::new(store.bucket("counter")?));
+
+    for (export, params, expect) in [
+        ("incr", vec![], json!([1])),
+        ("incr", vec![], json!([2])),
+        // "count" and its NUL terminator
+        ("list", vec![], json!([6])),
+        ("big", vec![json!(100)], json!([-2])),
+        ("big", vec![json!(10)], json!([0])),
+        ("reset", vec![], json!([0])),
+        ("reset", vec![], json!([-1])),
+        ("incr", vec![], json!([1])),
+    ] {
+        let output = call(export, params, func_config.clone()).await?;
+        assert_eq!(output, expect.to_string(), "{}", export);
+    }
+    assert_eq!(store.bucket("counter")?.info().keys, 2);
+
+    Ok(())
+}